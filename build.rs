@@ -0,0 +1,71 @@
+// Embeds the default web user interface (the keeper.web directory) into the executable, so the
+// daemon works without installing the web resources
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+const WEB_DIR: &'static str = "keeper.web";
+const EMBEDDED_FILE: &'static str = "webui.rs";
+/// Third party files of the user interface, downloaded by fetch-web-vendor.sh
+const VENDOR_DIR: &'static str = "res/vendor";
+const VENDOR_FILES: &'static [&'static str] = &[
+    "jquery-1.11.3.min.js",
+    "jquery.mobile-1.4.5.min.js",
+    "jquery.mobile-1.4.5.min.css",
+    "images/ajax-loader.gif",
+];
+
+/// All files of a directory and its subdirectories
+fn collect(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in try!(fs::read_dir(dir)) {
+        let path = try!(entry).path();
+
+        if path.is_dir() {
+            try!(collect(&path, files));
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+fn main() {
+    let root = Path::new(&env::var("CARGO_MANIFEST_DIR").expect("BUG: CARGO_MANIFEST_DIR not set")).join(WEB_DIR);
+    let out = Path::new(&env::var("OUT_DIR").expect("BUG: OUT_DIR not set")).join(EMBEDDED_FILE);
+
+    // a missing file is always considered changed, so the build picks up the files once they
+    // are downloaded
+    let vendor = root.join(VENDOR_DIR);
+    println!("cargo:rerun-if-changed={}", vendor.display());
+    for file in VENDOR_FILES {
+        println!("cargo:rerun-if-changed={}", vendor.join(file).display());
+    }
+    let missing: Vec<_> = VENDOR_FILES.iter().filter(|file| !vendor.join(file).is_file()).collect();
+    if !missing.is_empty() {
+        // the user interface then loads them from the CDN (see index.html)
+        println!("cargo:warning=third party files of the web user interface missing in {}: {:?}; run \
+                  ./fetch-web-vendor.sh to download them", vendor.display(), missing);
+    }
+
+    let mut files = vec![];
+    collect(&root, &mut files).unwrap_or_else(|err| panic!("unable to read {}: {}", root.display(), err));
+    files.sort();
+
+    let mut embedded = File::create(&out).unwrap_or_else(|err| panic!("unable to create {}: {}", out.display(), err));
+
+    writeln!(embedded, "pub static FILES: &'static [(&'static str, &'static [u8])] = &[").unwrap();
+    for file in &files {
+        let name = file.strip_prefix(&root).expect("BUG: file outside web directory");
+        let components: Vec<_> = name.components().map(|c| c.as_os_str().to_str().expect("web file names must be UTF-8")).collect();
+        let path = file.to_str().expect("web file names must be UTF-8");
+
+        writeln!(embedded, "    ({:?}, include_bytes!({:?})),", components.join("/"), path).unwrap();
+        println!("cargo:rerun-if-changed={}", path);
+    }
+    writeln!(embedded, "];").unwrap();
+
+    println!("cargo:rerun-if-changed={}", root.display());
+}
@@ -0,0 +1,23 @@
+#!/bin/sh
+# Download the third party files of the web user interface into keeper.web/res/vendor, so the
+# daemon serves them itself and embeds them at the next build (the user interface also works
+# without internet access). Until these files are present the build warns and the user
+# interface loads them from the CDN; commit them after downloading.
+set -e
+
+VENDOR="$(dirname "$0")/keeper.web/res/vendor"
+mkdir -p "$VENDOR"
+
+for url in \
+    https://code.jquery.com/jquery-1.11.3.min.js \
+    https://code.jquery.com/mobile/1.4.5/jquery.mobile-1.4.5.min.js \
+    https://code.jquery.com/mobile/1.4.5/jquery.mobile-1.4.5.min.css
+do
+    curl -fsSL -o "$VENDOR/$(basename "$url")" "$url"
+done
+
+# the stylesheet refers to its icons relative to itself
+mkdir -p "$VENDOR/images"
+for icon in ajax-loader.gif; do
+    curl -fsSL -o "$VENDOR/images/$icon" "https://code.jquery.com/mobile/1.4.5/images/$icon"
+done
@@ -13,6 +13,38 @@ function hide_loader() {
     $("#foo").removeClass('ui-disabled');
 }
 
+// Switch shown by the details page
+var current_switch = null;
+
+// Configuration of the current switch, as shown by the details page
+var current_config = null;
+
+// Toggle edited by the toggle page; null for a new toggle
+var editing_toggle = null;
+
+// Whether the circle page edits the current switch (or adds a new circle)
+var editing_circle = false;
+
+// Settings of a circle edited by the circle page
+var CIRCLE_SETTINGS = ["mac", "stick", "default", "shutdown", "site"];
+
+// Settings of the driver of a circle; shown, but only changed in the configuration file, since
+// they make keeper run commands and requests
+var DRIVER_SETTINGS = ["driver", "on_url", "off_url", "level_url", "on_command", "off_command", "level_command"];
+
+// Length of the timeline of the details page
+var TIMELINE_HOURS = 48;
+
+// Describe a level (percentage) of a switch
+function level_text(level) {
+    if (level == 0) {
+        return "off";
+    } else if (level == 100) {
+        return "on";
+    }
+    return level + "%";
+}
+
 // Retrieve all switch configurations and update web user interface
 function load_switches() {
     show_loader();
@@ -34,6 +66,9 @@ function load_switches() {
                     + '</a></li>');
 
             li = li.find("li").last();
+            li.find("a").click(function() {
+                current_switch = object;
+            });
             var flip = li.find("#flip_" + object);
             flip.slider().change(function(event) {
                 li.addClass('ui-disabled');
@@ -56,17 +91,33 @@ function load_switches() {
                     flip.val("off").slider("refresh");
                 }
 
+                // dimmers get a slider for the level
+                if (data.dimmable) {
+                    var dim = $('<input type="range" name="level_' + object + '" min="0" max="100" step="5" data-mini="true" value="'
+                            + data.level + '">');
+                    li.find("a p").first().after($('<p></p>').append(dim));
+                    dim.slider().on("slidestop", function(event) {
+                        li.addClass('ui-disabled');
+                        $.post("/api/switch/" + object + "/level/" + dim.val(), function(data) {
+                            dim.val(data).slider("refresh");
+                            flip.val(data ? "on" : "off").slider("refresh");
+                        }).always(function() {
+                            li.removeClass('ui-disabled');
+                        });
+                    });
+                }
+
                 // update side part of LI item
-                var next_event = Object.keys(data.next_events).shift();
+                var next_event = Object.keys(data.next_levels).shift();
                 var aside = li.find("p.ui-li-aside")
                 if (next_event === undefined) {
                     aside.html('manual');
                 } else {
-                    var next_state = data.next_events[next_event];
+                    var next_level = data.next_levels[next_event];
                     next_event = new Date(Date.parse(next_event));
                     aside.html("next: "
                             + next_event.getHours() + ":" + next_event.getMinutes()
-                            + " <strong>" + (next_state ? "on" : "off") + "</strong>");
+                            + " <strong>" + level_text(next_level) + "</strong>");
                 }
             }).fail(function() {
             }).always(function() {
@@ -82,6 +133,410 @@ function load_switches() {
     });
 }
 
+// Format the time of a timestamp of the API as HH:MM
+function clock_time(date) {
+    return ("0" + date.getHours()).slice(-2) + ":" + ("0" + date.getMinutes()).slice(-2);
+}
+
+// Describe an event of a toggle
+function event_text(event) {
+    var text;
+
+    switch (event.kind) {
+    case "fixed":
+        text = event.time;
+        break;
+    case "fuzzy":
+        text = "between " + event.time + " and " + event.latest;
+        break;
+    default:
+        text = event.kind;
+        if (event.variance > 0) {
+            text += " \u00b1" + event.variance + " min";
+        }
+        if (event.today !== null) {
+            text += " (today " + clock_time(new Date(Date.parse(event.today))) + ")";
+        }
+    }
+
+    return text;
+}
+
+// Draw the scheduled levels of the next 48 hours; fades are shown in steps
+function draw_timeline(status) {
+    var now = Date.now();
+    var span = TIMELINE_HOURS * 3600 * 1000;
+    var timeline = $("#timeline").empty();
+    var hours = $("#timeline-hours").empty();
+    var level = status.scheduled_level;
+    var from = now;
+
+    var segment = function(until) {
+        if (level > 0 && until > from) {
+            timeline.append($("<div></div>").css({
+                left: ((from - now) * 100 / span) + "%",
+                width: ((until - from) * 100 / span) + "%",
+                opacity: 0.3 + 0.7 * level / 100
+            }).attr("title", level_text(level)));
+        }
+    };
+
+    $.each(Object.keys(status.next_levels).sort(), function(index, ts) {
+        var at = Date.parse(ts);
+        if (at > now + span) {
+            return false;
+        }
+        segment(at);
+        level = status.next_levels[ts];
+        from = at;
+    });
+    segment(now + span);
+
+    for (var hour = 6; hour < TIMELINE_HOURS; hour += 6) {
+        var at = new Date(now + hour * 3600 * 1000);
+        hours.append($("<span></span>").css("left", (hour * 100 / TIMELINE_HOURS) + "%").text(clock_time(at)));
+    }
+}
+
+// Override the state of the current switch ("on", "off" or a percentage)
+function override_switch(state) {
+    var duration = $("#override-duration").val();
+
+    var path = state == "on" || state == "off" ? state : "level/" + state;
+
+    $.post("/api/switch/" + current_switch + "/" + path + (duration ? "/" + duration : ""), function() {
+        load_details();
+    });
+}
+
+// Retrieve the status (with the diagnostics), configuration and history of the current switch
+function load_details() {
+    if (current_switch === null) {
+        $.mobile.changePage("#list");
+        return;
+    }
+
+    $("#details-title").text(current_switch);
+
+    $.post("/api/get/" + current_switch, function(status) {
+        var state = level_text(status.level);
+        if (status.level != status.scheduled_level) {
+            state += " (scheduled: " + level_text(status.scheduled_level) + ")";
+        }
+        $("#details-state").html("<strong>" + state + "</strong>");
+        $("#override-level").val(status.level).slider("refresh");
+        $("#override-dimmer").toggle(status.dimmable);
+        draw_timeline(status);
+
+        // events of the toggles that were skipped or merged while building the schedule
+        $("#diagnostics").empty();
+        if (status.diagnostics.length == 0) {
+            $("#diagnostics").append("<li>no skipped or merged events</li>");
+        }
+        $.each(status.diagnostics, function(index, diagnostic) {
+            var at = new Date(Date.parse(diagnostic.time));
+            $("#diagnostics").append($("<li>").append(
+                $("<h2>").text(diagnostic.toggle + ", " + at.toDateString() + " " + clock_time(at)),
+                $("<p>").text(diagnostic.reason)));
+        });
+        $("#diagnostics").listview("refresh");
+    });
+
+    $.post("/api/config/" + current_switch, function(config) {
+        current_config = config;
+        $("#toggles").empty();
+
+        if (config.default != "schedule") {
+            $("#toggles").append("<li>always " + config.default + "</li>");
+        }
+        $.each(config.toggles, function(index, toggle) {
+            var details = [];
+            if (toggle.level < 100) {
+                details.push("level " + toggle.level + "%");
+            }
+            if (toggle.fade_in > 0) {
+                details.push("fade in " + toggle.fade_in + " min");
+            }
+            if (toggle.fade_out > 0) {
+                details.push("fade out " + toggle.fade_out + " min");
+            }
+            if (toggle.when) {
+                details.push("only when " + toggle.when);
+            }
+            if (toggle.min_daylight) {
+                details.push("at least " + toggle.min_daylight + " daylight");
+            }
+            if (toggle.max_daylight) {
+                details.push("at most " + toggle.max_daylight + " daylight");
+            }
+            if (toggle.template) {
+                details.push("from template " + toggle.template);
+            }
+            var li = $('<li><a href="#edit-toggle"><h2>' + toggle.name + '</h2>'
+                    + '<p>on: ' + event_text(toggle.start) + '</p>'
+                    + '<p>off: ' + event_text(toggle.end) + '</p>'
+                    + (details.length ? '<p>' + details.join(", ") + '</p>' : '')
+                    + '</a></li>');
+            li.find("a").click(function() {
+                editing_toggle = toggle;
+            });
+            $("#toggles").append(li);
+        });
+        $("#toggles").listview("refresh");
+    });
+
+    $.post("/api/history/" + current_switch, function(history) {
+        $("#history").empty();
+
+        if (history.length == 0) {
+            $("#history").append("<li>no recent actions</li>");
+        }
+        $.each(history.reverse(), function(index, action) {
+            var at = new Date(Date.parse(action.time));
+            $("#history").append("<li>" + at.toDateString() + " " + clock_time(at)
+                    + " <strong>" + level_text(action.level) + "</strong></li>");
+        });
+        $("#history").listview("refresh");
+    });
+}
+
+// Show the error of a failed configuration change on a page
+function show_edit_error(page, xhr) {
+    $(page).find(".edit-error").text(xhr.responseText || "unable to save the configuration");
+}
+
+// Post a request with a JSON body
+function post_json(url, data) {
+    return $.ajax({
+        url: url,
+        type: "POST",
+        data: JSON.stringify(data),
+        contentType: "application/json",
+        dataType: "json"
+    });
+}
+
+// Show the inputs of the selected kind of a toggle event ("start" or "end")
+function show_event_inputs(which) {
+    var kind = $("#toggle-" + which + "-kind").val();
+    var fieldset = $("#toggle-" + which + "-kind").closest("fieldset");
+
+    fieldset.find(".toggle-time").toggle(kind == "fixed" || kind == "fuzzy");
+    fieldset.find(".toggle-latest").toggle(kind == "fuzzy");
+    fieldset.find(".toggle-variance").toggle(kind == "sunrise" || kind == "sunset");
+}
+
+function fill_event(which, event) {
+    $("#toggle-" + which + "-kind").val(event.kind).selectmenu("refresh");
+    $("#toggle-" + which + "-time").val(event.time || "");
+    $("#toggle-" + which + "-latest").val(event.latest || "");
+    $("#toggle-" + which + "-variance").val(event.variance || 0);
+    show_event_inputs(which);
+}
+
+function read_event(which) {
+    return {
+        kind: $("#toggle-" + which + "-kind").val(),
+        time: $("#toggle-" + which + "-time").val() || null,
+        latest: $("#toggle-" + which + "-latest").val() || null,
+        variance: parseInt($("#toggle-" + which + "-variance").val()) || 0
+    };
+}
+
+// Fill the toggle page with the edited toggle, or the defaults of a new toggle
+function load_toggle() {
+    if (current_switch === null) {
+        $.mobile.changePage("#list");
+        return;
+    }
+
+    var toggle = editing_toggle || {
+        name: "",
+        start: { kind: "sunset", variance: 0 },
+        end: { kind: "fixed", time: "23:00" },
+        level: 100,
+        fade_in: 0,
+        fade_out: 0
+    };
+
+    $("#toggle-title").text(current_switch + ": " + (editing_toggle ? toggle.name : "new toggle"));
+    $("#toggle-name").val(toggle.name);
+    fill_event("start", toggle.start);
+    fill_event("end", toggle.end);
+    $("#toggle-level").val(toggle.level).slider("refresh");
+    $("#toggle-fade_in").val(toggle.fade_in);
+    $("#toggle-fade_out").val(toggle.fade_out);
+    $("#toggle-when").val(toggle.when || "").selectmenu("refresh");
+    $("#toggle-min_daylight").val(toggle.min_daylight || "");
+    $("#toggle-max_daylight").val(toggle.max_daylight || "");
+    // a toggle of a template cannot be renamed or deleted; saving it gives the circle its own
+    // toggle, which overrides the toggle of the template
+    $("#toggle-template").text(toggle.template ? "From template " + toggle.template
+                                                 + "; saving gives this circle its own toggle." : "");
+    $("#toggle-name").textinput(toggle.template ? "disable" : "enable");
+    $("#toggle-delete").toggle(editing_toggle !== null && !toggle.template);
+    $("#edit-toggle .edit-error").empty();
+}
+
+// Save the toggle page; the toggle is renamed when its name was changed
+function save_toggle() {
+    var toggle = {
+        name: $("#toggle-name").val(),
+        start: read_event("start"),
+        end: read_event("end"),
+        level: parseInt($("#toggle-level").val()),
+        fade_in: parseInt($("#toggle-fade_in").val()) || 0,
+        fade_out: parseInt($("#toggle-fade_out").val()) || 0,
+        when: $("#toggle-when").val() || null,
+        min_daylight: $("#toggle-min_daylight").val() || null,
+        max_daylight: $("#toggle-max_daylight").val() || null
+    };
+    var name = editing_toggle ? editing_toggle.name : toggle.name;
+
+    if (!toggle.name) {
+        $("#edit-toggle .edit-error").text("a name is required");
+        return;
+    }
+
+    post_json("/api/toggle/" + current_switch + "/" + name + "/set", toggle).done(function() {
+        $.mobile.changePage("#details");
+    }).fail(function(xhr) {
+        show_edit_error("#edit-toggle", xhr);
+    });
+}
+
+function delete_toggle() {
+    if (!confirm("Delete toggle " + editing_toggle.name + "?")) {
+        return;
+    }
+
+    $.post("/api/toggle/" + current_switch + "/" + editing_toggle.name + "/delete", function() {
+        $.mobile.changePage("#details");
+    }).fail(function(xhr) {
+        show_edit_error("#edit-toggle", xhr);
+    });
+}
+
+// Show the inputs of the selected driver of a circle
+function show_driver_inputs() {
+    var driver = $("#circle-driver").val();
+
+    $.each(["plugwise", "http", "command"], function(index, name) {
+        $(".driver-" + name).toggle(name == driver);
+    });
+    $(".driver-external").toggle(driver != "plugwise");
+}
+
+// Fill the circle page with the settings of the current switch, or the defaults of a new circle
+function load_circle() {
+    var config = editing_circle && current_config ? current_config : { driver: "plugwise", default: "schedule" };
+
+    $("#circle-title").text(editing_circle ? current_switch : "new circle");
+    $("#circle-alias").val(editing_circle ? current_switch : "").textinput(editing_circle ? "disable" : "enable");
+    $.each(CIRCLE_SETTINGS.concat(DRIVER_SETTINGS), function(index, key) {
+        var input = $("#circle-" + key).val(config[key] || "");
+        if (input.is("select")) {
+            input.selectmenu("refresh");
+        }
+    });
+    show_driver_inputs();
+    $("#circle-delete").toggle(editing_circle);
+    $("#edit-circle .edit-error").empty();
+}
+
+// Save the changed settings of the circle page; the mac and stick are removed from circles with
+// another driver
+function save_circle() {
+    var alias = editing_circle ? current_switch : $("#circle-alias").val();
+    var driver = $("#circle-driver").val();
+    var loaded = editing_circle && current_config ? current_config : {};
+    var settings = {};
+
+    if (!alias) {
+        $("#edit-circle .edit-error").text("an alias is required");
+        return;
+    }
+
+    $.each(CIRCLE_SETTINGS, function(index, key) {
+        var input = $("#circle-" + key);
+        var other_driver = input.closest("[class^=driver-]").length > 0 && input.closest(".driver-" + driver).length == 0;
+        var value = other_driver ? null : input.val() || null;
+
+        if (value !== (loaded[key] || null)) {
+            settings[key] = value;
+        }
+    });
+
+    post_json("/api/circle/" + alias + "/set", settings).done(function() {
+        current_switch = alias;
+        load_switches();
+        $.mobile.changePage("#details");
+    }).fail(function(xhr) {
+        show_edit_error("#edit-circle", xhr);
+    });
+}
+
+function delete_circle() {
+    if (!confirm("Delete circle " + current_switch + " and its toggles?")) {
+        return;
+    }
+
+    $.post("/api/circle/" + current_switch + "/delete", function() {
+        current_switch = null;
+        load_switches();
+        $.mobile.changePage("#list");
+    }).fail(function(xhr) {
+        show_edit_error("#edit-circle", xhr);
+    });
+}
+
+// Probe the configured circles and the given macs, and list the results
+function discover_circles() {
+    var macs = $("#macs").val().replace(/\s/g, "");
+    show_loader();
+
+    $.post("/api/discover" + (macs ? "/" + macs : ""), function(data) {
+        $("#circles").empty();
+
+        $.each(data, function(index, circle) {
+            var state = circle.reachable
+                ? "reachable (" + circle.latency_ms + " ms), " + (circle.switch ? "on" : "off")
+                : "unreachable" + (circle.error ? ": " + circle.error : "");
+            var li = $('<li class="ui-field-contain">'
+                    + '<h2>' + (circle.alias || "new circle") + '</h2>'
+                    + '<p>' + circle.mac + ' on stick ' + circle.stick + '</p>'
+                    + '<p>' + state + '</p>'
+                    + '</li>');
+
+            if (circle.alias === null && circle.reachable) {
+                var form = $('<form><input type="text" placeholder="alias" data-mini="true">'
+                        + '<input type="submit" value="Add to configuration" data-mini="true"></form>');
+                form.submit(function(event) {
+                    event.preventDefault();
+                    var alias = form.find("input[type=text]").val();
+                    $.post("/api/discover/add/" + circle.stick + "/" + circle.mac + "/" + alias, function() {
+                        discover_circles();
+                        load_switches();
+                    }).fail(function(xhr) {
+                        alert(xhr.responseText);
+                    });
+                });
+                li.append(form);
+            }
+
+            $("#circles").append(li);
+        });
+
+        $("#circles").listview("refresh");
+        $("#circles").trigger("create");
+    }).fail(function(xhr) {
+        $("#circles").empty().append($("<li>").text(xhr.responseText || "unable to probe the circles"));
+        $("#circles").listview("refresh");
+    }).always(function() {
+        hide_loader();
+    });
+}
+
 // Load web page and set event handlers
 $(function() {
     load_switches();
@@ -90,4 +545,81 @@ $(function() {
         event.preventDefault();
         load_switches();
     });
+
+    $("#probe").submit(function(event) {
+        event.preventDefault();
+        discover_circles();
+    });
+
+    $(document).on("pageshow", "#discover", function() {
+        discover_circles();
+    });
+
+    $(document).on("pageshow", "#details", function() {
+        load_details();
+    });
+
+    $("#override-on").click(function(event) {
+        event.preventDefault();
+        override_switch("on");
+    });
+
+    $("#override-off").click(function(event) {
+        event.preventDefault();
+        override_switch("off");
+    });
+
+    $("#override-level").on("slidestop", function() {
+        override_switch($(this).val());
+    });
+
+    $("#add-circle").click(function() {
+        editing_circle = false;
+    });
+
+    $("#edit-circle-settings").click(function() {
+        editing_circle = true;
+    });
+
+    $("#add-toggle").click(function() {
+        editing_toggle = null;
+    });
+
+    $(document).on("pageshow", "#edit-circle", function() {
+        load_circle();
+    });
+
+    $(document).on("pageshow", "#edit-toggle", function() {
+        load_toggle();
+    });
+
+    $("#circle-driver").change(show_driver_inputs);
+
+    $("#toggle-start-kind").change(function() {
+        show_event_inputs("start");
+    });
+
+    $("#toggle-end-kind").change(function() {
+        show_event_inputs("end");
+    });
+
+    $("#circle-form").submit(function(event) {
+        event.preventDefault();
+        save_circle();
+    });
+
+    $("#toggle-form").submit(function(event) {
+        event.preventDefault();
+        save_toggle();
+    });
+
+    $("#circle-delete").click(function(event) {
+        event.preventDefault();
+        delete_circle();
+    });
+
+    $("#toggle-delete").click(function(event) {
+        event.preventDefault();
+        delete_toggle();
+    });
 });
@@ -0,0 +1,147 @@
+// This module serves the files of the web user interface. Third party files are versioned (their
+// name changes with every upgrade), so browsers may cache them for a long time; the other files
+// are revalidated on every request, so a changed user interface shows up right away.
+//
+// The default user interface is embedded in the executable (see build.rs); files in the web
+// directory take precedence over the embedded ones.
+
+use iron::prelude::*;
+use iron::headers::{CacheControl, CacheDirective, HttpDate, IfModifiedSince, LastModified};
+use iron::mime::Mime;
+use iron::status;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use time::{Timespec, at_utc, get_time};
+
+mod embedded {
+    include!(concat!(env!("OUT_DIR"), "/webui.rs"));
+}
+
+/// Directory (relative to the web root) of the versioned third party files
+const VENDOR_DIR: &'static str = "res/vendor";
+
+/// Maximum age of the versioned third party files in a cache
+const VENDOR_MAX_AGE_SECONDS: u32 = 365 * 24 * 60 * 60;
+
+const INDEX: &'static str = "index.html";
+
+/// Content type of a file, by its extension
+fn content_type(path: &Path) -> Mime {
+    let mime = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js") => "application/javascript",
+        Some("css") => "text/css",
+        Some("json") | Some("map") => "application/json",
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("svg") => "image/svg+xml",
+        Some("ico") => "image/x-icon",
+        _ => "application/octet-stream",
+    };
+    mime.parse().expect("BUG: invalid content type")
+}
+
+/// Caching policy of a file (relative to the web root)
+fn cache_control(path: &Path) -> CacheControl {
+    if path.starts_with(VENDOR_DIR) {
+        CacheControl(vec![CacheDirective::Public, CacheDirective::MaxAge(VENDOR_MAX_AGE_SECONDS)])
+    } else {
+        CacheControl(vec![CacheDirective::NoCache])
+    }
+}
+
+/// Path (relative to the web root) of the requested file; `None` when it points outside the root
+fn resolve(segments: &[String]) -> Option<PathBuf> {
+    let path: PathBuf = segments.iter().filter(|segment| !segment.is_empty()).collect();
+
+    if path.components().all(|component| match component {
+        Component::Normal(_) => true,
+        _ => false,
+    }) {
+        Some(if path.as_os_str().is_empty() { PathBuf::from(INDEX) } else { path })
+    } else {
+        None
+    }
+}
+
+/// Content of a file to serve
+enum Body {
+    File(fs::File),
+    Embedded(&'static [u8]),
+}
+
+/// Serves the files of the web directory and the embedded user interface
+pub struct Assets {
+    root: Option<PathBuf>,
+    /// Modification time of the embedded files; they cannot change while running
+    started: Timespec,
+}
+
+impl Assets {
+    pub fn new(root: Option<&Path>) -> Assets {
+        Assets {
+            root: root.map(Path::to_path_buf),
+            started: Timespec::new(get_time().sec, 0),
+        }
+    }
+
+    /// A file of the web directory, with its modification time
+    fn open_file(root: &Path, path: &Path) -> Option<(Body, Timespec)> {
+        fs::File::open(root.join(path)).and_then(|file| {
+            file.metadata().and_then(|metadata| metadata.modified()).map(|modified| (file, modified))
+        }).ok().map(|(file, modified)| {
+            // HTTP dates have a resolution of a second
+            let modified = modified.duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+            (Body::File(file), Timespec::new(modified, 0))
+        })
+    }
+
+    /// An embedded file
+    fn open_embedded(&self, path: &Path) -> Option<(Body, Timespec)> {
+        let name: Vec<_> = path.iter().filter_map(|segment| segment.to_str()).collect();
+        let name = name.join("/");
+
+        embedded::FILES.iter()
+                       .find(|&&(embedded, _)| embedded == name)
+                       .map(|&(_, data)| (Body::Embedded(data), self.started))
+    }
+
+    /// The requested file (relative to the web root) with its modification time
+    fn open(&self, path: &Path) -> Option<(Body, Timespec)> {
+        self.root.as_ref()
+                 .and_then(|root| Assets::open_file(root, path))
+                 .or_else(|| self.open_embedded(path))
+    }
+}
+
+impl Handler for Assets {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let path = match resolve(&req.url.path) {
+            Some(path) => path,
+            None => return Ok(Response::with(status::NotFound)),
+        };
+        let is_dir = self.root.as_ref().map_or(false, |root| root.join(&path).is_dir());
+        let path = if is_dir { path.join(INDEX) } else { path };
+
+        let (body, modified) = match self.open(&path) {
+            Some(found) => found,
+            None => return Ok(Response::with(status::NotFound)),
+        };
+
+        let not_modified = match req.headers.get::<IfModifiedSince>() {
+            Some(&IfModifiedSince(HttpDate(ref since))) => since.to_timespec() >= modified,
+            None => false,
+        };
+
+        let mut response = match (not_modified, body) {
+            (true, _) => Response::with(status::NotModified),
+            (false, Body::File(file)) => Response::with((status::Ok, content_type(&path), file)),
+            (false, Body::Embedded(data)) => Response::with((status::Ok, content_type(&path), data.to_vec())),
+        };
+        response.headers.set(cache_control(&path));
+        response.headers.set(LastModified(HttpDate(at_utc(modified))));
+
+        Ok(response)
+    }
+}
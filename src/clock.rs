@@ -0,0 +1,395 @@
+// This module provides the sources of the current time used by the tracker: NTP (optionally
+// falling back to the system clock), the system clock and a simulated clock for tests and
+// simulations
+
+use config::{Device, TimePolicy};
+use ntpclient::retrieve_ntp_timestamp;
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use time::{Timespec, Duration, Tm, precise_time_ns, at, at_utc, get_time};
+use zoneinfo::ZoneInfo;
+
+const BILLION: u64 = 1_000_000_000;
+
+/// Maximum difference between NTP and the system clock before a warning is logged
+const MAX_SYSTEM_OFFSET_SECONDS: i64 = 30;
+
+/// Interval between two NTP polls
+const NTP_POLL_DAYS: i64 = 1;
+
+/// Broken down time of a timestamp in the given time zone, like `time::at` does for the local
+/// time zone
+pub fn zone_time(zoneinfo: &ZoneInfo, ts: Timespec) -> Tm {
+    let offset = zoneinfo.get_actual_zoneinfo(ts).map_or(Duration::zero(), |info| info.ut_offset);
+    let mut tm = at_utc(ts + offset);
+    tm.tm_utcoff = offset.num_seconds() as i32;
+    tm
+}
+
+/// Source of the timestamps send by the ticker
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeSource {
+    Ntp,
+    System,
+    Simulated,
+}
+
+/// Information about the time source in use
+#[derive(Clone, Debug)]
+pub struct TimeStatus {
+    /// `None` while no trusted time is available
+    pub source: Option<TimeSource>,
+    /// server of the last NTP response
+    pub ntp_server: Option<String>,
+    pub last_sync: Option<Timespec>,
+    /// difference between NTP and the system clock at the last NTP response
+    pub offset: Option<Duration>,
+}
+
+impl TimeStatus {
+    fn new() -> TimeStatus {
+        TimeStatus {
+            source: None,
+            ntp_server: None,
+            last_sync: None,
+            offset: None,
+        }
+    }
+
+    fn set_source(&mut self, source: TimeSource) {
+        if self.source != Some(source) {
+            info!("using {:?} time", source);
+            self.source = Some(source);
+        }
+    }
+}
+
+/// Source of the current time
+pub trait Clock: Send {
+    /// The current time; `None` while no trusted time is available
+    fn now(&mut self) -> Option<Timespec>;
+
+    /// Real time between two moments that are `interval` apart on this clock
+    fn real_interval(&self, interval: Duration) -> Duration {
+        interval
+    }
+
+    fn status(&self) -> TimeStatus;
+
+    /// Called before the first tick; the clock may notify `wakeup` to request a tick
+    /// immediately (e.g. when the time becomes available)
+    fn start(&mut self, _wakeup: Arc<(Mutex<bool>, Condvar)>) {
+    }
+}
+
+/// Create the clock selected by the time policy of the device
+pub fn from_config(device: &Device) -> Box<Clock> {
+    let ntp = NtpFetcher::new(device.ntp_servers.clone(), Duration::days(NTP_POLL_DAYS));
+
+    match device.time_policy {
+        TimePolicy::Ntp => Box::new(NtpClock::new(ntp, None)),
+        TimePolicy::Fallback(timeout) => Box::new(NtpClock::new(ntp, Some(timeout))),
+        TimePolicy::System => Box::new(SystemClock::new(Some(ntp))),
+    }
+}
+
+struct NtpSync {
+    timestamp: Timespec,
+    /// value of `precise_time_ns` when the timestamp was received (0 when never received)
+    ref_time: u64,
+    server: String,
+}
+
+// NOTE: obsolete when Rust starts to support UDP receive timeout
+struct NtpFetcher {
+    servers: Vec<String>,
+    /// server used for the next poll; moves to the next server when a poll fails
+    server: Arc<AtomicUsize>,
+    last_sync: Arc<Mutex<NtpSync>>,
+    poll: Duration,
+    last_poll: u64,
+    ntp_update: Option<Arc<(Mutex<bool>, Condvar)>>,
+}
+
+impl NtpFetcher {
+    fn new(servers: Vec<String>, ntp_poll: Duration) -> NtpFetcher {
+        NtpFetcher {
+            servers: servers,
+            server: Arc::new(AtomicUsize::new(0)),
+            last_sync: Arc::new(Mutex::new(NtpSync {
+                timestamp: Timespec::new(0,0),
+                ref_time: 0,
+                server: String::new(),
+            })),
+            poll: ntp_poll,
+            last_poll: 0,
+            ntp_update: None,
+        }
+    }
+
+    fn start(&mut self, ntp_update: Arc<(Mutex<bool>, Condvar)>) {
+        self.ntp_update = Some(ntp_update);
+        self.consider_poll_ntp();
+    }
+
+    fn consider_poll_ntp(&mut self) {
+        if let Ok(ref lock) = self.last_sync.lock() {
+            let ref_time = lock.ref_time;
+            let poll_interval = if ref_time == 0 {
+                // when never NTP timestamp was received, try again after 1 minute
+                Duration::minutes(1)
+            } else {
+                self.poll
+            };
+
+            let curr = precise_time_ns();
+            let must_poll = (self.last_poll == 0) ||
+                (((curr - self.last_poll) / BILLION) >= poll_interval.num_seconds() as u64);
+
+            if must_poll && !self.servers.is_empty() {
+                self.last_poll = curr;
+                let sync = self.last_sync.clone();
+                let index = self.server.clone();
+                let server = self.servers[index.load(Ordering::SeqCst) % self.servers.len()].clone();
+                let update = self.ntp_update.clone();
+
+                let join = thread::spawn(move || {
+                    match retrieve_ntp_timestamp(&server[..]) {
+                        Ok(ts) => {
+                            if let Ok(ref mut lock) = sync.lock() {
+                                debug!("updated internet time: {} ({})", at(ts).asctime(), server);
+                                let ref_time = lock.ref_time;
+                                **lock = NtpSync {
+                                    timestamp: ts,
+                                    ref_time: precise_time_ns(),
+                                    server: server,
+                                };
+                                if let (0, Some(update)) = (ref_time, update) {
+                                    // notify ntp listener that a initial ntp result is known
+                                    let &(_, ref cvar) = &*update;
+                                    cvar.notify_all();
+                                }
+                            }
+                        },
+                        Err(_) => {
+                            warn!("no response from NTP server {}", server);
+                            index.fetch_add(1, Ordering::SeqCst);
+                        }
+                    }
+                });
+                drop(join);
+            }
+        }
+    }
+
+    /// Current NTP time and the server it is based on
+    fn get_timespec(&mut self) -> Option<(Timespec, Timespec, String)> {
+        self.consider_poll_ntp();
+        match self.last_sync.lock() {
+            Ok(ref lock) => {
+                match lock.ref_time {
+                    0 => None,
+                    ref_time => {
+                        let ns = precise_time_ns() - ref_time;
+                        Some((lock.timestamp + Duration::nanoseconds(ns as i64),
+                              lock.timestamp,
+                              lock.server.clone()))
+                    }
+                }
+            },
+            Err(_) => None,
+        }
+    }
+
+    /// Current NTP time; the status is updated and the system clock is checked when a new NTP
+    /// response is received.
+    fn sync(&mut self, status: &mut TimeStatus) -> Option<Timespec> {
+        let system = get_time();
+
+        self.get_timespec().map(|(ts, last_sync, server)| {
+            if status.last_sync != Some(last_sync) {
+                let offset = ts - system;
+                if offset.num_seconds().abs() > MAX_SYSTEM_OFFSET_SECONDS {
+                    warn!("system clock differs {} seconds from NTP server {}",
+                          offset.num_seconds(), server);
+                }
+                status.ntp_server = Some(server);
+                status.last_sync = Some(last_sync);
+                status.offset = Some(offset);
+            }
+            ts
+        })
+    }
+}
+
+/// NTP time, optionally falling back to the system clock when no NTP server responds in time
+pub struct NtpClock {
+    ntp: NtpFetcher,
+    fallback: Option<Duration>,
+    started: u64,
+    status: TimeStatus,
+}
+
+impl NtpClock {
+    fn new(ntp: NtpFetcher, fallback: Option<Duration>) -> NtpClock {
+        NtpClock {
+            ntp: ntp,
+            fallback: fallback,
+            started: precise_time_ns(),
+            status: TimeStatus::new(),
+        }
+    }
+}
+
+impl Clock for NtpClock {
+    fn now(&mut self) -> Option<Timespec> {
+        match (self.ntp.sync(&mut self.status), self.fallback) {
+            (Some(ts), _) => {
+                self.status.set_source(TimeSource::Ntp);
+                Some(ts)
+            },
+            (None, Some(timeout)) => {
+                let waiting = Duration::nanoseconds((precise_time_ns() - self.started) as i64);
+                if waiting >= timeout {
+                    self.status.set_source(TimeSource::System);
+                    Some(get_time())
+                } else {
+                    None
+                }
+            },
+            (None, None) => None,
+        }
+    }
+
+    fn status(&self) -> TimeStatus {
+        self.status.clone()
+    }
+
+    fn start(&mut self, wakeup: Arc<(Mutex<bool>, Condvar)>) {
+        self.started = precise_time_ns();
+        self.ntp.start(wakeup);
+    }
+}
+
+/// System time, optionally checked against NTP
+pub struct SystemClock {
+    ntp: Option<NtpFetcher>,
+    status: TimeStatus,
+}
+
+impl SystemClock {
+    fn new(ntp: Option<NtpFetcher>) -> SystemClock {
+        SystemClock {
+            ntp: ntp,
+            status: TimeStatus::new(),
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&mut self) -> Option<Timespec> {
+        if let Some(ref mut ntp) = self.ntp {
+            ntp.sync(&mut self.status);
+        }
+        self.status.set_source(TimeSource::System);
+        Some(get_time())
+    }
+
+    fn status(&self) -> TimeStatus {
+        self.status.clone()
+    }
+
+    fn start(&mut self, wakeup: Arc<(Mutex<bool>, Condvar)>) {
+        if let Some(ref mut ntp) = self.ntp {
+            ntp.start(wakeup);
+        }
+    }
+}
+
+/// Virtual time starting at a given moment; it runs `speed` times faster than real time (not at
+/// all with speed 0) and moves forward when it is advanced explicitly.
+pub struct SimulatedClock {
+    start: Timespec,
+    advanced: Duration,
+    speed: u32,
+    real_start: u64,
+}
+
+impl SimulatedClock {
+    pub fn new(start: Timespec, speed: u32) -> SimulatedClock {
+        SimulatedClock {
+            start: start,
+            advanced: Duration::zero(),
+            speed: speed,
+            real_start: precise_time_ns(),
+        }
+    }
+
+    pub fn advance(&mut self, interval: Duration) {
+        self.advanced = self.advanced + interval;
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&mut self) -> Option<Timespec> {
+        // i64 nanoseconds cover 292 years, so only absurd speeds saturate
+        let real = ((precise_time_ns() - self.real_start) as i64).saturating_mul(self.speed as i64);
+        Some(self.start + self.advanced + Duration::nanoseconds(real))
+    }
+
+    fn real_interval(&self, interval: Duration) -> Duration {
+        match self.speed {
+            0 => Duration::zero(),
+            speed => Duration::nanoseconds(interval.num_nanoseconds().unwrap_or(i64::max_value()) / speed as i64),
+        }
+    }
+
+    fn status(&self) -> TimeStatus {
+        TimeStatus {
+            source: Some(TimeSource::Simulated),
+            ntp_server: None,
+            last_sync: None,
+            offset: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::{Duration, Timespec};
+
+    const START: i64 = 1774742400;
+
+    #[test]
+    fn simulated_clock_at_max_speed() {
+        let mut clock = SimulatedClock::new(Timespec::new(START, 0), 0);
+        assert_eq!(clock.now(), Some(Timespec::new(START, 0)));
+
+        clock.advance(Duration::seconds(10));
+        clock.advance(Duration::minutes(5));
+        assert_eq!(clock.now(), Some(Timespec::new(START + 310, 0)));
+        assert_eq!(clock.real_interval(Duration::seconds(10)), Duration::zero());
+    }
+
+    #[test]
+    fn simulated_clock_runs_faster() {
+        let mut clock = SimulatedClock::new(Timespec::new(START, 0), 3600);
+        clock.advance(Duration::hours(1));
+
+        let now = clock.now().unwrap();
+        assert!(now >= Timespec::new(START + 3600, 0));
+        assert!(clock.now().unwrap() >= now);
+        assert_eq!(clock.real_interval(Duration::hours(1)), Duration::seconds(1));
+    }
+
+    #[test]
+    fn simulated_clock_at_extreme_speed() {
+        let mut clock = SimulatedClock::new(Timespec::new(START, 0), u32::max_value());
+
+        // the real time multiplied by the speed does not wrap around
+        assert!(clock.now().unwrap() >= Timespec::new(START, 0));
+        assert!(clock.real_interval(Duration::seconds(10)) >= Duration::zero());
+    }
+}
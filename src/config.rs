@@ -1,6 +1,9 @@
 // This module loads the configuration file
 
+use clock::zone_time;
 use dailyschedule::{DailyEvent, Filter, Moment};
+use std::cmp;
+use std::collections::BTreeMap;
 use std::convert::Into;
 use std::error;
 use std::fmt;
@@ -9,34 +12,176 @@ use std::io;
 use std::io::prelude::*;
 use std::path;
 use std::result;
-use time::{Duration, at_utc};
+use time::{Duration, Timespec, Tm, at_utc};
 use toml;
 use daylight::calculate_daylight;
+use zoneinfo::ZoneInfo;
 
 const CONFIG_HEAD: &'static str = "config";
 const CONFIG_DEVICE: &'static str = "device";
 const CONFIG_LATITUDE: &'static str = "latitude";
 const CONFIG_LONGITUDE: &'static str = "longitude";
 const CONFIG_NTP_SERVER: &'static str = "ntp";
+const CONFIG_SOCKET: &'static str = "socket";
+const CONFIG_SHUTDOWN: &'static str = "shutdown";
+const CONFIG_TIME_SOURCE: &'static str = "time_source";
+const CONFIG_NTP_TIMEOUT: &'static str = "ntp_timeout";
+const CONFIG_TIMEZONE: &'static str = "timezone";
+const CONFIG_SITE: &'static str = "site";
+const CONFIG_STICK: &'static str = "stick";
+const CONFIG_TEMPLATE: &'static str = "template";
+const CONFIG_INCLUDE: &'static str = "include";
+/// directory next to the configuration file; its `*.toml` files are merged into the configuration
+const CONFIG_DIR: &'static str = "conf.d";
+const CONFIG_EXTENSION: &'static str = "toml";
+const STICK_DEVICE: &'static str = "device";
+/// name of the stick configured by the `device` key of `[config]`
+pub const DEFAULT_STICK: &'static str = "default";
+const DEFAULT_NTP_TIMEOUT: i64 = 120;
 const CIRCLE_MAC: &'static str = "mac";
 const CIRCLE_DEFAULT: &'static str = "default";
+const CIRCLE_SHUTDOWN: &'static str = "shutdown";
+const CIRCLE_SITE: &'static str = "site";
+const CIRCLE_STICK: &'static str = "stick";
+const CIRCLE_DRIVER: &'static str = "driver";
+const CIRCLE_ON_URL: &'static str = "on_url";
+const CIRCLE_OFF_URL: &'static str = "off_url";
+const CIRCLE_ON_COMMAND: &'static str = "on_command";
+const CIRCLE_OFF_COMMAND: &'static str = "off_command";
+const CIRCLE_LEVEL_URL: &'static str = "level_url";
+const CIRCLE_LEVEL_COMMAND: &'static str = "level_command";
+const CIRCLE_TEMPLATE: &'static str = "template";
+const CIRCLE_TEMPLATES: &'static str = "templates";
+const DRIVER_PLUGWISE: &'static str = "plugwise";
+const DRIVER_HTTP: &'static str = "http";
+const DRIVER_COMMAND: &'static str = "command";
+const TOGGLE_START: &'static str = "start_";
+const TOGGLE_END: &'static str = "end_";
+const TOGGLE_LEVEL: &'static str = "level";
+const TOGGLE_FADE_IN: &'static str = "fade_in";
+const TOGGLE_FADE_OUT: &'static str = "fade_out";
+const TOGGLE_WHEN: &'static str = "when";
+const TOGGLE_MIN_DAYLIGHT: &'static str = "min_daylight";
+const TOGGLE_MAX_DAYLIGHT: &'static str = "max_daylight";
+/// keys of a circle table, which cannot be used as toggle names
+const CIRCLE_KEYS: &'static [&'static str] = &[
+    CIRCLE_MAC, CIRCLE_DEFAULT, CIRCLE_SHUTDOWN, CIRCLE_SITE, CIRCLE_STICK, CIRCLE_DRIVER,
+    CIRCLE_ON_URL, CIRCLE_OFF_URL, CIRCLE_ON_COMMAND, CIRCLE_OFF_COMMAND, CIRCLE_LEVEL_URL,
+    CIRCLE_LEVEL_COMMAND, CIRCLE_TEMPLATE, CIRCLE_TEMPLATES, CONFIG_LATITUDE, CONFIG_LONGITUDE,
+    CONFIG_TIMEZONE];
+/// keys of a circle table that select how its relay is operated
+const DRIVER_KEYS: &'static [&'static str] = &[
+    CIRCLE_DRIVER, CIRCLE_ON_URL, CIRCLE_OFF_URL, CIRCLE_LEVEL_URL, CIRCLE_ON_COMMAND,
+    CIRCLE_OFF_COMMAND, CIRCLE_LEVEL_COMMAND];
+const MAX_LEVEL: i64 = 100;
+/// longest fade in minutes
+const MAX_FADE: i64 = 12 * 60;
+const EVENT_SPECIFIERS: &'static str = "fixed, fuzzy, sunrise or sunset";
 
 pub type Result<T> = result::Result<T, Error>;
 
+/// Key/value pairs of a table
+pub type Pairs = Vec<(String, toml::Value)>;
+
+/// Tables of a configuration file: the dotted name and the key/value pairs of every table
+pub type Tables = Vec<(String, Pairs)>;
+
+/// Position of a item in the configuration file, including the circle and
+/// toggle it belongs to (when applicable).
+#[derive(Debug, Clone, Default)]
+pub struct Location {
+    /// the configuration file, when known
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+    pub table: Option<String>,
+    pub toggle: Option<String>,
+    pub key: Option<String>,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref file) = self.file {
+            try!(write!(f, "{}, ", file));
+        }
+        if self.line > 0 {
+            try!(write!(f, "line {}, column {}", self.line, self.column));
+        } else {
+            try!(write!(f, "unknown position"));
+        }
+        match (self.table.as_ref(), self.toggle.as_ref()) {
+            (Some(table), Some(name)) if table == CONFIG_SITE || table == CONFIG_STICK || table == CONFIG_TEMPLATE =>
+                try!(write!(f, " in [{}.{}]", table, name)),
+            // a template and its toggles
+            (Some(table), toggle) if table.starts_with(&format!("{}.", CONFIG_TEMPLATE)) => {
+                try!(write!(f, " in [{}]", table));
+                if let Some(toggle) = toggle {
+                    try!(write!(f, ", toggle '{}'", toggle));
+                }
+            },
+            (Some(table), toggle) => {
+                if table == CONFIG_HEAD {
+                    try!(write!(f, " in [{}]", table));
+                } else {
+                    try!(write!(f, " in circle '{}'", table));
+                }
+                if let Some(toggle) = toggle {
+                    try!(write!(f, ", toggle '{}'", toggle));
+                }
+            },
+            (None, _) => {}
+        }
+        if let Some(ref key) = self.key {
+            try!(write!(f, ", key '{}'", key));
+        }
+        Ok(())
+    }
+}
+
+/// A syntax error reported by the TOML parser
+#[derive(Debug, Clone)]
+pub struct TomlError {
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+    pub description: String,
+}
+
+impl fmt::Display for TomlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref file) = self.file {
+            try!(write!(f, "{}, ", file));
+        }
+        write!(f, "line {}, column {}: {}", self.line, self.column, self.description)
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     Io(io::Error),
-    MissingEventSpecifier(String),
-    WrongEventSpecifier(String),
-    MissingStartEvent(String),
-    MissingEndEvent(String),
-    ScheduleExpected(String),
-    InvalidMac(String),
-    InvalidDefault(String),
+    MissingEventSpecifier(Location),
+    WrongEventSpecifier(Location, &'static str),
+    MissingStartEvent(Location),
+    MissingEndEvent(Location),
+    DuplicateEvent(Location, &'static str),
+    InvalidMac(Location),
+    InvalidDefault(Location),
+    InvalidShutdown(Location),
+    InvalidTimeSource(Location),
+    InvalidValue(Location, &'static str),
+    UnknownKey(Location, &'static str),
     MissingConfig,
-    MissingNTP,
-    LocationMissing,
-    InvalidToml,
+    MissingNTP(Location),
+    LocationMissing(Location),
+    /// a NTP timeout without the fallback time source it applies to
+    UnusedNtpTimeout(Location),
+    InvalidToml(Vec<TomlError>),
+    /// a included file (or directory) that cannot be read
+    Include(String, io::Error),
+    /// a table that is defined in more than one file, and where it was defined first
+    DuplicateTable(Location, Location),
+    /// a mac used by two circles, and the circle that uses it first
+    DuplicateMac(Location, Location),
 }
 
 impl From<io::Error> for Error {
@@ -49,7 +194,53 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Error::Io(ref err) => fmt::Display::fmt(err, f),
-            _ => write!(f, "Keeper configuraton error"),
+            Error::MissingEventSpecifier(ref loc) =>
+                write!(f, "{}: missing event specifier, expected one of {}", loc, EVENT_SPECIFIERS),
+            Error::WrongEventSpecifier(ref loc, expected) =>
+                write!(f, "{}: {}", loc, expected),
+            Error::MissingStartEvent(ref loc) =>
+                write!(f, "{}: missing start event, expected a start_* key ({})", loc, EVENT_SPECIFIERS),
+            Error::MissingEndEvent(ref loc) =>
+                write!(f, "{}: missing end event, expected a end_* key ({})", loc, EVENT_SPECIFIERS),
+            Error::DuplicateEvent(ref loc, kind) =>
+                write!(f, "{}: toggle holds more than one {} event, expected exactly one (use a \
+                           separate toggle table for every on-period)", loc, kind),
+            Error::InvalidMac(ref loc) =>
+                write!(f, "{}: invalid or missing mac, expected a hexadecimal string", loc),
+            Error::InvalidDefault(ref loc) =>
+                write!(f, "{}: invalid or missing default, expected \"off\", \"on\" or \"schedule\"", loc),
+            Error::InvalidTimeSource(ref loc) =>
+                write!(f, "{}: invalid time source, expected \"ntp\", \"fallback\" or \"system\"", loc),
+            Error::InvalidShutdown(ref loc) =>
+                write!(f, "{}: invalid shutdown policy, expected \"keep\", \"off\" or \"default\"", loc),
+            Error::InvalidValue(ref loc, expected) =>
+                write!(f, "{}: invalid value, expected {}", loc, expected),
+            Error::UnknownKey(ref loc, expected) =>
+                write!(f, "{}: unknown key, expected {}", loc, expected),
+            Error::MissingConfig =>
+                write!(f, "missing [{}] table", CONFIG_HEAD),
+            Error::MissingNTP(ref loc) =>
+                write!(f, "{}: missing '{}' key, expected a NTP server name or a array of names", loc,
+                       CONFIG_NTP_SERVER),
+            Error::LocationMissing(ref loc) =>
+                write!(f, "{}: missing '{}' and/or '{}' key, expected a float", loc,
+                       CONFIG_LATITUDE, CONFIG_LONGITUDE),
+            Error::UnusedNtpTimeout(ref loc) =>
+                write!(f, "{}: '{}' only applies to {} = \"fallback\"", loc, CONFIG_NTP_TIMEOUT,
+                       CONFIG_TIME_SOURCE),
+            Error::InvalidToml(ref errors) => {
+                try!(write!(f, "invalid TOML"));
+                for err in errors {
+                    try!(write!(f, "; {}", err));
+                }
+                Ok(())
+            },
+            Error::Include(ref file, ref err) =>
+                write!(f, "unable to read included {}: {}", file, err),
+            Error::DuplicateTable(ref loc, ref first) =>
+                write!(f, "{}: duplicate table, already defined at {}", loc, first),
+            Error::DuplicateMac(ref loc, ref first) =>
+                write!(f, "{}: duplicate mac, already used at {}", loc, first),
         }
     }
 }
@@ -58,19 +249,108 @@ impl error::Error for Error {
     fn description(&self) -> &str {
         match *self {
             Error::Io(ref err) => error::Error::description(err),
+            Error::InvalidToml(_) => "Keeper configuration contains invalid TOML",
             _ => "Keeper configuration error",
         }
     }
 
     fn cause(&self) -> Option<&error::Error> {
         match *self {
-            Error::Io(ref err) => err.cause(),
+            Error::Io(ref err) | Error::Include(_, ref err) => err.cause(),
             _ => None,
         }
     }
 }
 
-#[derive(Debug)]
+/// Keeps track of the line and column of every table and key in the TOML
+/// source, since the TOML parser only provides positions for syntax errors.
+struct Locator {
+    file: Option<String>,
+    positions: BTreeMap<(String, String), (usize, usize)>,
+}
+
+impl Locator {
+    fn new(source: &str, file: Option<String>) -> Locator {
+        let mut positions = BTreeMap::new();
+        let mut table = String::new();
+
+        for (n, line) in source.lines().enumerate() {
+            let trimmed = line.trim_left();
+            let column = line.len() - trimmed.len() + 1;
+
+            if let Some(header) = Locator::table_header(trimmed) {
+                table = header;
+                positions.entry((table.clone(), String::new())).or_insert((n + 1, column));
+            } else if let Some(eq) = trimmed.find('=') {
+                let key = trimmed[..eq].trim().trim_matches('"');
+                if !key.is_empty() && !key.starts_with('#') {
+                    positions.entry((table.clone(), key.into())).or_insert((n + 1, column));
+                }
+            }
+        }
+
+        Locator {
+            file: file,
+            positions: positions
+        }
+    }
+
+    /// Extract the (dotted) table name of a `[table]` or `[[table]]` line
+    fn table_header(line: &str) -> Option<String> {
+        if !line.starts_with('[') {
+            return None;
+        }
+
+        let name = line.trim_left_matches('[');
+        name.find(']').and_then(|end| {
+            let name = &name[..end];
+            let valid = name.chars().all(|c| c.is_alphanumeric() || "_-.\" ".contains(c));
+
+            if valid && !name.trim().is_empty() {
+                let parts: Vec<_> = name.split('.').map(|s| s.trim().trim_matches('"')).collect();
+                Some(parts.join("."))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Locate a key of a circle (or `[config]`) table, optionally inside a toggle table. When
+    /// the key cannot be found, the position of the surrounding table is used.
+    fn locate(&self, table: &str, toggle: Option<&str>, key: Option<&str>) -> Location {
+        let path = toggle.map_or(table.into(), |t| format!("{}.{}", table, t));
+        let mut candidates = vec![];
+
+        if let Some(key) = key {
+            candidates.push((path.clone(), key.into()));
+            if let Some(toggle) = toggle {
+                // inline toggle table: `toggle = { ... }`
+                candidates.push((table.into(), toggle.into()));
+            }
+        }
+        candidates.push((path, String::new()));
+        if let Some(toggle) = toggle {
+            candidates.push((table.into(), toggle.into()));
+        }
+        candidates.push((table.into(), String::new()));
+
+        let (line, column) = candidates.iter()
+                                      .filter_map(|c| self.positions.get(c))
+                                      .next()
+                                      .map_or((0, 0), |&p| p);
+
+        Location {
+            file: self.file.clone(),
+            line: line,
+            column: column,
+            table: Some(table.into()),
+            toggle: toggle.map(|t| t.into()),
+            key: key.map(|k| k.into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum Event {
     Fixed(u8, u8),
     Fuzzy((u8, u8), (u8, u8)),
@@ -99,13 +379,13 @@ impl Event {
         })
     }
 
-    fn new(key: &str, value: &toml::Value) -> Result<Event> {
-        let specifier = try!(key.split("_").last().ok_or_else(||
-                Error::MissingEventSpecifier(key.into())));
+    fn new(key: &str, value: &toml::Value, location: Location) -> Result<Event> {
+        let specifier = key.split("_").last().unwrap_or("");
 
         match specifier {
+            "" => Err(Error::MissingEventSpecifier(location)),
             "fixed" => Event::time_in_a_day(value).map(|(f,s)| Event::Fixed(f,s)).ok_or_else(||
-                Error::WrongEventSpecifier("fixed must hold a array of two integers".into())),
+                Error::WrongEventSpecifier(location, "fixed must hold a array of two integers ([hour, minute])")),
             "fuzzy" => value.as_slice().map_or(None, |s| {
                     match s.len() {
                         2 => {
@@ -115,18 +395,77 @@ impl Event {
                         },
                         _ => None
                     }
-                }).ok_or_else(|| Error::WrongEventSpecifier("fuzzy must hold a array of two arrays of two integers".into())),
-            "sunrise" => value.as_integer().map(|i| Event::Sunrise(i as u16)).ok_or_else(||
-                Error::WrongEventSpecifier("sunrise must only hold one integer (variance in minutes)".into())),
-            "sunset" => value.as_integer().map(|i| Event::Sunset(i as u16)).ok_or_else(||
-                Error::WrongEventSpecifier("sunset must only hold one integer (variance in minutes)".into())),
-            _ => Err(Error::WrongEventSpecifier("unsupported specifier".into()))
+                }).ok_or_else(|| Error::WrongEventSpecifier(location,
+                    "fuzzy must hold a array of two arrays of two integers ([[hour, minute], [hour, minute]])")),
+            "sunrise" => value.as_integer().and_then(Event::variance).map(Event::Sunrise).ok_or_else(||
+                Error::WrongEventSpecifier(location, "sunrise must only hold one positive integer (variance in minutes)")),
+            "sunset" => value.as_integer().and_then(Event::variance).map(Event::Sunset).ok_or_else(||
+                Error::WrongEventSpecifier(location, "sunset must only hold one positive integer (variance in minutes)")),
+            _ => Err(Error::WrongEventSpecifier(location, "unsupported specifier, expected fixed, fuzzy, sunrise or sunset"))
+        }
+    }
+
+    /// Specifier (the suffix of the key) and value of the event in a toggle table
+    fn to_toml(&self) -> (&'static str, toml::Value) {
+        let time = |(h, m): (u8, u8)| toml::Value::Array(vec![toml::Value::Integer(h as i64),
+                                                               toml::Value::Integer(m as i64)]);
+        match *self {
+            Event::Fixed(h, m) => ("fixed", time((h, m))),
+            Event::Fuzzy(first, second) => ("fuzzy", toml::Value::Array(vec![time(first), time(second)])),
+            Event::Sunrise(variance) => ("sunrise", toml::Value::Integer(variance as i64)),
+            Event::Sunset(variance) => ("sunset", toml::Value::Integer(variance as i64)),
+        }
+    }
+
+    /// Earliest and latest moment of the event in minutes since midnight (when known upfront)
+    fn bounds(&self) -> Option<(u16, u16)> {
+        let minutes = |(h, m): (u8, u8)| h as u16 * 60 + m as u16;
+
+        match *self {
+            Event::Fixed(h, m) => Some((minutes((h, m)), minutes((h, m)))),
+            Event::Fuzzy(first, second) => {
+                let (first, second) = (minutes(first), minutes(second));
+                Some((cmp::min(first, second), cmp::max(first, second)))
+            },
+            Event::Sunrise(_) | Event::Sunset(_) => None
+        }
+    }
+
+    fn variance(minutes: i64) -> Option<u16> {
+        if minutes < 0 || minutes > u16::max_value() as i64 {
+            None
+        } else {
+            Some(minutes as u16)
+        }
+    }
+
+    /// Moment of sunrise events: halfway the morning twilight and the sunrise
+    fn sunrise(t: Timespec, latitude: f64, longitude: f64) -> Timespec {
+        let daylight = calculate_daylight(at_utc(t), latitude, longitude);
+        let dusk = Duration::seconds((daylight.sunrise - daylight.twilight_morning).num_seconds() / 2);
+        daylight.twilight_morning + dusk
+    }
+
+    /// Moment of sunset events: halfway the sunset and the evening twilight
+    fn sunset(t: Timespec, latitude: f64, longitude: f64) -> Timespec {
+        let daylight = calculate_daylight(at_utc(t), latitude, longitude);
+        let dusk = Duration::seconds((daylight.twilight_evening - daylight.sunset).num_seconds() / 2);
+        daylight.sunset + dusk
+    }
+
+    /// Moment of a sun based event (without variance) on the day of `day`; `None` for the other
+    /// events
+    pub fn sun_time(&self, day: Timespec, site: &Site) -> Option<Timespec> {
+        match *self {
+            Event::Sunrise(_) => Some(Event::sunrise(day, site.latitude, site.longitude)),
+            Event::Sunset(_) => Some(Event::sunset(day, site.latitude, site.longitude)),
+            Event::Fixed(..) | Event::Fuzzy(..) => None,
         }
     }
 
-    pub fn create_dailyevent(&self, device: &Device) -> DailyEvent {
-        let latitude = device.latitude;
-        let longitude = device.longitude;
+    pub fn create_dailyevent(&self, site: &Site) -> DailyEvent {
+        let latitude = site.latitude;
+        let longitude = site.longitude;
         match *self {
             Event::Fixed(h,m) => DailyEvent::Fixed(Filter::Always, Moment::new(h,m,0)),
             Event::Fuzzy((h1,m1),(h2,m2)) =>
@@ -134,49 +473,257 @@ impl Event {
             Event::Sunrise(m) =>
                 DailyEvent::ByClosure(
                     Filter::Always,
-                    Box::new(move|t| {
-                        let daylight = calculate_daylight(at_utc(t), latitude, longitude);
-                        let dusk = Duration::seconds((daylight.sunrise -
-                                                      daylight.twilight_morning).num_seconds() / 2);
-                        Moment::new_from_timespec(daylight.twilight_morning + dusk)
-                    }), Duration::minutes(m as i64)),
+                    Box::new(move|t| Moment::new_from_timespec(Event::sunrise(t, latitude, longitude))),
+                    Duration::minutes(m as i64)),
             Event::Sunset(m) =>
                 DailyEvent::ByClosure(
                     Filter::Always,
-                    Box::new(move|t| {
-                        let daylight = calculate_daylight(at_utc(t), latitude, longitude);
-                        let dusk = Duration::seconds((daylight.twilight_evening -
-                                                      daylight.sunset).num_seconds() / 2);
-                        Moment::new_from_timespec(daylight.sunset + dusk)
-                    }), Duration::minutes(m as i64)),
+                    Box::new(move|t| Moment::new_from_timespec(Event::sunset(t, latitude, longitude))),
+                    Duration::minutes(m as i64)),
         }
     }
 }
 
-#[derive(Debug)]
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Event::Fixed(h, m) => write!(f, "{:02}:{:02}", h, m),
+            Event::Fuzzy((h1, m1), (h2, m2)) => write!(f, "{:02}:{:02}-{:02}:{:02}", h1, m1, h2, m2),
+            Event::Sunrise(0) => write!(f, "sunrise"),
+            Event::Sunrise(variance) => write!(f, "sunrise ±{}m", variance),
+            Event::Sunset(0) => write!(f, "sunset"),
+            Event::Sunset(variance) => write!(f, "sunset ±{}m", variance),
+        }
+    }
+}
+
+/// Condition of a toggle, evaluated for every day: the toggle is skipped on the days the
+/// condition does not hold
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Condition {
+    /// only when the toggle starts in the dark (`true`) or in daylight (`false`), that is before
+    /// sunrise or after sunset, or in between
+    pub dark: Option<bool>,
+    /// shortest daylight (from sunrise to sunset) in minutes
+    pub min_daylight: Option<u16>,
+    /// longest daylight in minutes
+    pub max_daylight: Option<u16>,
+}
+
+impl Condition {
+    pub fn is_always(&self) -> bool {
+        *self == Condition::default()
+    }
+
+    /// Noon (UTC) of the date of the site at `t`, so the sun is calculated for that date instead
+    /// of the UTC date
+    fn local_noon(t: Timespec, zoneinfo: &ZoneInfo) -> Tm {
+        Tm { tm_hour: 12, tm_min: 0, tm_sec: 0, tm_nsec: 0, tm_utcoff: 0, ..zone_time(zoneinfo, t) }
+    }
+
+    /// Why the toggle is skipped for the on-period starting at `start`; `None` when the
+    /// condition holds. The actual sunrise and sunset (not the moments of the sun based events)
+    /// on the date of the site are used.
+    pub fn skip_reason(&self, start: Timespec, site: &Site, zoneinfo: &ZoneInfo) -> Option<String> {
+        if self.is_always() {
+            return None;
+        }
+
+        let daylight = calculate_daylight(Condition::local_noon(start, zoneinfo), site.latitude, site.longitude);
+        self.skip_reason_between(start, daylight.sunrise, daylight.sunset)
+    }
+
+    fn skip_reason_between(&self, start: Timespec, sunrise: Timespec, sunset: Timespec) -> Option<String> {
+        let daylight = (sunset - sunrise).num_minutes();
+        let duration = |minutes: i64| format!("{}:{:02}", minutes / 60, minutes % 60);
+
+        match self.dark {
+            Some(true) if start >= sunrise && start <= sunset =>
+                return Some(String::from("starts in daylight, but only when dark")),
+            Some(false) if start < sunrise || start > sunset =>
+                return Some(String::from("starts in the dark, but only when light")),
+            _ => {}
+        }
+        if let Some(min) = self.min_daylight {
+            if daylight < min as i64 {
+                return Some(format!("daylight of {} is shorter than {}", duration(daylight), duration(min as i64)));
+            }
+        }
+        if let Some(max) = self.max_daylight {
+            if daylight > max as i64 {
+                return Some(format!("daylight of {} is longer than {}", duration(daylight), duration(max as i64)));
+            }
+        }
+        None
+    }
+
+    fn parse_daylight(value: &toml::Value) -> Option<u16> {
+        Event::time_in_a_day(value).map(|(h, m)| h as u16 * 60 + m as u16).and_then(|minutes| {
+            if minutes <= 24 * 60 { Some(minutes) } else { None }
+        })
+    }
+
+    /// Keys and values of the condition in a toggle table
+    fn to_toml(&self) -> Pairs {
+        let daylight = |minutes: u16| toml::Value::Array(vec![toml::Value::Integer(minutes as i64 / 60),
+                                                              toml::Value::Integer(minutes as i64 % 60)]);
+        let mut pairs = vec![];
+
+        if let Some(dark) = self.dark {
+            pairs.push((TOGGLE_WHEN.into(), toml::Value::String(String::from(if dark { "dark" } else { "light" }))));
+        }
+        if let Some(min) = self.min_daylight {
+            pairs.push((TOGGLE_MIN_DAYLIGHT.into(), daylight(min)));
+        }
+        if let Some(max) = self.max_daylight {
+            pairs.push((TOGGLE_MAX_DAYLIGHT.into(), daylight(max)));
+        }
+        pairs
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = vec![];
+
+        if let Some(dark) = self.dark {
+            parts.push(String::from(if dark { "when dark" } else { "when light" }));
+        }
+        if let Some(min) = self.min_daylight {
+            parts.push(format!("daylight at least {}:{:02}", min / 60, min % 60));
+        }
+        if let Some(max) = self.max_daylight {
+            parts.push(format!("daylight at most {}:{:02}", max / 60, max % 60));
+        }
+        write!(f, "{}", if parts.is_empty() { String::from("always") } else { parts.join(", ") })
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Toggle {
     pub alias: String,
     pub start: Event,
-    pub end: Event
+    pub end: Event,
+    /// level (percent) of the switch during the toggle
+    pub level: u8,
+    /// minutes before the start event during which the level rises from 0
+    pub fade_in: u16,
+    /// minutes after the end event during which the level falls to 0
+    pub fade_out: u16,
+    /// days on which the toggle is used
+    pub condition: Condition,
+    /// template the toggle comes from; `None` for the toggles of the circle itself
+    pub template: Option<String>,
 }
 
 impl Toggle {
-    fn new(alias: &str, table: &toml::Table) -> Result<Toggle> {
-        let start = try!(table.iter().find(|&(k,_)| k.starts_with("start_")).map_or(
-                Err(Error::MissingStartEvent(alias.into())),
-                |(k,v)| Event::new(&k[..], v)));
-        let end = try!(table.iter().find(|&(k,_)| k.starts_with("end_")).map_or(
-                Err(Error::MissingEndEvent(alias.into())),
-                |(k,v)| Event::new(&k[..], v)));
+    fn new(circle: &str, alias: &str, table: &toml::Table, locator: &Locator) -> Result<Toggle> {
+        let locate = |key: Option<&str>| locator.locate(circle, Some(alias), key);
+        let mut start = None;
+        let mut end = None;
+        let mut level = None;
+        let mut fades = (None, None);
+        let mut condition = Condition::default();
+
+        for (k, v) in table {
+            if k == TOGGLE_LEVEL {
+                level = Some(try!(v.as_integer()
+                                   .and_then(|l| if l > 0 && l <= MAX_LEVEL { Some(l as u8) } else { None })
+                                   .ok_or(Error::InvalidValue(locate(Some(&k[..])), "an integer from 1 to 100 (percent)"))));
+            } else if k == TOGGLE_FADE_IN || k == TOGGLE_FADE_OUT {
+                let fade = Some(try!(v.as_integer()
+                                      .and_then(|m| if m >= 0 && m <= MAX_FADE { Some(m as u16) } else { None })
+                                      .ok_or(Error::InvalidValue(locate(Some(&k[..])),
+                                             "an integer from 0 to 720 (minutes)"))));
+                if k == TOGGLE_FADE_IN {
+                    fades.0 = fade;
+                } else {
+                    fades.1 = fade;
+                }
+            } else if k == TOGGLE_WHEN {
+                condition.dark = Some(try!(v.as_str()
+                                            .and_then(|s| match s {
+                                                "dark" => Some(true),
+                                                "light" => Some(false),
+                                                _ => None
+                                            })
+                                            .ok_or(Error::InvalidValue(locate(Some(&k[..])), "\"dark\" or \"light\""))));
+            } else if k == TOGGLE_MIN_DAYLIGHT || k == TOGGLE_MAX_DAYLIGHT {
+                let daylight = Some(try!(Condition::parse_daylight(v).ok_or(Error::InvalidValue(locate(Some(&k[..])),
+                                         "a array of two integers ([hours, minutes]) of at most 24 hours"))));
+                if k == TOGGLE_MIN_DAYLIGHT {
+                    condition.min_daylight = daylight;
+                } else {
+                    condition.max_daylight = daylight;
+                }
+            } else if k.starts_with(TOGGLE_START) {
+                if start.is_some() {
+                    return Err(Error::DuplicateEvent(locate(Some(&k[..])), "start"));
+                }
+                start = Some(try!(Event::new(&k[..], v, locate(Some(&k[..])))));
+            } else if k.starts_with(TOGGLE_END) {
+                if end.is_some() {
+                    return Err(Error::DuplicateEvent(locate(Some(&k[..])), "end"));
+                }
+                end = Some(try!(Event::new(&k[..], v, locate(Some(&k[..])))));
+            } else {
+                return Err(Error::UnknownKey(locate(Some(&k[..])),
+                    "a start_* or end_* event, 'level', 'fade_in', 'fade_out', 'when', 'min_daylight' \
+                     or 'max_daylight'"));
+            }
+        }
+
         Ok(Toggle {
             alias: alias.into(),
-            start: start,
-            end: end
+            start: try!(start.ok_or_else(|| Error::MissingStartEvent(locate(None)))),
+            end: try!(end.ok_or_else(|| Error::MissingEndEvent(locate(None)))),
+            level: level.unwrap_or(MAX_LEVEL as u8),
+            fade_in: fades.0.unwrap_or(0),
+            fade_out: fades.1.unwrap_or(0),
+            condition: condition,
+            template: None,
         })
     }
+
+    /// Keys and values of the toggle table; the default level, fades and condition are left out
+    pub fn to_toml(&self) -> Pairs {
+        let (start, start_value) = self.start.to_toml();
+        let (end, end_value) = self.end.to_toml();
+        let mut pairs = vec![(format!("{}{}", TOGGLE_START, start), start_value),
+                             (format!("{}{}", TOGGLE_END, end), end_value)];
+
+        if self.level as i64 != MAX_LEVEL {
+            pairs.push((TOGGLE_LEVEL.into(), toml::Value::Integer(self.level as i64)));
+        }
+        if self.fade_in > 0 {
+            pairs.push((TOGGLE_FADE_IN.into(), toml::Value::Integer(self.fade_in as i64)));
+        }
+        if self.fade_out > 0 {
+            pairs.push((TOGGLE_FADE_OUT.into(), toml::Value::Integer(self.fade_out as i64)));
+        }
+        pairs.extend(self.condition.to_toml());
+
+        pairs
+    }
+
+    /// Earliest start and latest end of the toggle (including its fades) in minutes since
+    /// midnight, only known when both events are not depending on the position of the sun.
+    fn window(&self) -> Option<(u16, u16)> {
+        self.start.bounds().and_then(|(start, _)| self.end.bounds().map(|(_, end)| {
+            let day = 24 * 60;
+            let end = if end <= start {
+                // toggle ends the next day
+                end + day
+            } else {
+                end
+            };
+            // shifted by a day, so a fade in before midnight stays positive
+            (start + day - self.fade_in, end + day + self.fade_out)
+        }))
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum CircleSetting {
     Off,
     On,
@@ -184,6 +731,14 @@ pub enum CircleSetting {
 }
 
 impl CircleSetting {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            CircleSetting::Off => "off",
+            CircleSetting::On => "on",
+            CircleSetting::Schedule => "schedule",
+        }
+    }
+
     fn new(setting_as_str: &str) -> Option<CircleSetting> {
         match setting_as_str {
             "off" => Some(CircleSetting::Off),
@@ -194,128 +749,1389 @@ impl CircleSetting {
     }
 }
 
-#[derive(Debug)]
+/// State a circle is left in when keeper shuts down
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShutdownPolicy {
+    /// leave the relay as it is
+    Keep,
+    /// switch the relay off
+    Off,
+    /// switch the relay to its default; for scheduled circles the currently scheduled state
+    Default
+}
+
+impl ShutdownPolicy {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ShutdownPolicy::Keep => "keep",
+            ShutdownPolicy::Off => "off",
+            ShutdownPolicy::Default => "default",
+        }
+    }
+
+    fn new(policy_as_str: &str) -> Option<ShutdownPolicy> {
+        match policy_as_str {
+            "keep" => Some(ShutdownPolicy::Keep),
+            "off" => Some(ShutdownPolicy::Off),
+            "default" => Some(ShutdownPolicy::Default),
+            _ => None
+        }
+    }
+}
+
+/// Position and time zone used for the sun based and fixed events of a circle
+#[derive(Debug, Clone, PartialEq)]
+pub struct Site {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// name of the time zone (e.g. "Europe/Amsterdam"); the local time zone when `None`
+    pub timezone: Option<String>,
+}
+
+/// Site settings of a `[site.<name>]` table or a circle; settings that are not given are taken
+/// from the surrounding level (`[config]`, the named site, the circle)
+#[derive(Debug, Clone, Default)]
+pub struct SiteSettings {
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    timezone: Option<String>,
+}
+
+impl SiteSettings {
+    /// Parse a site key; returns `false` when the key is no site key
+    fn parse_key(&mut self, key: &str, value: &toml::Value, location: Location) -> Result<bool> {
+        match key {
+            CONFIG_LATITUDE => {
+                self.latitude = Some(try!(value.as_float().ok_or(
+                            Error::InvalidValue(location, "a float (degrees)"))));
+            },
+            CONFIG_LONGITUDE => {
+                self.longitude = Some(try!(value.as_float().ok_or(
+                            Error::InvalidValue(location, "a float (degrees)"))));
+            },
+            CONFIG_TIMEZONE => {
+                self.timezone = Some(try!(value.as_str()
+                                               .and_then(|tz| ZoneInfo::by_tz(tz).ok().map(|_| String::from(tz)))
+                                               .ok_or(Error::InvalidValue(location,
+                                                      "a time zone name (e.g. \"Europe/Amsterdam\")"))));
+            },
+            _ => return Ok(false)
+        }
+        Ok(true)
+    }
+
+    fn apply(&self, site: &Site) -> Site {
+        Site {
+            latitude: self.latitude.unwrap_or(site.latitude),
+            longitude: self.longitude.unwrap_or(site.longitude),
+            timezone: self.timezone.clone().or(site.timezone.clone()),
+        }
+    }
+
+    /// Site keys of the settings of `site` that differ from `base`
+    fn to_toml(site: &Site, base: &Site) -> Pairs {
+        let mut pairs = vec![];
+
+        if site.latitude != base.latitude {
+            pairs.push((CONFIG_LATITUDE.into(), toml::Value::Float(site.latitude)));
+        }
+        if site.longitude != base.longitude {
+            pairs.push((CONFIG_LONGITUDE.into(), toml::Value::Float(site.longitude)));
+        }
+        if site.timezone != base.timezone {
+            if let Some(ref timezone) = site.timezone {
+                pairs.push((CONFIG_TIMEZONE.into(), toml::Value::String(timezone.clone())));
+            }
+        }
+
+        pairs
+    }
+}
+
+/// A Plugwise stick; every stick controls its own network of circles
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stick {
+    pub name: String,
+    /// serial device of the stick; the plugwise simulator is used when `None`
+    pub device: Option<String>,
+}
+
+/// Backend that operates the relay of a circle
+#[derive(Debug, Clone, PartialEq)]
+pub enum Driver {
+    /// Plugwise circle (mac) connected to the named stick
+    Plugwise(u64, String),
+    /// relay with a HTTP interface (e.g. Tasmota or Shelly): the on, off and (for dimmers) level URL
+    Http(String, String, Option<String>),
+    /// relay operated by a shell command (e.g. a GPIO relay board): the on, off and (for dimmers)
+    /// level command
+    Command(String, String, Option<String>),
+}
+
+#[derive(Debug, Clone)]
 pub struct Circle {
     pub alias: String,
-    pub mac: u64,
+    pub driver: Driver,
     pub default: CircleSetting,
+    /// overrides the shutdown policy of the `[config]` table
+    pub shutdown: Option<ShutdownPolicy>,
+    /// name of the `[site.<name>]` table the circle belongs to
+    pub site_name: Option<String>,
+    /// position and time zone of the circle (the site or `[config]` values, unless overridden)
+    pub site: Site,
+    /// templates whose toggles the circle uses, in order
+    pub templates: Vec<String>,
+    /// the toggles of the circle, followed by the toggles of its templates
     pub toggles: Vec<Toggle>
 }
 
 impl Circle {
-    fn new(alias: &str, table: &toml::Table) -> Result<Circle> {
+    fn new(alias: &str,
+           table: &toml::Table,
+           locator: &Locator,
+           device: &Device,
+           sites: &BTreeMap<String, SiteSettings>,
+           sticks: &[Stick],
+           templates: &BTreeMap<String, Vec<Toggle>>) -> Result<Circle> {
         let mut mac = None;
+        let mut stick = None;
+        let mut driver = None;
+        let mut urls = (None, None, None);
+        let mut commands = (None, None, None);
         let mut default = None;
+        let mut shutdown = None;
+        let mut site_name = None;
+        let mut settings = SiteSettings::default();
+        let mut template_names = vec![];
         let mut toggles = vec![];
 
         for (k, v) in table {
+            let location = locator.locate(alias, None, Some(&k[..]));
+
+            if try!(settings.parse_key(&k[..], v, location.clone())) {
+                continue;
+            }
+
             match &k[..] {
                 CIRCLE_MAC => {
-                    mac = v.as_str().map_or(None, |s| u64::from_str_radix(s, 16).ok());
+                    mac = Some(try!(v.as_str()
+                                     .and_then(|s| u64::from_str_radix(s, 16).ok())
+                                     .ok_or(Error::InvalidMac(location))));
                 },
                 CIRCLE_DEFAULT => {
-                    default = v.as_str().map_or(None, |s| CircleSetting::new(s));
+                    default = Some(try!(v.as_str()
+                                         .and_then(|s| CircleSetting::new(s))
+                                         .ok_or(Error::InvalidDefault(location))));
+                },
+                CIRCLE_SHUTDOWN => {
+                    shutdown = Some(try!(v.as_str()
+                                          .and_then(|s| ShutdownPolicy::new(s))
+                                          .ok_or(Error::InvalidShutdown(location))));
+                },
+                CIRCLE_STICK => {
+                    stick = Some(try!(v.as_str()
+                                       .and_then(|s| sticks.iter().find(|stick| stick.name == s))
+                                       .map(|stick| stick.name.clone())
+                                       .ok_or(Error::InvalidValue(location,
+                                              "the name of a [stick.<name>] table"))));
+                },
+                CIRCLE_DRIVER => {
+                    driver = Some(try!(v.as_str()
+                                        .and_then(|s| match s {
+                                            DRIVER_PLUGWISE | DRIVER_HTTP | DRIVER_COMMAND => Some(String::from(s)),
+                                            _ => None
+                                        })
+                                        .ok_or(Error::InvalidValue(location,
+                                               "\"plugwise\", \"http\" or \"command\""))));
+                },
+                CIRCLE_ON_URL | CIRCLE_OFF_URL | CIRCLE_LEVEL_URL |
+                CIRCLE_ON_COMMAND | CIRCLE_OFF_COMMAND | CIRCLE_LEVEL_COMMAND => {
+                    let value = Some(try!(v.as_str().map(String::from).ok_or(
+                                Error::InvalidValue(location, "a string"))));
+                    match &k[..] {
+                        CIRCLE_ON_URL => urls.0 = value,
+                        CIRCLE_OFF_URL => urls.1 = value,
+                        CIRCLE_LEVEL_URL => urls.2 = value,
+                        CIRCLE_ON_COMMAND => commands.0 = value,
+                        CIRCLE_OFF_COMMAND => commands.1 = value,
+                        _ => commands.2 = value,
+                    }
+                },
+                CIRCLE_TEMPLATE | CIRCLE_TEMPLATES => {
+                    let names = match *v {
+                        toml::Value::String(ref name) if k == CIRCLE_TEMPLATE => Some(vec![&name[..]]),
+                        toml::Value::Array(ref names) if k == CIRCLE_TEMPLATES => names.iter().map(|name| name.as_str()).collect(),
+                        _ => None
+                    };
+                    let names = try!(names.and_then(|names| {
+                        if names.iter().all(|name| templates.contains_key(*name)) { Some(names) } else { None }
+                    }).ok_or(Error::InvalidValue(location, if k == CIRCLE_TEMPLATE {
+                        "the name of a [template.<name>] table"
+                    } else {
+                        "a array of names of [template.<name>] tables"
+                    })));
+                    template_names.extend(names.into_iter().map(String::from));
+                },
+                CIRCLE_SITE => {
+                    site_name = Some(try!(v.as_str()
+                                           .and_then(|s| if sites.contains_key(s) { Some(String::from(s)) } else { None })
+                                           .ok_or(Error::InvalidValue(location,
+                                                  "the name of a [site.<name>] table"))));
                 },
                 _ => {
                     let toggle = try!(v.as_table().map_or(
-                            Err(Error::ScheduleExpected(alias.into())),
-                            |t| Toggle::new(&k[..], t)));
+                            Err(Error::UnknownKey(location,
+                                "'driver', 'mac', 'stick', 'on_url', 'off_url', 'level_url', \
+                                 'on_command', 'off_command', 'level_command', 'default', \
+                                 'shutdown', 'site', 'template', 'templates', 'latitude', 'longitude', \
+                                 'timezone' or a toggle table")),
+                            |t| Toggle::new(alias, &k[..], t, locator)));
                     toggles.push(toggle);
                 }
             }
         }
 
+        // a toggle of the circle overrides the toggle of a template with the same name, a toggle of
+        // a template overrides the toggle of the same name of a earlier template
+        for (n, name) in template_names.iter().enumerate() {
+            for toggle in &templates[name] {
+                let overridden = toggles.iter().any(|t: &Toggle| t.template.is_none() && t.alias == toggle.alias) ||
+                                 template_names[n + 1..].iter().any(|later| {
+                                     templates[later].iter().any(|t| t.alias == toggle.alias)
+                                 });
+                if !overridden {
+                    toggles.push(Toggle { template: Some(name.clone()), ..toggle.clone() });
+                }
+            }
+        }
+
+        let location = |key: Option<&str>| locator.locate(alias, None, key);
+        let driver = driver.unwrap_or(DRIVER_PLUGWISE.into());
+
+        // keys of other drivers are rejected
+        let (keys, expected): (&[&str], _) = match &driver[..] {
+            DRIVER_PLUGWISE => (&[CIRCLE_MAC, CIRCLE_STICK], "'mac' and 'stick' for the plugwise driver"),
+            DRIVER_HTTP => (&[CIRCLE_ON_URL, CIRCLE_OFF_URL, CIRCLE_LEVEL_URL],
+                            "'on_url', 'off_url' and optionally 'level_url' for the http driver"),
+            _ => (&[CIRCLE_ON_COMMAND, CIRCLE_OFF_COMMAND, CIRCLE_LEVEL_COMMAND],
+                  "'on_command', 'off_command' and optionally 'level_command' for the command driver"),
+        };
+        let driver_keys = [CIRCLE_MAC, CIRCLE_STICK, CIRCLE_ON_URL, CIRCLE_OFF_URL, CIRCLE_LEVEL_URL,
+                           CIRCLE_ON_COMMAND, CIRCLE_OFF_COMMAND, CIRCLE_LEVEL_COMMAND];
+        if let Some(key) = table.keys().find(|k| driver_keys.contains(&&k[..]) && !keys.contains(&&k[..])) {
+            return Err(Error::UnknownKey(location(Some(&key[..])), expected));
+        }
+
+        let driver = match &driver[..] {
+            DRIVER_PLUGWISE => {
+                // without a stick key, the circle uses the stick of [config] or the only stick
+                let stick = match stick {
+                    Some(stick) => stick,
+                    None if sticks.len() == 1 => sticks[0].name.clone(),
+                    None => match sticks.iter().find(|stick| stick.name == DEFAULT_STICK) {
+                        Some(stick) => stick.name.clone(),
+                        None => return Err(Error::InvalidValue(location(None),
+                                           "a 'stick' key, since multiple sticks are configured")),
+                    },
+                };
+                Driver::Plugwise(try!(mac.ok_or_else(|| Error::InvalidMac(location(None)))), stick)
+            },
+            DRIVER_HTTP => match urls {
+                (Some(on), Some(off), level) => Driver::Http(on, off, level),
+                _ => return Err(Error::InvalidValue(location(None), expected)),
+            },
+            _ => match commands {
+                (Some(on), Some(off), level) => Driver::Command(on, off, level),
+                _ => return Err(Error::InvalidValue(location(None), expected)),
+            },
+        };
+
         Ok(Circle {
             alias: alias.into(),
-            mac: try!(mac.ok_or(Error::InvalidMac(alias.into()))),
-            default: try!(default.ok_or(Error::InvalidDefault(alias.into()))),
+            driver: driver,
+            default: try!(default.ok_or_else(|| Error::InvalidDefault(locator.locate(alias, None, None)))),
+            shutdown: shutdown,
+            site: settings.apply(&site_name.as_ref().map_or(device.site(), |name| sites[name].apply(&device.site()))),
+            site_name: site_name,
+            templates: template_names,
             toggles: toggles
         })
     }
+
+    /// Mac and stick of a Plugwise circle
+    pub fn plugwise(&self) -> Option<(u64, &str)> {
+        match self.driver {
+            Driver::Plugwise(mac, ref stick) => Some((mac, &stick[..])),
+            _ => None,
+        }
+    }
+
+    /// Whether the circle supports levels between off and on
+    pub fn dimmable(&self) -> bool {
+        match self.driver {
+            Driver::Plugwise(..) => false,
+            Driver::Http(_, _, ref level) | Driver::Command(_, _, ref level) => level.is_some(),
+        }
+    }
+
+    /// Pairs of toggles whose windows (possibly) overlap; only toggles with fixed or fuzzy
+    /// events are considered, since sun based windows are only known per day. Overlapping
+    /// windows are merged by the tracker (the switch is on during the union of both windows).
+    pub fn overlapping_toggles(&self) -> Vec<(&str, &str)> {
+        let mut overlaps = vec![];
+        let day = 24 * 60;
+
+        for (n, first) in self.toggles.iter().enumerate() {
+            for second in &self.toggles[n + 1..] {
+                if let (Some((s1, e1)), Some((s2, e2))) = (first.window(), second.window()) {
+                    // also compare with the windows of the surrounding days
+                    let overlap = [0, day].iter().any(|&shift| {
+                        (s1 + shift < e2 && s2 < e1 + shift) || (s2 + shift < e1 && s1 < e2 + shift)
+                    });
+
+                    if overlap {
+                        overlaps.push((&first.alias[..], &second.alias[..]));
+                    }
+                }
+            }
+        }
+
+        overlaps
+    }
 }
 
-#[derive(Debug)]
+/// Policy of the source of the current time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimePolicy {
+    /// only use NTP; nothing is scheduled until a NTP server responds
+    Ntp,
+    /// use NTP, but trust the system clock when no NTP server responded within the timeout
+    Fallback(Duration),
+    /// use the system clock right away; NTP is only used to check the system clock
+    System
+}
+
+#[derive(Debug, Clone)]
 pub struct Device {
     pub serial_device: Option<String>,
     pub latitude: f64,
     pub longitude: f64,
-    pub ntp_server: String,
+    pub ntp_servers: Vec<String>,
+    pub time_policy: TimePolicy,
+    /// time zone of the circles without their own time zone; the local time zone when `None`
+    pub timezone: Option<String>,
+    /// path of the local control socket
+    pub socket: Option<String>,
+    /// shutdown policy of all circles without their own policy
+    pub shutdown: ShutdownPolicy,
 }
 
 impl Device {
-    fn new(table: &toml::Table) -> Result<Device> {
+    fn new(table: &toml::Table, locator: &Locator) -> Result<Device> {
         let mut serial_device = None;
-        let mut latitude = None;
-        let mut longitude = None;
+        let mut settings = SiteSettings::default();
         let mut ntp = None;
+        let mut socket = None;
+        let mut shutdown = None;
+        let mut time_source = None;
+        let mut ntp_timeout = None;
 
         for (k, v) in table {
+            let location = locator.locate(CONFIG_HEAD, None, Some(&k[..]));
+
+            if try!(settings.parse_key(&k[..], v, location.clone())) {
+                continue;
+            }
+
             match &k[..] {
                 CONFIG_DEVICE => {
-                    if let Some(string) = v.as_str() {
-                        serial_device = Some(string.into());
-                    }
+                    serial_device = Some(try!(v.as_str().map(String::from).ok_or(
+                                Error::InvalidValue(location, "a string (serial device path)"))));
                 },
-                CONFIG_LATITUDE => {
-                    if let Some(lat) = v.as_float() {
-                        latitude = Some(lat);
-                    }
+                CONFIG_NTP_SERVER => {
+                    let servers = match *v {
+                        toml::Value::String(ref server) => Some(vec![server.clone()]),
+                        toml::Value::Array(ref servers) => servers.iter()
+                                                                  .map(|s| s.as_str().map(String::from))
+                                                                  .collect(),
+                        _ => None
+                    };
+                    ntp = Some(try!(servers.and_then(|s| if s.is_empty() { None } else { Some(s) })
+                                           .ok_or(Error::InvalidValue(location,
+                                                  "a string or array of strings (NTP server names)"))));
                 },
-                CONFIG_LONGITUDE => {
-                    if let Some(long) = v.as_float() {
-                        longitude = Some(long);
-                    }
+                CONFIG_TIME_SOURCE => {
+                    time_source = Some(try!(v.as_str()
+                                             .and_then(|s| match s {
+                                                 "ntp" | "fallback" | "system" => Some(String::from(s)),
+                                                 _ => None
+                                             })
+                                             .ok_or(Error::InvalidTimeSource(location))));
                 },
-                CONFIG_NTP_SERVER => {
-                    if let Some(string) = v.as_str() {
-                        ntp = Some(string.into());
-                    }
+                CONFIG_NTP_TIMEOUT => {
+                    let timeout = try!(v.as_integer().and_then(|t| if t > 0 { Some(t) } else { None })
+                                       .ok_or(Error::InvalidValue(location.clone(), "a positive integer (seconds)")));
+                    ntp_timeout = Some((timeout, location));
+                },
+                CONFIG_SOCKET => {
+                    socket = Some(try!(v.as_str().map(String::from).ok_or(
+                                Error::InvalidValue(location, "a string (control socket path)"))));
+                },
+                CONFIG_SHUTDOWN => {
+                    shutdown = Some(try!(v.as_str()
+                                          .and_then(|s| ShutdownPolicy::new(s))
+                                          .ok_or(Error::InvalidShutdown(location))));
                 },
-                _ => {}
+                _ => {
+                    return Err(Error::UnknownKey(location,
+                        "'device', 'latitude', 'longitude', 'timezone', 'ntp', 'time_source', \
+                         'ntp_timeout', 'socket' or 'shutdown'"));
+                }
             }
         }
 
-        latitude.map_or(Err(Error::LocationMissing), |lat|
-             longitude.map_or(Err(Error::LocationMissing), |long|
-             ntp.map_or(Err(Error::MissingNTP), |ntp:String|
-             serial_device.map_or(
-                    Ok(Device{serial_device: None, latitude:lat, longitude:long, ntp_server:ntp.clone()}),
-                    |dev| Ok(Device{serial_device: Some(dev), latitude:lat, longitude: long, ntp_server:ntp.clone()})))))
+        let location = || locator.locate(CONFIG_HEAD, None, None);
+        let time_policy = match (time_source.as_ref().map(|s| &s[..]), ntp_timeout) {
+            (Some("fallback"), timeout) => {
+                TimePolicy::Fallback(Duration::seconds(timeout.map_or(DEFAULT_NTP_TIMEOUT, |(t, _)| t)))
+            },
+            (_, Some((_, location))) => return Err(Error::UnusedNtpTimeout(location)),
+            (Some("system"), None) => TimePolicy::System,
+            (_, None) => TimePolicy::Ntp
+        };
+
+        Ok(Device {
+            serial_device: serial_device,
+            latitude: try!(settings.latitude.ok_or_else(|| Error::LocationMissing(location()))),
+            longitude: try!(settings.longitude.ok_or_else(|| Error::LocationMissing(location()))),
+            ntp_servers: try!(ntp.ok_or_else(|| Error::MissingNTP(location()))),
+            time_policy: time_policy,
+            timezone: settings.timezone,
+            socket: socket,
+            shutdown: shutdown.unwrap_or(ShutdownPolicy::Keep),
+        })
     }
 }
 
-#[derive(Debug)]
+impl Device {
+    /// The site of all circles without a site or site settings of their own
+    pub fn site(&self) -> Site {
+        Site {
+            latitude: self.latitude,
+            longitude: self.longitude,
+            timezone: self.timezone.clone(),
+        }
+    }
+
+    /// Keys and values of the `[config]` table; default settings are left out
+    fn to_toml(&self) -> Pairs {
+        let string = |s: &str| toml::Value::String(s.into());
+        let mut pairs = vec![];
+
+        if let Some(ref device) = self.serial_device {
+            pairs.push((CONFIG_DEVICE.into(), string(device)));
+        }
+        pairs.push((CONFIG_LATITUDE.into(), toml::Value::Float(self.latitude)));
+        pairs.push((CONFIG_LONGITUDE.into(), toml::Value::Float(self.longitude)));
+        if let Some(ref timezone) = self.timezone {
+            pairs.push((CONFIG_TIMEZONE.into(), string(timezone)));
+        }
+        pairs.push((CONFIG_NTP_SERVER.into(), match self.ntp_servers.len() {
+            1 => string(&self.ntp_servers[0]),
+            _ => toml::Value::Array(self.ntp_servers.iter().map(|server| string(server)).collect()),
+        }));
+        match self.time_policy {
+            TimePolicy::Ntp => {},
+            TimePolicy::Fallback(timeout) => {
+                pairs.push((CONFIG_TIME_SOURCE.into(), string("fallback")));
+                if timeout != Duration::seconds(DEFAULT_NTP_TIMEOUT) {
+                    pairs.push((CONFIG_NTP_TIMEOUT.into(), toml::Value::Integer(timeout.num_seconds())));
+                }
+            },
+            TimePolicy::System => pairs.push((CONFIG_TIME_SOURCE.into(), string("system"))),
+        }
+        if let Some(ref socket) = self.socket {
+            pairs.push((CONFIG_SOCKET.into(), string(socket)));
+        }
+        if self.shutdown != ShutdownPolicy::Keep {
+            pairs.push((CONFIG_SHUTDOWN.into(), string(self.shutdown.name())));
+        }
+
+        pairs
+    }
+}
+
+/// Whether the name can be used as the alias of a new circle: a bare TOML key that is not
+/// reserved for another table
+pub fn valid_alias(alias: &str) -> bool {
+    !alias.is_empty() &&
+        ![CONFIG_HEAD, CONFIG_SITE, CONFIG_STICK, CONFIG_TEMPLATE, CONFIG_INCLUDE].contains(&alias) &&
+        alias.chars().all(|c| match c {
+            'a'...'z' | 'A'...'Z' | '0'...'9' | '_' | '-' => true,
+            _ => false
+        })
+}
+
+/// Whether the name can be used as the name of a new toggle: a valid alias that is no key of the
+/// circle table
+pub fn valid_toggle_name(name: &str) -> bool {
+    valid_alias(name) && !circle_setting(name)
+}
+
+/// Whether the key is a setting of a circle table (and not a toggle)
+pub fn circle_setting(key: &str) -> bool {
+    CIRCLE_KEYS.contains(&key)
+}
+
+/// Whether the key selects a driver, or the requests or commands it runs
+pub fn driver_setting(key: &str) -> bool {
+    DRIVER_KEYS.contains(&key)
+}
+
+/// Name of the table of a `[table]` or `[[table]]` line (dotted for subtables)
+pub fn table_name(line: &str) -> Option<String> {
+    Locator::table_header(line.trim_left())
+}
+
+/// A key as written in TOML: quoted unless it is a bare key
+pub fn quote_key(key: &str) -> String {
+    let bare = !key.is_empty() && key.chars().all(|c| match c {
+        'a'...'z' | 'A'...'Z' | '0'...'9' | '_' | '-' => true,
+        _ => false
+    });
+
+    if bare {
+        key.into()
+    } else {
+        toml::Value::String(key.into()).to_string()
+    }
+}
+
+/// The `[table]` header of a (dotted) table name
+pub fn table_header(table: &str) -> String {
+    let parts: Vec<_> = table.split('.').map(quote_key).collect();
+    format!("[{}]", parts.join("."))
+}
+
+/// A parsed configuration file
+struct Source {
+    table: toml::Table,
+    locator: Locator,
+}
+
+impl Source {
+    fn parse(source: &str, file: Option<String>) -> Result<Source> {
+        let mut parser = toml::Parser::new(source);
+        let table = try!(parser.parse().ok_or_else(|| {
+            Error::InvalidToml(parser.errors.iter().map(|err| {
+                let (line, column) = parser.to_linecol(err.lo);
+                TomlError {
+                    file: file.clone(),
+                    line: line + 1,
+                    column: column + 1,
+                    description: err.desc.clone(),
+                }
+            }).collect())
+        }));
+
+        Ok(Source {
+            table: table,
+            locator: Locator::new(source, file),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Config {
     pub device: Device,
-    pub circles: Vec<Circle>
+    pub sites: BTreeMap<String, SiteSettings>,
+    pub sticks: Vec<Stick>,
+    /// toggles of every `[template.<name>]` table
+    pub templates: BTreeMap<String, Vec<Toggle>>,
+    pub circles: Vec<Circle>,
+    /// file of every table (dotted for sites and sticks) that is defined in a included file
+    pub included: BTreeMap<String, String>,
+}
+
+impl fmt::Display for Config {
+    /// The configuration as canonical TOML
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (n, (name, pairs)) in self.to_toml().into_iter().enumerate() {
+            if n > 0 {
+                try!(writeln!(f, ""));
+            }
+            try!(writeln!(f, "{}", table_header(&name)));
+            for (key, value) in pairs {
+                try!(writeln!(f, "{} = {}", quote_key(&key), value));
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Config {
     pub fn new(configfile: &path::PathBuf) -> Result<Config> {
-        let mut circles = vec![];
-        let mut device = None;
-
         let mut config = String::new();
         let mut file = try!(fs::File::open(configfile));
         try!(file.read_to_string(&mut config));
-        let config = try!(toml::Parser::new(&config).parse().ok_or(Error::InvalidToml));
 
-        for (k,v) in config {
-            if let Some(table) = v.as_table() {
-                match &k[..] {
-                    CONFIG_HEAD => {
-                        device = Some(try!(Device::new(table)));
+        Config::parse(configfile, &config)
+    }
+
+    /// Parse the configuration with `source` as the content of the configuration file; the
+    /// included files and the files of the `conf.d` directory next to it are read from disk
+    pub fn parse(configfile: &path::Path, source: &str) -> Result<Config> {
+        let file_name = |path: &path::Path| Some(format!("{}", path.display()));
+        let main = try!(Source::parse(source, file_name(configfile)));
+        let dir = configfile.parent().unwrap_or(path::Path::new(""));
+        let mut files = vec![];
+
+        if let Some(include) = main.table.get(CONFIG_INCLUDE) {
+            let mut location = main.locator.locate("", None, Some(CONFIG_INCLUDE));
+            location.table = None;
+            let paths: Option<Vec<_>> = match *include {
+                toml::Value::String(ref path) => Some(vec![&path[..]]),
+                toml::Value::Array(ref paths) => paths.iter().map(|path| path.as_str()).collect(),
+                _ => None
+            };
+
+            for path in try!(paths.ok_or(Error::InvalidValue(location,
+                                         "a string or array of strings (file or directory paths)"))) {
+                try!(Config::collect_files(&dir.join(path), &mut files));
+            }
+        }
+
+        let confdir = dir.join(CONFIG_DIR);
+        if confdir.is_dir() {
+            try!(Config::collect_files(&confdir, &mut files));
+        }
+
+        let mut sources = vec![main];
+        for file in files {
+            if file == configfile {
+                continue;
+            }
+            let mut source = String::new();
+            try!(fs::File::open(&file).and_then(|mut f| f.read_to_string(&mut source))
+                     .map_err(|err| Error::Include(format!("file {}", file.display()), err)));
+            sources.push(try!(Source::parse(&source, file_name(&file))));
+        }
+
+        Config::merge(&sources)
+    }
+
+    /// Add a included file, or the `*.toml` files of a included directory (by name), once
+    fn collect_files(path: &path::Path, files: &mut Vec<path::PathBuf>) -> Result<()> {
+        let mut found = vec![];
+
+        if path.is_dir() {
+            let entries = try!(fs::read_dir(path).map_err(|err|
+                Error::Include(format!("directory {}", path.display()), err)));
+            for entry in entries {
+                let entry = try!(entry.map_err(|err| Error::Include(format!("directory {}", path.display()), err)));
+                let path = entry.path();
+                if path.is_file() && path.extension().map_or(false, |ext| ext == CONFIG_EXTENSION) {
+                    found.push(path);
+                }
+            }
+            found.sort();
+        } else {
+            found.push(path.to_path_buf());
+        }
+
+        for path in found {
+            if !files.contains(&path) {
+                files.push(path);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Remember where a table is defined; a table may only be defined in one file
+    fn define(defined: &mut BTreeMap<String, Location>, name: String, location: Location) -> Result<()> {
+        if let Some(first) = defined.get(&name) {
+            return Err(Error::DuplicateTable(location, first.clone()));
+        }
+        defined.insert(name, location);
+        Ok(())
+    }
+
+    /// Build the configuration from the configuration file (the first source) and the included
+    /// files
+    fn merge(sources: &[Source]) -> Result<Config> {
+        let mut circles = vec![];
+        let mut device = None;
+        let mut circle_tables = vec![];
+        let mut sites = BTreeMap::new();
+        let mut sticks = vec![];
+        let mut templates = BTreeMap::new();
+        let mut defined = BTreeMap::new();
+        let mut included = BTreeMap::new();
+
+        for (n, source) in sources.iter().enumerate() {
+            let locator = &source.locator;
+
+            for (k, v) in &source.table {
+                let mut names = vec![];
+
+                match v.as_table() {
+                    Some(table) => match &k[..] {
+                        CONFIG_HEAD => {
+                            device = Some(try!(Device::new(table, locator)));
+                            names.push((k.clone(), locator.locate(CONFIG_HEAD, None, None)));
+                        },
+                        CONFIG_SITE => {
+                            let parsed = try!(Config::parse_sites(table, locator));
+                            for name in parsed.keys() {
+                                names.push((format!("{}.{}", CONFIG_SITE, name),
+                                            locator.locate(CONFIG_SITE, Some(&name[..]), None)));
+                            }
+                            sites.extend(parsed);
+                        },
+                        CONFIG_STICK => {
+                            let parsed = try!(Config::parse_sticks(table, locator));
+                            for stick in &parsed {
+                                names.push((format!("{}.{}", CONFIG_STICK, stick.name),
+                                            locator.locate(CONFIG_STICK, Some(&stick.name[..]), None)));
+                            }
+                            sticks.extend(parsed);
+                        },
+                        CONFIG_TEMPLATE => {
+                            let parsed = try!(Config::parse_templates(table, locator));
+                            for name in parsed.keys() {
+                                names.push((format!("{}.{}", CONFIG_TEMPLATE, name),
+                                            locator.locate(CONFIG_TEMPLATE, Some(&name[..]), None)));
+                            }
+                            templates.extend(parsed);
+                        },
+                        _ => {
+                            circle_tables.push((k, table, locator));
+                            names.push((k.clone(), locator.locate(&k[..], None, None)));
+                        }
                     },
-                    _ => {
-                        circles.push(try!(Circle::new(&k[..], table)));
+                    // only the configuration file itself may include files
+                    None if n == 0 && k == CONFIG_INCLUDE => {},
+                    None => {
+                        let mut location = locator.locate("", None, Some(&k[..]));
+                        location.table = None;
+                        return Err(Error::UnknownKey(location,
+                            "a [config], [site.<name>], [stick.<name>], [template.<name>] or circle table (or 'include' \
+                             in the main configuration file)"));
+                    }
+                }
+
+                for (name, location) in names {
+                    if n > 0 {
+                        if let Some(ref file) = locator.file {
+                            included.insert(name.clone(), file.clone());
+                        }
                     }
+                    try!(Config::define(&mut defined, name, location));
                 }
             }
         }
 
+        // circles are parsed after [config], the sites, the sticks and the templates, since they
+        // refer to them
+        let device = try!(device.ok_or(Error::MissingConfig));
+
+        // the device of [config] is the default stick (also when no stick is configured at all,
+        // in which case the simulator is used)
+        if device.serial_device.is_some() || sticks.is_empty() {
+            if let Some(location) = defined.get(&format!("{}.{}", CONFIG_STICK, DEFAULT_STICK)) {
+                return Err(Error::InvalidValue(location.clone(),
+                    "a stick name other than 'default', which is the device of [config]"));
+            }
+            sticks.insert(0, Stick {
+                name: DEFAULT_STICK.into(),
+                device: device.serial_device.clone(),
+            });
+        }
+
+        for &(alias, table, locator) in &circle_tables {
+            let circle = try!(Circle::new(&alias[..], table, locator, &device, &sites, &sticks, &templates));
+
+            if let Some((mac, _)) = circle.plugwise() {
+                let first = circles.iter().position(|c: &Circle| c.plugwise().map(|(m, _)| m) == Some(mac));
+                if let Some(first) = first {
+                    let (first_alias, _, first_locator) = circle_tables[first];
+                    return Err(Error::DuplicateMac(locator.locate(alias, None, Some(CIRCLE_MAC)),
+                                                   first_locator.locate(first_alias, None, Some(CIRCLE_MAC))));
+                }
+            }
+            circles.push(circle);
+        }
+
         Ok(Config {
-            device: try!(device.ok_or(Error::MissingConfig)),
-            circles: circles
+            device: device,
+            sites: sites,
+            sticks: sticks,
+            templates: templates,
+            circles: circles,
+            included: included,
         })
     }
+
+    /// The included file a table (or toggle) is defined in; `None` for the tables of the
+    /// configuration file itself
+    pub fn included_file(&self, table: &str) -> Option<&str> {
+        let mut name = table;
+
+        loop {
+            if let Some(file) = self.included.get(name) {
+                return Some(&file[..]);
+            }
+            match name.rfind('.') {
+                Some(dot) => name = &name[..dot],
+                None => return None,
+            }
+        }
+    }
+
+    /// The first table (and the file it is defined in) of an included file that differs between
+    /// the `old` and `new` tables (both in the form of `to_toml`); included files are never
+    /// changed
+    pub fn changed_included_table<'a>(&'a self, old: &'a Tables, new: &'a Tables) -> Option<(&'a str, &'a str)> {
+        let unchanged = |tables: &Tables, name: &str, pairs: &Pairs| {
+            tables.iter().any(|&(ref n, ref p)| n == name && p == pairs)
+        };
+
+        old.iter().chain(new.iter()).filter_map(|&(ref name, ref pairs)| {
+            self.included_file(name).and_then(|file| {
+                if unchanged(old, name, pairs) && unchanged(new, name, pairs) {
+                    None
+                } else {
+                    Some((&name[..], file))
+                }
+            })
+        }).next()
+    }
+
+    /// Keys and values of the table of a circle; settings that equal the defaults (or the
+    /// settings of its site) are left out
+    fn circle_to_toml(&self, circle: &Circle) -> Pairs {
+        let string = |s: &str| toml::Value::String(s.into());
+        let mut pairs = vec![];
+
+        match circle.driver {
+            Driver::Plugwise(mac, ref stick) => {
+                pairs.push((CIRCLE_MAC.into(), toml::Value::String(format!("{:016X}", mac))));
+                if stick != DEFAULT_STICK {
+                    pairs.push((CIRCLE_STICK.into(), string(stick)));
+                }
+            },
+            Driver::Http(ref on, ref off, ref level) => {
+                pairs.push((CIRCLE_DRIVER.into(), string(DRIVER_HTTP)));
+                pairs.push((CIRCLE_ON_URL.into(), string(on)));
+                pairs.push((CIRCLE_OFF_URL.into(), string(off)));
+                if let Some(ref level) = *level {
+                    pairs.push((CIRCLE_LEVEL_URL.into(), string(level)));
+                }
+            },
+            Driver::Command(ref on, ref off, ref level) => {
+                pairs.push((CIRCLE_DRIVER.into(), string(DRIVER_COMMAND)));
+                pairs.push((CIRCLE_ON_COMMAND.into(), string(on)));
+                pairs.push((CIRCLE_OFF_COMMAND.into(), string(off)));
+                if let Some(ref level) = *level {
+                    pairs.push((CIRCLE_LEVEL_COMMAND.into(), string(level)));
+                }
+            },
+        }
+        pairs.push((CIRCLE_DEFAULT.into(), string(circle.default.name())));
+        match circle.templates.len() {
+            0 => {},
+            1 => pairs.push((CIRCLE_TEMPLATE.into(), string(&circle.templates[0]))),
+            _ => pairs.push((CIRCLE_TEMPLATES.into(),
+                             toml::Value::Array(circle.templates.iter().map(|name| string(name)).collect()))),
+        }
+        if let Some(shutdown) = circle.shutdown {
+            pairs.push((CIRCLE_SHUTDOWN.into(), string(shutdown.name())));
+        }
+
+        let base = match circle.site_name {
+            Some(ref name) => {
+                pairs.push((CIRCLE_SITE.into(), string(name)));
+                self.sites.get(name).map_or(self.device.site(), |site| site.apply(&self.device.site()))
+            },
+            None => self.device.site(),
+        };
+        pairs.extend(SiteSettings::to_toml(&circle.site, &base));
+
+        pairs
+    }
+
+    /// The configuration in canonical form: `[config]`, the sites, the sticks, the toggles of the
+    /// templates and every circle followed by its own toggles
+    pub fn to_toml(&self) -> Tables {
+        let mut tables = vec![(String::from(CONFIG_HEAD), self.device.to_toml())];
+
+        for (name, site) in &self.sites {
+            let mut pairs = vec![];
+            if let Some(latitude) = site.latitude {
+                pairs.push((CONFIG_LATITUDE.into(), toml::Value::Float(latitude)));
+            }
+            if let Some(longitude) = site.longitude {
+                pairs.push((CONFIG_LONGITUDE.into(), toml::Value::Float(longitude)));
+            }
+            if let Some(ref timezone) = site.timezone {
+                pairs.push((CONFIG_TIMEZONE.into(), toml::Value::String(timezone.clone())));
+            }
+            tables.push((format!("{}.{}", CONFIG_SITE, name), pairs));
+        }
+
+        for stick in &self.sticks {
+            // the device of [config] is the default stick
+            if stick.name == DEFAULT_STICK && stick.device == self.device.serial_device {
+                continue;
+            }
+            let pairs = stick.device.iter().map(|device| (STICK_DEVICE.into(), toml::Value::String(device.clone())));
+            tables.push((format!("{}.{}", CONFIG_STICK, stick.name), pairs.collect()));
+        }
+
+        for (name, toggles) in &self.templates {
+            for toggle in toggles {
+                tables.push((format!("{}.{}.{}", CONFIG_TEMPLATE, name, toggle.alias), toggle.to_toml()));
+            }
+        }
+
+        for circle in &self.circles {
+            tables.push((circle.alias.clone(), self.circle_to_toml(circle)));
+            for toggle in circle.toggles.iter().filter(|toggle| toggle.template.is_none()) {
+                tables.push((format!("{}.{}", circle.alias, toggle.alias), toggle.to_toml()));
+            }
+        }
+
+        tables
+    }
+
+    fn parse_sticks(table: &toml::Table, locator: &Locator) -> Result<Vec<Stick>> {
+        let mut sticks = vec![];
+
+        for (name, stick) in table {
+            let stick = try!(stick.as_table().ok_or_else(|| Error::UnknownKey(
+                locator.locate(CONFIG_STICK, None, Some(&name[..])), "a [stick.<name>] table")));
+            let mut device = None;
+
+            for (k, v) in stick {
+                let location = locator.locate(CONFIG_STICK, Some(&name[..]), Some(&k[..]));
+                match &k[..] {
+                    STICK_DEVICE => {
+                        device = Some(try!(v.as_str().map(String::from).ok_or(
+                                    Error::InvalidValue(location, "a string (serial device path)"))));
+                    },
+                    _ => return Err(Error::UnknownKey(location, "'device'")),
+                }
+            }
+
+            sticks.push(Stick {
+                name: name.clone(),
+                device: Some(try!(device.ok_or_else(|| Error::InvalidValue(
+                    locator.locate(CONFIG_STICK, Some(&name[..]), None),
+                    "a 'device' key (serial device path)")))),
+            });
+        }
+
+        Ok(sticks)
+    }
+
+    fn parse_templates(table: &toml::Table, locator: &Locator) -> Result<BTreeMap<String, Vec<Toggle>>> {
+        let mut templates = BTreeMap::new();
+
+        for (name, template) in table {
+            let template = try!(template.as_table().ok_or_else(|| Error::UnknownKey(
+                locator.locate(CONFIG_TEMPLATE, None, Some(&name[..])), "a [template.<name>] table")));
+            let path = format!("{}.{}", CONFIG_TEMPLATE, name);
+            let mut toggles = vec![];
+
+            for (k, v) in template {
+                let toggle = try!(v.as_table().and_then(|t| if circle_setting(k) { None } else { Some(t) }).map_or(
+                        Err(Error::UnknownKey(locator.locate(&path, None, Some(&k[..])),
+                            "a toggle table (named differently than a circle setting)")),
+                        |t| Toggle::new(&path, &k[..], t, locator)));
+                toggles.push(toggle);
+            }
+            templates.insert(name.clone(), toggles);
+        }
+
+        Ok(templates)
+    }
+
+    fn parse_sites(table: &toml::Table, locator: &Locator) -> Result<BTreeMap<String, SiteSettings>> {
+        let mut sites = BTreeMap::new();
+
+        for (name, site) in table {
+            let site = try!(site.as_table().ok_or_else(|| Error::UnknownKey(
+                locator.locate(CONFIG_SITE, None, Some(&name[..])), "a [site.<name>] table")));
+            let mut settings = SiteSettings::default();
+
+            for (k, v) in site {
+                let location = locator.locate(CONFIG_SITE, Some(&name[..]), Some(&k[..]));
+                if !try!(settings.parse_key(&k[..], v, location.clone())) {
+                    return Err(Error::UnknownKey(location, "'latitude', 'longitude' or 'timezone'"));
+                }
+            }
+            sites.insert(name.clone(), settings);
+        }
+
+        Ok(sites)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::path::{Path, PathBuf};
+    use time;
+
+    const HEAD: &'static str = "[config]\nlatitude = 52.0\nlongitude = 5.0\nntp = \"pool.ntp.org\"\n\n";
+
+    fn parse(circles: &str) -> Result<Config> {
+        Config::parse(Path::new("/nonexistent/plugwise.toml"), &format!("{}{}", HEAD, circles))
+    }
+
+    /// A circle with the given toggle tables (`name = { ... }`)
+    fn circle(toggles: &str) -> Circle {
+        let source = format!("[lamp]\nmac = \"000D6F0000123456\"\ndefault = \"schedule\"\n{}", toggles);
+        parse(&source).unwrap_or_else(|err| panic!("{}", err)).circles.remove(0)
+    }
+
+    #[test]
+    fn duplicate_start_event() {
+        match parse("[lamp]\nmac = \"000D6F0000123456\"\ndefault = \"schedule\"\n\
+                     [lamp.evening]\nstart_fixed = [18, 0]\nstart_sunset = 0\nend_fixed = [23, 0]\n") {
+            Err(Error::DuplicateEvent(location, "start")) => {
+                assert_eq!(location.toggle, Some(String::from("evening")));
+            },
+            other => panic!("expected a duplicate start event, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn duplicate_end_event() {
+        match parse("[lamp]\nmac = \"000D6F0000123456\"\ndefault = \"schedule\"\n\
+                     [lamp.evening]\nstart_fixed = [18, 0]\nend_fixed = [23, 0]\nend_fuzzy = [[22, 0], [23, 0]]\n") {
+            Err(Error::DuplicateEvent(_, "end")) => {},
+            other => panic!("expected a duplicate end event, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn missing_events() {
+        match parse("[lamp]\nmac = \"000D6F0000123456\"\ndefault = \"schedule\"\n\
+                     [lamp.evening]\nstart_fixed = [18, 0]\n") {
+            Err(Error::MissingEndEvent(_)) => {},
+            other => panic!("expected a missing end event, got {:?}", other.map(|_| ())),
+        }
+        match parse("[lamp]\nmac = \"000D6F0000123456\"\ndefault = \"schedule\"\n\
+                     [lamp.evening]\nend_fixed = [23, 0]\n") {
+            Err(Error::MissingStartEvent(_)) => {},
+            other => panic!("expected a missing start event, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn overlapping_windows() {
+        let lamp = circle("a = { start_fixed = [18, 0], end_fixed = [22, 0] }\n\
+                           b = { start_fixed = [21, 0], end_fixed = [23, 0] }\n\
+                           c = { start_fixed = [7, 0], end_fixed = [8, 0] }\n");
+        assert_eq!(lamp.overlapping_toggles(), vec![("a", "b")]);
+    }
+
+    #[test]
+    fn adjacent_windows_do_not_overlap() {
+        let lamp = circle("a = { start_fixed = [18, 0], end_fixed = [20, 0] }\n\
+                           b = { start_fixed = [20, 0], end_fixed = [22, 0] }\n");
+        assert!(lamp.overlapping_toggles().is_empty());
+    }
+
+    #[test]
+    fn windows_past_midnight() {
+        let lamp = circle("a = { start_fixed = [22, 0], end_fixed = [2, 0] }\n\
+                           b = { start_fixed = [1, 0], end_fixed = [3, 0] }\n\
+                           c = { start_fixed = [3, 0], end_fixed = [4, 0] }\n\
+                           d = { start_fixed = [23, 30], end_fixed = [23, 45] }\n");
+        assert_eq!(lamp.overlapping_toggles(), vec![("a", "b"), ("a", "d")]);
+    }
+
+    #[test]
+    fn fades_and_fuzzy_windows_overlap() {
+        // the fade in of b starts before a ends
+        let lamp = circle("a = { start_fixed = [5, 0], end_fixed = [6, 0] }\n\
+                           b = { start_fixed = [6, 30], end_fixed = [7, 0], fade_in = 45 }\n");
+        assert_eq!(lamp.overlapping_toggles(), vec![("a", "b")]);
+
+        // a fuzzy end may be as late as 22:30
+        let lamp = circle("a = { start_fixed = [18, 0], end_fuzzy = [[21, 0], [22, 30]] }\n\
+                           b = { start_fixed = [22, 0], end_fixed = [23, 0] }\n");
+        assert_eq!(lamp.overlapping_toggles(), vec![("a", "b")]);
+    }
+
+    #[test]
+    fn sun_based_windows_are_not_compared() {
+        let lamp = circle("a = { start_sunset = 0, end_fixed = [23, 0] }\n\
+                           b = { start_fixed = [22, 0], end_fixed = [23, 30] }\n");
+        assert!(lamp.overlapping_toggles().is_empty());
+    }
+
+    fn device(settings: &str) -> Result<Device> {
+        let source = format!("[config]\nlatitude = 52.0\nlongitude = 5.0\nntp = \"pool.ntp.org\"\n{}", settings);
+        Config::parse(Path::new("/nonexistent/plugwise.toml"), &source).map(|config| config.device)
+    }
+
+    #[test]
+    fn ntp_timeout_of_fallback() {
+        let device = device("time_source = \"fallback\"\nntp_timeout = 30\n").unwrap();
+        assert_eq!(device.time_policy, TimePolicy::Fallback(Duration::seconds(30)));
+    }
+
+    #[test]
+    fn ntp_timeout_without_fallback() {
+        for settings in &["ntp_timeout = 30\n", "time_source = \"ntp\"\nntp_timeout = 30\n",
+                          "time_source = \"system\"\nntp_timeout = 30\n"] {
+            match device(settings) {
+                Err(Error::UnusedNtpTimeout(location)) => {
+                    assert_eq!(location.key, Some(String::from("ntp_timeout")));
+                },
+                other => panic!("expected a unused NTP timeout, got {:?}", other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn table_headers_quote_keys_that_are_not_bare() {
+        assert_eq!(table_header("lamp.evening"), "[lamp.evening]");
+        assert_eq!(table_header("living room.late night"), "[\"living room\".\"late night\"]");
+        assert_eq!(table_name(&table_header("living room.late night")), Some(String::from("living room.late night")));
+        assert_eq!(quote_key("x\"y"), "\"x\\\"y\"");
+    }
+
+    const TEMPLATES: &'static str = "[template.evening.lights]\nstart_sunset = 0\nend_fixed = [23, 0]\nlevel = 10\n\
+                                      [template.evening.late]\nstart_fixed = [23, 0]\nend_fixed = [23, 30]\nlevel = 20\n\
+                                      [template.dim.lights]\nstart_sunset = 0\nend_fixed = [22, 0]\nlevel = 30\n";
+
+    /// Name, template and level of the toggles of the circle with the templates above
+    fn templated(circle: &str) -> Vec<(String, Option<String>, u8)> {
+        let config = parse(&format!("{}{}", TEMPLATES, circle)).unwrap_or_else(|err| panic!("{}", err));
+        config.circles[0].toggles.iter().map(|t| (t.alias.clone(), t.template.clone(), t.level)).collect()
+    }
+
+    #[test]
+    fn template_expansion() {
+        let toggles = templated("[lamp]\nmac = \"000D6F0000123456\"\ndefault = \"schedule\"\ntemplate = \"evening\"\n");
+        assert_eq!(toggles, vec![(String::from("late"), Some(String::from("evening")), 20),
+                                 (String::from("lights"), Some(String::from("evening")), 10)]);
+    }
+
+    #[test]
+    fn template_override_precedence() {
+        // the toggle of the circle overrides 'late' of evening; dim, the later template,
+        // overrides 'lights' of evening
+        let toggles = templated("[lamp]\nmac = \"000D6F0000123456\"\ndefault = \"schedule\"\n\
+                                 templates = [\"evening\", \"dim\"]\n\
+                                 late = { start_fixed = [23, 0], end_fixed = [23, 15], level = 40 }\n");
+        assert_eq!(toggles, vec![(String::from("late"), None, 40),
+                                 (String::from("lights"), Some(String::from("dim")), 30)]);
+    }
+
+    #[test]
+    fn unknown_template() {
+        match parse("[lamp]\nmac = \"000D6F0000123456\"\ndefault = \"schedule\"\ntemplate = \"evening\"\n") {
+            Err(Error::InvalidValue(location, _)) => assert_eq!(location.key, Some(String::from("template"))),
+            other => panic!("expected a invalid template, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn reserved_aliases() {
+        for alias in &["config", "site", "stick", "template", "include", "", "living room"] {
+            assert!(!valid_alias(alias), "'{}' accepted as alias", alias);
+        }
+        assert!(valid_alias("living-room_2"));
+    }
+
+    #[test]
+    fn dark_and_light_at_sunrise_and_sunset() {
+        let (sunrise, sunset) = (Timespec::new(1782012600, 0), Timespec::new(1782072000, 0));
+        let dark = Condition { dark: Some(true), ..Condition::default() };
+        let light = Condition { dark: Some(false), ..Condition::default() };
+        let second = Duration::seconds(1);
+
+        for &(start, in_daylight) in &[(sunrise - second, false), (sunrise, true), (sunset, true), (sunset + second, false)] {
+            assert_eq!(dark.skip_reason_between(start, sunrise, sunset).is_none(), !in_daylight);
+            assert_eq!(light.skip_reason_between(start, sunrise, sunset).is_none(), in_daylight);
+        }
+    }
+
+    #[test]
+    fn daylight_bounds() {
+        let (sunrise, sunset) = (Timespec::new(1782012600, 0), Timespec::new(1782072000, 0));
+        let daylight = |min: u16, max: u16| Condition { min_daylight: Some(min), max_daylight: Some(max), ..Condition::default() };
+
+        // 16:30 of daylight
+        assert_eq!(daylight(16 * 60 + 30, 16 * 60 + 30).skip_reason_between(sunrise, sunrise, sunset), None);
+        assert_eq!(daylight(16 * 60 + 31, 24 * 60).skip_reason_between(sunrise, sunrise, sunset),
+                   Some(String::from("daylight of 16:30 is shorter than 16:31")));
+        assert_eq!(daylight(0, 16 * 60 + 29).skip_reason_between(sunrise, sunrise, sunset),
+                   Some(String::from("daylight of 16:30 is longer than 16:29")));
+    }
+
+    #[test]
+    fn sun_of_the_local_date() {
+        let zoneinfo = ZoneInfo::by_tz("Europe/Amsterdam").expect("zoneinfo of Europe/Amsterdam");
+
+        // 0:30 on June 21 in Amsterdam is still June 20 in UTC
+        let noon = Condition::local_noon(Timespec::new(1781994600, 0), &zoneinfo);
+        assert_eq!(noon.to_timespec(), Timespec::new(1782043200, 0));
+    }
+
+    /// Temporary directory with configuration files, removed when dropped
+    struct Files {
+        dir: PathBuf,
+    }
+
+    impl Files {
+        fn new(name: &str, files: &[(&str, &str)]) -> Files {
+            let dir = env::temp_dir().join(format!("keeper-{}-{}", name, time::precise_time_ns()));
+            for &(file, content) in files {
+                let path = dir.join(file);
+                fs::create_dir_all(path.parent().expect("BUG: file without directory")).unwrap();
+                fs::File::create(&path).and_then(|mut f| f.write_all(content.as_bytes())).unwrap();
+            }
+            Files { dir: dir }
+        }
+
+        fn path(&self, file: &str) -> String {
+            format!("{}", self.dir.join(file).display())
+        }
+
+        /// Load `keeper.toml` of the directory
+        fn load(&self) -> Result<Config> {
+            Config::new(&self.dir.join("keeper.toml"))
+        }
+    }
+
+    impl Drop for Files {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn lamp(alias: &str, mac: &str) -> String {
+        format!("[{}]\nmac = \"{}\"\ndefault = \"schedule\"\n", alias, mac)
+    }
+
+    fn aliases(config: &Config) -> Vec<&str> {
+        config.circles.iter().map(|c| &c.alias[..]).collect()
+    }
+
+    #[test]
+    fn include_files_and_directories() {
+        let files = Files::new("include", &[
+            ("keeper.toml", &format!("include = [\"hall.toml\", \"garden\"]\n{}{}", HEAD, lamp("lamp", "000D6F0000000001"))),
+            ("hall.toml", &lamp("hall", "000D6F0000000002")),
+            ("garden/pond.toml", &lamp("pond", "000D6F0000000003")),
+            ("garden/fence.toml", &lamp("fence", "000D6F0000000004")),
+            ("garden/notes.txt", "not a configuration file"),
+        ]);
+        let config = files.load().unwrap_or_else(|err| panic!("{}", err));
+
+        assert_eq!(aliases(&config), vec!["lamp", "hall", "fence", "pond"]);
+        assert_eq!(config.included_file("lamp"), None);
+        assert_eq!(config.included_file("config"), None);
+        assert_eq!(config.included_file("hall"), Some(&files.path("hall.toml")[..]));
+        assert_eq!(config.included_file("pond.evening"), Some(&files.path("garden/pond.toml")[..]));
+    }
+
+    #[test]
+    fn missing_include() {
+        let files = Files::new("missing-include", &[("keeper.toml", &format!("include = \"hall.toml\"\n{}", HEAD))]);
+
+        match files.load() {
+            Err(Error::Include(file, _)) => assert_eq!(file, format!("file {}", files.path("hall.toml"))),
+            other => panic!("expected a missing include, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn conf_d_files_in_name_order() {
+        let files = Files::new("conf-d", &[
+            ("keeper.toml", &format!("include = \"conf.d/b.toml\"\n{}", HEAD)),
+            ("conf.d/b.toml", &lamp("b", "000D6F0000000001")),
+            ("conf.d/a.toml", &lamp("a", "000D6F0000000002")),
+            ("conf.d/c.toml", &lamp("c", "000D6F0000000003")),
+        ]);
+        let config = files.load().unwrap_or_else(|err| panic!("{}", err));
+
+        // the included file comes first and is read once
+        assert_eq!(aliases(&config), vec!["b", "a", "c"]);
+        assert_eq!(config.included_file("a"), Some(&files.path("conf.d/a.toml")[..]));
+    }
+
+    #[test]
+    fn duplicate_table_in_included_file() {
+        let files = Files::new("duplicate-table", &[
+            ("keeper.toml", &format!("{}{}", HEAD, lamp("lamp", "000D6F0000000001"))),
+            ("conf.d/lamp.toml", &lamp("lamp", "000D6F0000000002")),
+        ]);
+
+        match files.load() {
+            Err(Error::DuplicateTable(location, first)) => {
+                assert_eq!(location.file, Some(files.path("conf.d/lamp.toml")));
+                assert_eq!(location.line, 1);
+                assert_eq!(first.file, Some(files.path("keeper.toml")));
+                assert_eq!(first.line, 6);
+            },
+            other => panic!("expected a duplicate table, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn duplicate_mac_in_included_file() {
+        let files = Files::new("duplicate-mac", &[
+            ("keeper.toml", &format!("{}{}", HEAD, lamp("lamp", "000D6F0000000001"))),
+            ("conf.d/hall.toml", &lamp("hall", "000D6F0000000001")),
+        ]);
+
+        match files.load() {
+            Err(Error::DuplicateMac(location, first)) => {
+                assert_eq!((location.file, location.table), (Some(files.path("conf.d/hall.toml")), Some(String::from("hall"))));
+                assert_eq!((first.file, first.table), (Some(files.path("keeper.toml")), Some(String::from("lamp"))));
+            },
+            other => panic!("expected a duplicate mac, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn changes_of_included_tables() {
+        let files = Files::new("included-change", &[
+            ("keeper.toml", &format!("{}{}", HEAD, lamp("lamp", "000D6F0000000001"))),
+            ("conf.d/hall.toml", &format!("{}[hall.evening]\nstart_fixed = [18, 0]\nend_fixed = [23, 0]\n",
+                                          lamp("hall", "000D6F0000000002"))),
+        ]);
+        let config = files.load().unwrap_or_else(|err| panic!("{}", err));
+        let old = config.to_toml();
+        let change = |change: &Fn(&mut Circle)| {
+            let mut changed = config.clone();
+            for circle in &mut changed.circles {
+                change(circle);
+            }
+            changed.to_toml()
+        };
+
+        let new = change(&|circle| if circle.alias == "lamp" { circle.default = CircleSetting::On });
+        assert_eq!(config.changed_included_table(&old, &new), None);
+
+        let hall = files.path("conf.d/hall.toml");
+        let new = change(&|circle| if circle.alias == "hall" { circle.default = CircleSetting::On });
+        assert_eq!(config.changed_included_table(&old, &new), Some(("hall", &hall[..])));
+
+        let new = change(&|circle| if circle.alias == "hall" { circle.toggles.clear() });
+        assert_eq!(config.changed_included_table(&old, &new), Some(("hall.evening", &hall[..])));
+
+        let mut new = old.clone();
+        new.push((String::from("hall.night"), vec![]));
+        assert_eq!(config.changed_included_table(&old, &new), Some(("hall.night", &hall[..])));
+    }
 }
@@ -0,0 +1,169 @@
+// This module exposes the tracker on a local Unix domain socket
+//
+// Every request is a single line, holding either a JSON array of strings or the same words
+// separated by whitespace:
+//
+//     ["list"]
+//     ["get", "<switch>"]
+//     ["switch", "<switch>", "on"|"off"|"<percent>", "<duration>"?]
+//     ["reload"]
+//
+// Every response is a single line holding a JSON object: `{"ok":true,"result":...}` or
+// `{"ok":false,"error":"..."}`.
+
+use libc;
+use rustc_serialize::json::{self, Json};
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use super::tracker::{TrackerClient, Level};
+use web::{SwitchStatus, parse_duration};
+
+/// Only the owner and the group of the socket are allowed to control the switches
+const SOCKET_MODE: u32 = 0o660;
+
+#[derive(RustcEncodable)]
+struct Reply<T> {
+    ok: bool,
+    result: Option<T>,
+    error: Option<String>,
+}
+
+fn reply<T: ::rustc_serialize::Encodable>(result: Result<T, String>) -> String {
+    let reply = match result {
+        Ok(result) => Reply { ok: true, result: Some(result), error: None },
+        Err(err) => Reply { ok: false, result: None, error: Some(err) },
+    };
+    format!("{}", json::as_json(&reply))
+}
+
+/// Split a request line into its words
+fn parse_request(line: &str) -> Option<Vec<String>> {
+    let line = line.trim();
+
+    if line.starts_with('[') {
+        Json::from_str(line).ok().and_then(|request| request.as_array().and_then(|words| {
+            words.iter().map(|word| word.as_string().map(String::from)).collect()
+        }))
+    } else {
+        Some(line.split_whitespace().map(String::from).collect())
+    }
+}
+
+fn execute(tracker: &TrackerClient, request: &[String]) -> String {
+    let words: Vec<_> = request.iter().map(|w| &w[..]).collect();
+    let word = |n: usize| words.get(n).cloned().unwrap_or("");
+
+    match (word(0), words.len()) {
+        ("list", 1) => reply(Ok(tracker.get_list())),
+        ("get", 2) => reply(tracker.get_switch(word(1))
+                                   .map(|info| SwitchStatus::new(&info))
+                                   .ok_or(format!("unknown switch '{}'", word(1)))),
+        ("switch", 3) => switch_state(tracker, word(1), word(2), None),
+        ("switch", 4) => switch_state(tracker, word(1), word(2), Some(word(3))),
+        ("reload", 1) => reply(tracker.reload().map(|_| true)),
+        _ => reply::<()>(Err(format!("invalid request '{}'", words.join(" ")))),
+    }
+}
+
+fn switch_state(tracker: &TrackerClient, switch: &str, state: &str, duration: Option<&str>) -> String {
+    let state = match Level::parse(state) {
+        Some(state) => state,
+        None => return reply::<()>(Err(format!("invalid state '{}', expected on, off or a percentage", state))),
+    };
+    let duration = match duration.map(|d| (d, parse_duration(d))) {
+        Some((_, Some(duration))) => Some(duration),
+        Some((d, None)) => return reply::<()>(Err(format!("invalid duration '{}'", d))),
+        None => None,
+    };
+
+    reply(tracker.switch(switch, state, duration)
+                 .map(|state| state.percent())
+                 .ok_or(format!("unknown switch '{}'", switch)))
+}
+
+fn handle_client(tracker: TrackerClient, stopping: Arc<RwLock<bool>>, stream: UnixStream) -> io::Result<()> {
+    let mut writer = try!(stream.try_clone());
+    let reader = BufReader::new(stream);
+
+    for line in reader.lines() {
+        let line = try!(line);
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        // the lock is held while executing, so `Control::stop` waits for the running requests
+        let stopped = stopping.read().expect("BUG: stopping lock poisoned");
+        let response = match parse_request(&line) {
+            _ if *stopped => reply::<()>(Err("keeper is shutting down".into())),
+            Some(request) => execute(&tracker, &request),
+            None => reply::<()>(Err("malformed request".into())),
+        };
+        drop(stopped);
+        try!(writeln!(writer, "{}", response));
+    }
+
+    Ok(())
+}
+
+pub struct Control {
+    stopping: Arc<RwLock<bool>>,
+}
+
+impl Control {
+    /// Listen on the given socket path and serve every client in its own thread
+    pub fn spawn(tracker: TrackerClient, path: &Path) -> io::Result<Control> {
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                          format!("{} exists and is not a socket", path.display())));
+            }
+            // stale socket of a previous instance
+            try!(fs::remove_file(path));
+        }
+
+        // the socket is created with the permissions of the umask, so other users must not be
+        // able to connect before the permissions are set
+        let umask = unsafe { libc::umask(!SOCKET_MODE as libc::mode_t & 0o777) };
+        let listener = UnixListener::bind(path);
+        unsafe { libc::umask(umask) };
+        let listener = try!(listener);
+        try!(fs::set_permissions(path, fs::Permissions::from_mode(SOCKET_MODE)));
+        info!("control socket listening on {}", path.display());
+
+        let stopping = Arc::new(RwLock::new(false));
+        let control = Control { stopping: stopping.clone() };
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let tracker = tracker.clone();
+                        let stopping = stopping.clone();
+                        thread::spawn(move || {
+                            if let Err(err) = handle_client(tracker, stopping, stream) {
+                                debug!("control client error: {}", err);
+                            }
+                        });
+                    },
+                    Err(err) => error!("control socket error: {}", err),
+                }
+            }
+        });
+
+        Ok(control)
+    }
+
+    /// Refuse all further requests; returns once the running requests are finished, after which
+    /// the tracker may be torn down
+    pub fn stop(&self) {
+        *self.stopping.write().expect("BUG: stopping lock poisoned") = true;
+    }
+}
@@ -0,0 +1,386 @@
+// This module implements the `keeper ctl` client, which controls a running daemon through its
+// HTTP API or its local control socket
+
+use rustc_serialize::json::{self, Json};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use time::{at, strptime};
+use web::{SwitchStatus, parse_duration};
+
+const DEFAULT_SERVER: &'static str = "127.0.0.1:3000";
+const DEFAULT_SOCKET: &'static str = "/run/keeper/keeper.sock";
+
+const USAGE: &'static str = "usage: keeper ctl [--server HOST:PORT | --socket PATH] [--json] COMMAND
+
+When neither --server nor --socket is given, /run/keeper/keeper.sock is used when it exists,
+otherwise 127.0.0.1:3000.
+
+commands:
+    list                        list all switches and their state
+    status <switch>             show the state and upcoming events of a switch
+    on <switch> [--for 2h]      switch on (optionally only for the given duration)
+    off <switch> [--for 2h]     switch off (optionally only for the given duration)
+    level <switch> <percent> [--for 2h]
+                                dim to the given level (binary switches are on above 0%)
+    next <switch>               show the next scheduled event of a switch
+    reload                      reload the configuration of the daemon";
+
+#[derive(Debug)]
+pub enum CtlError {
+    Usage(String),
+    Io(io::Error),
+    /// status code, endpoint and message of a failed HTTP request
+    Http(u16, String, String),
+    Remote(String),
+    InvalidResponse(String),
+}
+
+impl From<io::Error> for CtlError {
+    fn from(err: io::Error) -> CtlError {
+        CtlError::Io(err)
+    }
+}
+
+impl Error for CtlError {
+    fn description(&self) -> &str {
+        match *self {
+            CtlError::Usage(ref e) => &e[..],
+            CtlError::Io(ref e) => e.description(),
+            CtlError::Http(_, _, ref e) => &e[..],
+            CtlError::Remote(ref e) => &e[..],
+            CtlError::InvalidResponse(ref e) => &e[..],
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            CtlError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for CtlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            CtlError::Usage(ref e) => write!(f, "{}\n\n{}", e, USAGE),
+            CtlError::Io(ref e) => write!(f, "unable to reach keeper: {}", e),
+            CtlError::Http(404, ref endpoint, ref e) if e.is_empty() => write!(f, "{}: not found", endpoint),
+            CtlError::Http(code, ref endpoint, ref e) if e.is_empty() =>
+                write!(f, "{}: keeper responded with {}", endpoint, code),
+            CtlError::Http(code, ref endpoint, ref e) => write!(f, "{}: keeper responded with {}: {}", endpoint, code, e),
+            CtlError::Remote(ref e) => write!(f, "{}", e),
+            CtlError::InvalidResponse(ref e) => write!(f, "invalid response: {}", e),
+        }
+    }
+}
+
+/// Sends a request (see `control` for the request format) to the daemon and returns the JSON
+/// result
+trait Transport {
+    fn request(&self, request: &[&str]) -> Result<String, CtlError>;
+}
+
+/// Minimal HTTP client for the API of the daemon
+struct HttpTransport {
+    server: String,
+}
+
+impl Transport for HttpTransport {
+    fn request(&self, request: &[&str]) -> Result<String, CtlError> {
+        match (request.get(0).cloned(), request.get(2).cloned()) {
+            (Some("list"), _) => self.post(&["switches"]),
+            // on and off return whether the switch is on, levels the resulting level
+            (Some("switch"), Some("on")) | (Some("switch"), Some("off")) => {
+                let on: bool = try!(Ctl::decode(&try!(self.post(request))));
+                Ok(format!("{}", if on { 100 } else { 0 }))
+            },
+            (Some("switch"), Some(_)) => {
+                let mut segments = request.to_vec();
+                segments.insert(2, "level");
+                self.post(&segments)
+            },
+            _ => self.post(request),
+        }
+    }
+}
+
+/// Client of the local control socket of the daemon
+struct SocketTransport {
+    path: String,
+}
+
+impl Transport for SocketTransport {
+    fn request(&self, request: &[&str]) -> Result<String, CtlError> {
+        let mut stream = try!(UnixStream::connect(&self.path[..]));
+        try!(writeln!(stream, "{}", json::as_json(&request)));
+
+        let mut response = String::new();
+        try!(BufReader::new(stream).read_line(&mut response));
+
+        let response = try!(Json::from_str(&response).map_err(|err|
+            CtlError::InvalidResponse(format!("{}", err))));
+
+        match response.find("ok").and_then(|ok| ok.as_boolean()) {
+            Some(true) => Ok(format!("{}", response.find("result").unwrap_or(&Json::Null))),
+            Some(false) => Err(CtlError::Remote(response.find("error")
+                                                        .and_then(|e| e.as_string())
+                                                        .unwrap_or("unknown error")
+                                                        .into())),
+            None => Err(CtlError::InvalidResponse("missing 'ok' field".into())),
+        }
+    }
+}
+
+impl HttpTransport {
+    /// Percent encode a path segment
+    fn encode(segment: &str) -> String {
+        let mut encoded = String::new();
+
+        for byte in segment.bytes() {
+            match byte {
+                b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' | b'-' | b'_' | b'.' | b'~' =>
+                    encoded.push(byte as char),
+                _ => encoded.push_str(&format!("%{:02X}", byte)),
+            }
+        }
+
+        encoded
+    }
+
+    fn post(&self, segments: &[&str]) -> Result<String, CtlError> {
+        let path: Vec<_> = segments.iter().map(|s| HttpTransport::encode(s)).collect();
+        let endpoint = format!("/api/{}", path.join("/"));
+        let mut stream = try!(TcpStream::connect(&self.server[..]));
+
+        try!(write!(stream, "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Length: 0\r\n\r\n",
+                    endpoint, self.server));
+
+        let mut response = String::new();
+        try!(stream.read_to_string(&mut response));
+
+        let (head, body) = match response.find("\r\n\r\n") {
+            Some(pos) => (&response[..pos], &response[pos + 4..]),
+            None => return Err(CtlError::InvalidResponse("missing HTTP header".into())),
+        };
+        let code = head.split(' ').nth(1).and_then(|c| c.parse::<u16>().ok());
+
+        match code {
+            Some(200) => Ok(body.into()),
+            Some(code) => Err(CtlError::Http(code, endpoint, body.trim().into())),
+            None => Err(CtlError::InvalidResponse("missing HTTP status".into())),
+        }
+    }
+}
+
+struct Ctl {
+    transport: Box<Transport>,
+    json: bool,
+}
+
+impl Ctl {
+    fn decode<T: ::rustc_serialize::Decodable>(response: &str) -> Result<T, CtlError> {
+        json::decode(response).map_err(|err| CtlError::InvalidResponse(format!("{}", err)))
+    }
+
+    /// Convert a RFC 3339 UTC timestamp of the API to local time
+    fn local_time(timestamp: &str) -> String {
+        strptime(timestamp, "%Y-%m-%dT%H:%M:%SZ")
+            .map(|tm| format!("{}", at(tm.to_timespec()).strftime("%a %d %b %H:%M").unwrap()))
+            .unwrap_or(timestamp.into())
+    }
+
+    fn state(percent: u8) -> String {
+        match percent {
+            0 => "off".into(),
+            100 => "on".into(),
+            percent => format!("{}%", percent),
+        }
+    }
+
+    fn next_event(status: &SwitchStatus) -> String {
+        status.next_levels.iter().next().map_or("manual".into(), |(ts, level)|
+            format!("{} {}", Ctl::local_time(ts), Ctl::state(*level)))
+    }
+
+    fn list(&self) -> Result<(), CtlError> {
+        let response = try!(self.transport.request(&["list"]));
+
+        if self.json {
+            println!("{}", response.trim());
+            return Ok(());
+        }
+
+        let switches: Vec<String> = try!(Ctl::decode(&response));
+        println!("{:<24} {:<5} {}", "SWITCH", "STATE", "NEXT");
+        for switch in switches {
+            let status: SwitchStatus = try!(Ctl::decode(&try!(self.transport.request(&["get", &switch]))));
+            println!("{:<24} {:<5} {}", switch, Ctl::state(status.level), Ctl::next_event(&status));
+        }
+
+        Ok(())
+    }
+
+    fn status(&self, switch: &str) -> Result<(), CtlError> {
+        let response = try!(self.transport.request(&["get", switch]));
+
+        if self.json {
+            println!("{}", response.trim());
+            return Ok(());
+        }
+
+        let status: SwitchStatus = try!(Ctl::decode(&response));
+        if status.level == status.scheduled_level {
+            println!("{}: {}", switch, Ctl::state(status.level));
+        } else {
+            println!("{}: {} (scheduled: {})", switch, Ctl::state(status.level),
+                     Ctl::state(status.scheduled_level));
+        }
+        for (ts, level) in &status.next_levels {
+            println!("  {:<20} {}", Ctl::local_time(ts), Ctl::state(*level));
+        }
+
+        Ok(())
+    }
+
+    fn next(&self, switch: &str) -> Result<(), CtlError> {
+        let response = try!(self.transport.request(&["get", switch]));
+        let status: SwitchStatus = try!(Ctl::decode(&response));
+
+        if self.json {
+            let next: Vec<_> = status.next_levels.iter().take(1).collect();
+            println!("{}", json::as_json(&next));
+        } else {
+            println!("{}", Ctl::next_event(&status));
+        }
+
+        Ok(())
+    }
+
+    fn switch(&self, switch: &str, state: &str, duration: Option<&str>) -> Result<(), CtlError> {
+        let response = try!(match duration {
+            Some(duration) => self.transport.request(&["switch", switch, state, duration]),
+            None => self.transport.request(&["switch", switch, state]),
+        });
+
+        if self.json {
+            println!("{}", response.trim());
+        } else {
+            let level: u8 = try!(Ctl::decode(&response));
+            println!("{}: {}", switch, Ctl::state(level));
+        }
+
+        Ok(())
+    }
+
+    fn reload(&self) -> Result<(), CtlError> {
+        let response = try!(self.transport.request(&["reload"]));
+
+        if self.json {
+            println!("{}", response.trim());
+        } else {
+            println!("configuration reloaded");
+        }
+
+        Ok(())
+    }
+}
+
+fn execute(args: &[String]) -> Result<(), CtlError> {
+    let mut server = None;
+    let mut socket = None;
+    let mut json = false;
+    let mut duration = None;
+    let mut positional = vec![];
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "--json" => json = true,
+            "--server" => {
+                server = Some(try!(args.next().ok_or(CtlError::Usage("--server requires a value".into()))).clone());
+            },
+            "--socket" => {
+                socket = Some(try!(args.next().ok_or(CtlError::Usage("--socket requires a path".into()))).clone());
+            },
+            "--for" => {
+                let value = try!(args.next().ok_or(CtlError::Usage("--for requires a duration".into())));
+                if parse_duration(value).is_none() {
+                    return Err(CtlError::Usage(format!("invalid duration '{}'", value)));
+                }
+                duration = Some(&value[..]);
+            },
+            "-h" | "--help" => return Err(CtlError::Usage("keeper ctl".into())),
+            _ if arg.starts_with("--") => return Err(CtlError::Usage(format!("unknown option '{}'", arg))),
+            _ => positional.push(&arg[..]),
+        }
+    }
+
+    let transport: Box<Transport> = match (server, socket) {
+        (Some(_), Some(_)) => return Err(CtlError::Usage("use either --server or --socket".into())),
+        (Some(server), None) => Box::new(HttpTransport { server: server }),
+        (None, Some(path)) => Box::new(SocketTransport { path: path }),
+        (None, None) => {
+            if Path::new(DEFAULT_SOCKET).exists() {
+                Box::new(SocketTransport { path: DEFAULT_SOCKET.into() })
+            } else {
+                Box::new(HttpTransport { server: DEFAULT_SERVER.into() })
+            }
+        },
+    };
+
+    let ctl = Ctl {
+        transport: transport,
+        json: json,
+    };
+
+    match (positional.get(0).cloned(), positional.get(1).cloned(), positional.len()) {
+        (Some("list"), None, 1) => ctl.list(),
+        (Some("status"), Some(switch), 2) => ctl.status(switch),
+        (Some("next"), Some(switch), 2) => ctl.next(switch),
+        (Some(state @ "on"), Some(switch), 2) |
+        (Some(state @ "off"), Some(switch), 2) => ctl.switch(switch, state, duration),
+        (Some("level"), Some(switch), 3) => {
+            let level = positional[2];
+            match level.trim_right_matches('%').parse::<u8>() {
+                Ok(percent) if percent <= 100 => ctl.switch(switch, &format!("{}", percent), duration),
+                _ => Err(CtlError::Usage(format!("invalid level '{}', expected 0 to 100", level))),
+            }
+        },
+        (Some("reload"), None, 1) => ctl.reload(),
+        (None, _, _) => Err(CtlError::Usage("missing command".into())),
+        _ => Err(CtlError::Usage(format!("invalid command '{}'", positional.join(" ")))),
+    }
+}
+
+/// Run the `keeper ctl` client with the arguments following `ctl`; returns the exit code.
+pub fn run(args: &[String]) -> i32 {
+    match execute(args) {
+        Ok(()) => 0,
+        Err(err) => {
+            let _ = writeln!(io::stderr(), "{}", err);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CtlError;
+
+    #[test]
+    fn http_errors_name_the_endpoint() {
+        assert_eq!(format!("{}", CtlError::Http(404, "/api/get/lamp".into(), String::new())),
+                   "/api/get/lamp: not found");
+        assert_eq!(format!("{}", CtlError::Http(404, "/api/discover".into(), "no sticks".into())),
+                   "/api/discover: keeper responded with 404: no sticks");
+        assert_eq!(format!("{}", CtlError::Http(503, "/api/status".into(), String::new())),
+                   "/api/status: keeper responded with 503");
+    }
+}
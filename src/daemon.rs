@@ -0,0 +1,44 @@
+// This module detaches the process from the terminal
+
+use libc;
+use std::ffi::CString;
+use std::io;
+
+/// Fork into the background, start a new session and redirect the standard streams to
+/// /dev/null. Must be called before any thread is spawned.
+pub fn daemonize() -> io::Result<()> {
+    unsafe {
+        match libc::fork() {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => {},
+            _ => libc::_exit(0),
+        }
+
+        if libc::setsid() == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let root = CString::new("/").unwrap();
+        if libc::chdir(root.as_ptr()) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let devnull = CString::new("/dev/null").unwrap();
+        let fd = libc::open(devnull.as_ptr(), libc::O_RDWR);
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        for stream in &[libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            if libc::dup2(fd, *stream) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        if fd > libc::STDERR_FILENO {
+            libc::close(fd);
+        }
+    }
+
+    Ok(())
+}
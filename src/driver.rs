@@ -0,0 +1,187 @@
+// This module provides the drivers that operate the relays of the switches
+
+use hyper::Client;
+use serial::SerialClient;
+use std::process::Command;
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::Duration;
+use tracker::Level;
+
+/// Timeout of the requests of the HTTP driver
+const HTTP_TIMEOUT_SECONDS: u64 = 10;
+
+/// Placeholder for the percentage in the level URL and the level command
+const LEVEL_PLACEHOLDER: &'static str = "{level}";
+
+/// Operates the relay of a switch; the operations must not block the tracker
+pub trait SwitchDriver {
+    /// Whether the driver supports levels between off and on
+    fn dimmable(&self) -> bool {
+        false
+    }
+
+    /// Set the output; binary drivers only receive off and on
+    fn set_level(&self, level: Level);
+
+    /// Block until all requested operations are performed
+    fn flush(&self) {
+    }
+}
+
+/// Plugwise circle, operated by the serial thread of its stick
+pub struct PlugwiseDriver {
+    serial: SerialClient,
+    alias: String,
+}
+
+impl PlugwiseDriver {
+    pub fn new(serial: SerialClient, alias: &str) -> PlugwiseDriver {
+        PlugwiseDriver {
+            serial: serial,
+            alias: alias.into(),
+        }
+    }
+}
+
+impl SwitchDriver for PlugwiseDriver {
+    fn set_level(&self, level: Level) {
+        if level.is_on() {
+            self.serial.switch_on(&self.alias[..]);
+        } else {
+            self.serial.switch_off(&self.alias[..]);
+        }
+    }
+}
+
+/// Driver that does not operate any relay (used by simulations)
+pub struct NullDriver {
+    dimmable: bool,
+}
+
+impl NullDriver {
+    pub fn new(dimmable: bool) -> NullDriver {
+        NullDriver {
+            dimmable: dimmable,
+        }
+    }
+}
+
+impl SwitchDriver for NullDriver {
+    fn dimmable(&self) -> bool {
+        self.dimmable
+    }
+
+    fn set_level(&self, _: Level) {
+    }
+}
+
+enum Request {
+    Level(Level),
+    Flush(Sender<()>),
+}
+
+/// Relay operated by a HTTP request or a command; the operations are performed by a thread of
+/// its own, so a slow or unreachable relay does not block the tracker. The thread ends when
+/// the driver is dropped.
+pub struct ExternalDriver {
+    tx: Sender<Request>,
+    dimmable: bool,
+}
+
+impl ExternalDriver {
+    fn spawn<F>(alias: &str, dimmable: bool, operate: F) -> ExternalDriver
+        where F: Fn(Level) -> Result<(), String> + Send + 'static {
+        let (tx, rx) = channel();
+        let alias = String::from(alias);
+
+        thread::spawn(move || {
+            for request in rx.iter() {
+                match request {
+                    Request::Level(level) => {
+                        if let Err(err) = operate(level) {
+                            error!("unable to switch '{}' {}: {}", alias, level, err);
+                        }
+                    },
+                    Request::Flush(tx) => {
+                        let _ = tx.send(());
+                    },
+                }
+            }
+        });
+
+        ExternalDriver {
+            tx: tx,
+            dimmable: dimmable,
+        }
+    }
+
+    /// The on or off variant; or the level variant with the percentage filled in for levels
+    /// between off and on, when available
+    fn select(level: Level, on: &str, off: &str, dimmed: &Option<String>) -> String {
+        match (level.percent(), dimmed) {
+            (0, _) => off.into(),
+            (_, &Some(ref dimmed)) => dimmed.replace(LEVEL_PLACEHOLDER, &format!("{}", level.percent())),
+            (_, &None) => on.into(),
+        }
+    }
+
+    /// Request the on or off URL (e.g. `http://relay/cm?cmnd=Power%20On` for Tasmota or
+    /// `http://relay/relay/0?turn=on` for Shelly); a dimmer is set by the level URL, in which
+    /// `{level}` is replaced by the percentage (e.g. `http://dimmer/light/0?turn=on&brightness={level}`)
+    pub fn http(alias: &str, on_url: &str, off_url: &str, level_url: Option<&str>) -> ExternalDriver {
+        let (on_url, off_url) = (String::from(on_url), String::from(off_url));
+        let level_url = level_url.map(String::from);
+
+        ExternalDriver::spawn(alias, level_url.is_some(), move |level| {
+            let mut client = Client::new();
+            client.set_read_timeout(Some(Duration::from_secs(HTTP_TIMEOUT_SECONDS)));
+            client.set_write_timeout(Some(Duration::from_secs(HTTP_TIMEOUT_SECONDS)));
+
+            let url = ExternalDriver::select(level, &on_url, &off_url, &level_url);
+            let response = try!(client.get(&url[..]).send().map_err(|err| format!("{}: {}", url, err)));
+
+            if response.status.is_success() {
+                Ok(())
+            } else {
+                Err(format!("{} responded with {}", url, response.status))
+            }
+        })
+    }
+
+    /// Run the on or off command with `sh -c`; a dimmer is set by the level command, in which
+    /// `{level}` is replaced by the percentage
+    pub fn command(alias: &str, on_command: &str, off_command: &str, level_command: Option<&str>) -> ExternalDriver {
+        let (on_command, off_command) = (String::from(on_command), String::from(off_command));
+        let level_command = level_command.map(String::from);
+
+        ExternalDriver::spawn(alias, level_command.is_some(), move |level| {
+            let command = ExternalDriver::select(level, &on_command, &off_command, &level_command);
+            let status = try!(Command::new("sh").arg("-c").arg(&command[..]).status()
+                                  .map_err(|err| format!("{}: {}", command, err)));
+
+            if status.success() {
+                Ok(())
+            } else {
+                Err(format!("{} exited with {}", command, status))
+            }
+        })
+    }
+}
+
+impl SwitchDriver for ExternalDriver {
+    fn dimmable(&self) -> bool {
+        self.dimmable
+    }
+
+    fn set_level(&self, level: Level) {
+        self.tx.send(Request::Level(level)).expect("BUG: driver thread ended unexpectedly");
+    }
+
+    fn flush(&self) {
+        let (tx, rx) = channel();
+
+        self.tx.send(Request::Flush(tx)).expect("BUG: driver thread ended unexpectedly");
+        rx.recv().expect("BUG: driver thread did not acknowledge flush");
+    }
+}
@@ -0,0 +1,528 @@
+// This module edits the source of the configuration file: only the changed keys and tables are
+// rewritten, so comments, blank lines and the order of the other keys and tables are kept.
+
+use config::{self, Pairs, Tables};
+use std::fmt;
+use toml;
+
+/// Key/value pair of a table
+struct Pair {
+    key: String,
+    line: usize,
+    /// number of lines of the pair (multi-line arrays and strings)
+    length: usize,
+}
+
+/// A table and the lines it occupies
+struct Section {
+    /// dotted name of the table; empty for the keys before the first table header
+    name: String,
+    /// first line, including the comments directly above the header
+    start: usize,
+    /// line after the last key/value pair
+    end: usize,
+    pairs: Vec<Pair>,
+}
+
+pub struct ConfigEditor {
+    lines: Vec<String>,
+    trailing_newline: bool,
+}
+
+impl ConfigEditor {
+    pub fn new(source: &str) -> ConfigEditor {
+        ConfigEditor {
+            lines: source.lines().map(String::from).collect(),
+            trailing_newline: source.is_empty() || source.ends_with('\n'),
+        }
+    }
+
+    /// Number of lines of the key/value pair starting at line `n`; a value ends at the end of the
+    /// first line on which all its brackets and multi-line strings are closed
+    fn pair_length(&self, n: usize) -> usize {
+        let mut depth = 0;
+        let mut open: Option<&'static str> = None;
+
+        for (i, line) in self.lines[n..].iter().enumerate() {
+            let mut rest = if i == 0 { line.splitn(2, '=').nth(1).unwrap_or("") } else { &line[..] };
+
+            while !rest.is_empty() {
+                if let Some(delimiter) = open {
+                    match rest.find(delimiter) {
+                        Some(end) => {
+                            rest = &rest[end + delimiter.len()..];
+                            open = None;
+                        },
+                        None => rest = "",
+                    }
+                } else if rest.starts_with("\"\"\"") || rest.starts_with("'''") {
+                    open = Some(if rest.starts_with('"') { "\"\"\"" } else { "'''" });
+                    rest = &rest[3..];
+                } else if rest.starts_with('"') || rest.starts_with('\'') {
+                    let quote = if rest.starts_with('"') { '"' } else { '\'' };
+                    let mut escaped = false;
+                    let end = rest[1..].char_indices().find(|&(_, c)| {
+                        let end = c == quote && !escaped;
+                        escaped = quote == '"' && c == '\\' && !escaped;
+                        end
+                    });
+                    rest = end.map_or("", |(end, _)| &rest[end + 2..]);
+                } else {
+                    let c = rest.chars().next().expect("BUG: empty rest of line");
+                    match c {
+                        '[' | '{' => depth += 1,
+                        ']' | '}' => depth -= 1,
+                        '#' => break,
+                        _ => {}
+                    }
+                    rest = &rest[c.len_utf8()..];
+                }
+            }
+
+            if open.is_none() && depth <= 0 {
+                return i + 1;
+            }
+        }
+
+        self.lines.len() - n
+    }
+
+    /// All tables of the source, in order
+    fn sections(&self) -> Vec<Section> {
+        let mut sections = vec![Section { name: String::new(), start: 0, end: 0, pairs: vec![] }];
+        let mut n = 0;
+
+        while n < self.lines.len() {
+            let trimmed = self.lines[n].trim_left();
+
+            if let Some(name) = config::table_name(trimmed) {
+                // the comments directly above a header belong to its table
+                let mut start = n;
+                while start > sections.last().map_or(0, |s| s.end) && self.lines[start - 1].trim_left().starts_with('#') {
+                    start -= 1;
+                }
+                sections.push(Section { name: name, start: start, end: n + 1, pairs: vec![] });
+                n += 1;
+            } else if !trimmed.is_empty() && !trimmed.starts_with('#') && trimmed.contains('=') {
+                let length = self.pair_length(n);
+                let key = trimmed[..trimmed.find('=').expect("BUG: no '=' in pair")].trim().trim_matches('"');
+                let section = sections.last_mut().expect("BUG: no section");
+
+                section.pairs.push(Pair { key: key.into(), line: n, length: length });
+                section.end = n + length;
+                n += length;
+            } else {
+                n += 1;
+            }
+        }
+
+        sections
+    }
+
+    fn section(&self, table: &str) -> Option<Section> {
+        self.sections().into_iter().find(|section| section.name == table)
+    }
+
+    pub fn has_table(&self, table: &str) -> bool {
+        self.section(table).is_some()
+    }
+
+    /// Replace `count` lines starting at line `n`
+    fn replace(&mut self, n: usize, count: usize, lines: Vec<String>) {
+        let _ = self.lines.drain(n..n + count);
+        for (i, line) in lines.into_iter().enumerate() {
+            self.lines.insert(n + i, line);
+        }
+    }
+
+    fn pair_line(key: &str, value: &toml::Value) -> String {
+        format!("{} = {}", config::quote_key(key), value)
+    }
+
+    /// Set a key of an existing table; a pair that already holds the value is left untouched
+    /// (keeping its comment)
+    pub fn set(&mut self, table: &str, key: &str, value: &toml::Value) -> bool {
+        let section = match self.section(table) {
+            Some(section) => section,
+            None => return false,
+        };
+
+        match section.pairs.iter().find(|pair| pair.key == key) {
+            Some(pair) => {
+                let indent = {
+                    let line = &self.lines[pair.line];
+                    String::from(&line[..line.len() - line.trim_left().len()])
+                };
+                let source = self.lines[pair.line..pair.line + pair.length].join("\n");
+                let current = toml::Parser::new(&source).parse().and_then(|mut parsed| parsed.remove(key));
+
+                if current.as_ref() != Some(value) {
+                    let replacement = format!("{}{}", indent, ConfigEditor::pair_line(key, value));
+                    self.replace(pair.line, pair.length, vec![replacement]);
+                }
+            },
+            None => {
+                let indent = section.pairs.last().map_or(String::new(), |pair| {
+                    let line = &self.lines[pair.line];
+                    line[..line.len() - line.trim_left().len()].into()
+                });
+                self.lines.insert(section.end, format!("{}{}", indent, ConfigEditor::pair_line(key, value)));
+            },
+        }
+
+        true
+    }
+
+    /// Remove a key of a table; returns whether the key existed
+    pub fn remove(&mut self, table: &str, key: &str) -> bool {
+        let pair = self.section(table).and_then(|section| section.pairs.into_iter().find(|pair| pair.key == key));
+
+        match pair {
+            Some(pair) => {
+                self.replace(pair.line, pair.length, vec![]);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Remove a table and its subtables, up to the next table; returns whether the table existed
+    pub fn remove_table(&mut self, table: &str) -> bool {
+        let prefix = format!("{}.", table);
+        let sections = self.sections();
+        let mut ranges = vec![];
+
+        for (n, section) in sections.iter().enumerate() {
+            if section.name == table || section.name.starts_with(&prefix) {
+                let end = sections.get(n + 1).map_or(self.lines.len(), |next| next.start);
+                ranges.push((section.start, end));
+            }
+        }
+
+        // from the end, so the positions of the other ranges stay valid
+        for &(start, end) in ranges.iter().rev() {
+            self.replace(start, end - start, vec![]);
+        }
+        // no blank lines at the end of the file
+        while self.lines.last().map_or(false, |line| line.trim().is_empty()) {
+            let _ = self.lines.pop();
+        }
+
+        !ranges.is_empty()
+    }
+
+    /// Add a table; it is placed after `after` (and its subtables) when that table exists,
+    /// otherwise at the end
+    pub fn add_table(&mut self, table: &str, pairs: &[(String, toml::Value)], after: Option<&str>) {
+        let position = after.and_then(|after| {
+            let prefix = format!("{}.", after);
+            self.sections().into_iter()
+                           .filter(|section| section.name == after || section.name.starts_with(&prefix))
+                           .map(|section| section.end)
+                           .max()
+        });
+
+        let mut lines = vec![config::table_header(table)];
+        lines.extend(pairs.iter().map(|&(ref key, ref value)| ConfigEditor::pair_line(key, value)));
+
+        match position {
+            Some(position) if position < self.lines.len() => {
+                lines.insert(0, String::new());
+                self.replace(position, 0, lines);
+            },
+            _ => {
+                while self.lines.last().map_or(false, |line| line.trim().is_empty()) {
+                    let _ = self.lines.pop();
+                }
+                if !self.lines.is_empty() {
+                    self.lines.push(String::new());
+                }
+                self.lines.extend(lines);
+            },
+        }
+    }
+}
+
+impl ConfigEditor {
+    /// Parent table and key of a subtable
+    fn split_table(table: &str) -> Option<(&str, &str)> {
+        table.rfind('.').map(|dot| (&table[..dot], &table[dot + 1..]))
+    }
+
+    fn find<'a>(tables: &'a Tables, name: &str) -> Option<&'a Pairs> {
+        tables.iter().find(|&&(ref n, _)| n == name).map(|&(_, ref pairs)| pairs)
+    }
+
+    /// Remove a table, which may also be a inline table of its parent
+    fn remove_any_table(&mut self, table: &str) {
+        if !self.remove_table(table) {
+            if let Some((parent, key)) = ConfigEditor::split_table(table) {
+                let _ = self.remove(parent, key);
+            }
+        }
+    }
+
+    /// Patch the source from the `old` tables to the `new` tables (both in the canonical form of
+    /// `Config::to_toml`); only the keys and tables that differ are rewritten
+    pub fn update(&mut self, old: &Tables, new: &Tables) {
+        for &(ref name, _) in old {
+            if ConfigEditor::find(new, name).is_none() {
+                self.remove_any_table(name);
+            }
+        }
+
+        for &(ref name, ref pairs) in new {
+            match ConfigEditor::find(old, name) {
+                Some(old_pairs) if old_pairs == pairs => {},
+                Some(old_pairs) if self.has_table(name) => {
+                    for &(ref key, _) in old_pairs {
+                        if !pairs.iter().any(|&(ref k, _)| k == key) {
+                            let _ = self.remove(name, key);
+                        }
+                    }
+                    for &(ref key, ref value) in pairs {
+                        if !old_pairs.iter().any(|&(ref k, ref v)| k == key && v == value) {
+                            let _ = self.set(name, key, value);
+                        }
+                    }
+                },
+                old_pairs => {
+                    // new table, or a inline table that is replaced by a table
+                    if old_pairs.is_some() {
+                        self.remove_any_table(name);
+                    }
+                    let parent = ConfigEditor::split_table(name).map(|(parent, _)| parent);
+                    self.add_table(name, pairs, parent);
+                },
+            }
+        }
+    }
+}
+
+impl fmt::Display for ConfigEditor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{}", self.lines.join("\n")));
+        if self.trailing_newline && !self.lines.is_empty() {
+            try!(write!(f, "\n"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use toml;
+
+    const SOURCE: &'static str = "# keeper
+[config]
+latitude = 52.0 # home
+ntp = [
+    \"a.pool.ntp.org\", # first
+    \"b.pool.ntp.org\",
+]
+
+# the lamp
+[lamp]
+mac = \"000D6F0000123456\"
+evening = { start_sunset = 0, end_fixed = [23, 0] }
+
+# morning
+[lamp.morning]
+start_fixed = [7, 0] # wake
+end_sunrise = 15
+
+[pond]
+driver = \"http\"
+";
+
+    fn int(i: i64) -> toml::Value {
+        toml::Value::Integer(i)
+    }
+
+    fn string(s: &str) -> toml::Value {
+        toml::Value::String(s.into())
+    }
+
+    fn time(h: i64, m: i64) -> toml::Value {
+        toml::Value::Array(vec![int(h), int(m)])
+    }
+
+    fn edited<F: FnOnce(&mut ConfigEditor)>(source: &str, edit: F) -> String {
+        let mut editor = ConfigEditor::new(source);
+        edit(&mut editor);
+        editor.to_string()
+    }
+
+    #[test]
+    fn unchanged_source_round_trips() {
+        assert_eq!(ConfigEditor::new(SOURCE).to_string(), SOURCE);
+        assert_eq!(ConfigEditor::new("[lamp]\nmac = \"1\"").to_string(), "[lamp]\nmac = \"1\"");
+        assert_eq!(ConfigEditor::new("").to_string(), "");
+    }
+
+    #[test]
+    fn sections() {
+        let sections = ConfigEditor::new(SOURCE).sections();
+        let spans: Vec<_> = sections.iter().map(|s| (&s.name[..], s.start, s.end)).collect();
+        assert_eq!(spans, vec![("", 0, 0), ("config", 0, 7), ("lamp", 8, 12), ("lamp.morning", 13, 17), ("pond", 18, 20)]);
+
+        let pairs: Vec<_> = sections[1..].iter()
+                                         .flat_map(|s| s.pairs.iter().map(|p| (&p.key[..], p.line, p.length)))
+                                         .collect();
+        assert_eq!(pairs, vec![("latitude", 2, 1), ("ntp", 3, 4), ("mac", 10, 1), ("evening", 11, 1),
+                               ("start_fixed", 15, 1), ("end_sunrise", 16, 1), ("driver", 19, 1)]);
+    }
+
+    #[test]
+    fn pair_length() {
+        let editor = ConfigEditor::new("a = [1, # [\n  2]\n\
+                                        b = \"[{\"\n\
+                                        c = '''\n[\n'''\n\
+                                        d = { e = [1,\n 2] }\n\
+                                        f = \"\\\"[\"\n\
+                                        g = [\n");
+        let lengths: Vec<_> = [0, 2, 3, 6, 8, 9].iter().map(|&n| editor.pair_length(n)).collect();
+        // the unterminated array runs to the end of the file
+        assert_eq!(lengths, vec![2, 1, 3, 2, 1, 1]);
+    }
+
+    #[test]
+    fn set_replaces_only_the_changed_pair() {
+        let output = edited(SOURCE, |editor| {
+            assert!(editor.set("lamp.morning", "start_fixed", &time(6, 30)));
+            assert!(editor.set("config", "latitude", &toml::Value::Float(52.0)));
+        });
+        assert_eq!(output, SOURCE.replace("start_fixed = [7, 0] # wake", "start_fixed = [6, 30]"));
+    }
+
+    #[test]
+    fn set_replaces_a_multi_line_value() {
+        let output = edited(SOURCE, |editor| assert!(editor.set("config", "ntp", &string("pool.ntp.org"))));
+        assert_eq!(output, SOURCE.replace("ntp = [\n    \"a.pool.ntp.org\", # first\n    \"b.pool.ntp.org\",\n]",
+                                          "ntp = \"pool.ntp.org\""));
+    }
+
+    #[test]
+    fn set_adds_a_missing_key_after_the_last_pair() {
+        let output = edited("[lamp]\n    mac = \"1\" # comment\n\n[pond]\n", |editor| {
+            assert!(editor.set("lamp", "default", &string("on")));
+            assert!(!editor.set("garden", "default", &string("on")));
+        });
+        assert_eq!(output, "[lamp]\n    mac = \"1\" # comment\n    default = \"on\"\n\n[pond]\n");
+    }
+
+    #[test]
+    fn remove() {
+        let output = edited(SOURCE, |editor| {
+            assert!(editor.remove("config", "ntp"));
+            assert!(editor.remove("lamp", "evening"));
+            assert!(!editor.remove("lamp", "evening"));
+            assert!(!editor.remove("garden", "mac"));
+        });
+        assert_eq!(output, "# keeper
+[config]
+latitude = 52.0 # home
+
+# the lamp
+[lamp]
+mac = \"000D6F0000123456\"
+
+# morning
+[lamp.morning]
+start_fixed = [7, 0] # wake
+end_sunrise = 15
+
+[pond]
+driver = \"http\"
+");
+    }
+
+    #[test]
+    fn remove_table_with_its_subtables_and_comments() {
+        let output = edited(SOURCE, |editor| {
+            assert!(editor.remove_table("lamp"));
+            assert!(!editor.remove_table("garden"));
+        });
+        assert_eq!(output, "# keeper
+[config]
+latitude = 52.0 # home
+ntp = [
+    \"a.pool.ntp.org\", # first
+    \"b.pool.ntp.org\",
+]
+
+[pond]
+driver = \"http\"
+");
+    }
+
+    #[test]
+    fn remove_last_table() {
+        let output = edited(SOURCE, |editor| assert!(editor.remove_table("pond")));
+        assert_eq!(output, SOURCE.replace("\n[pond]\ndriver = \"http\"\n", ""));
+    }
+
+    #[test]
+    fn add_table_after_a_table_and_its_subtables() {
+        let output = edited(SOURCE, |editor| {
+            editor.add_table("lamp.night", &[("start_fixed".into(), time(23, 0)), ("end_sunrise".into(), int(0))],
+                             Some("lamp"));
+        });
+        assert_eq!(output, SOURCE.replace("end_sunrise = 15\n",
+                                          "end_sunrise = 15\n\n[lamp.night]\nstart_fixed = [23, 0]\nend_sunrise = 0\n"));
+    }
+
+    #[test]
+    fn add_table_at_the_end_without_trailing_newline() {
+        let output = edited("[config]\nlatitude = 52.0\n\n", |editor| {
+            editor.add_table("living room", &[("mac".into(), string("1"))], Some("garden"));
+        });
+        assert_eq!(output, "[config]\nlatitude = 52.0\n\n[\"living room\"]\nmac = \"1\"\n");
+
+        let output = edited("[config]\nlatitude = 52.0", |editor| editor.add_table("lamp", &[], None));
+        assert_eq!(output, "[config]\nlatitude = 52.0\n\n[lamp]");
+    }
+
+    #[test]
+    fn update() {
+        let lamp = vec![("mac".into(), string("000D6F0000123456"))];
+        let evening = vec![("start_sunset".into(), int(0)), ("end_fixed".into(), time(23, 0))];
+        let morning = vec![("start_fixed".into(), time(7, 0)), ("end_sunrise".into(), int(15))];
+        let old = vec![(String::from("lamp"), lamp.clone()),
+                       (String::from("lamp.evening"), evening),
+                       (String::from("lamp.morning"), morning),
+                       (String::from("pond"), vec![("driver".into(), string("http"))])];
+        let new = vec![(String::from("lamp"), lamp),
+                       (String::from("lamp.evening"), vec![("start_sunset".into(), int(10)),
+                                                           ("end_fixed".into(), time(23, 0))]),
+                       (String::from("lamp.morning"), vec![("start_fixed".into(), time(7, 0)),
+                                                           ("end_sunrise".into(), int(15)),
+                                                           ("level".into(), int(50))])];
+
+        let output = edited(SOURCE, |editor| editor.update(&old, &new));
+        // the inline table becomes a table, as its replacement cannot be placed inline
+        assert_eq!(output, "# keeper
+[config]
+latitude = 52.0 # home
+ntp = [
+    \"a.pool.ntp.org\", # first
+    \"b.pool.ntp.org\",
+]
+
+# the lamp
+[lamp]
+mac = \"000D6F0000123456\"
+
+# morning
+[lamp.morning]
+start_fixed = [7, 0] # wake
+end_sunrise = 15
+level = 50
+
+[lamp.evening]
+start_sunset = 10
+end_fixed = [23, 0]
+");
+    }
+}
@@ -0,0 +1,55 @@
+// This module provides the logging used when no log4rs configuration is available
+
+use log::{self, LogLevelFilter, LogMetadata, LogRecord, SetLoggerError};
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use std::sync::Mutex;
+use time::now;
+
+struct DefaultLogger {
+    level: LogLevelFilter,
+    /// log file; stderr when `None`
+    file: Option<Mutex<fs::File>>,
+}
+
+impl log::Log for DefaultLogger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if self.enabled(record.metadata()) {
+            let timestamp = now().strftime("%Y-%m-%d %H:%M:%S").map(|t| format!("{}", t))
+                                 .unwrap_or(String::new());
+            let line = format!("{} {:<5} {}: {}\n", timestamp, record.level(), record.target(), record.args());
+            let _ = match self.file {
+                Some(ref file) => file.lock().expect("BUG: log file lock poisoned").write_all(line.as_bytes()),
+                None => io::stderr().write_all(line.as_bytes()),
+            };
+        }
+    }
+}
+
+/// Log all messages of level info and above to stderr
+pub fn init_stderr() -> Result<(), SetLoggerError> {
+    log::set_logger(|max_log_level| {
+        max_log_level.set(LogLevelFilter::Info);
+        Box::new(DefaultLogger { level: LogLevelFilter::Info, file: None })
+    })
+}
+
+/// Open (or create) a log file for `init_file`; opened separately, so errors can be reported
+/// before detaching from the terminal
+pub fn open_file(path: &Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new().append(true).create(true).open(path)
+}
+
+/// Log all messages of level info and above to the given file
+pub fn init_file(file: fs::File) -> Result<(), SetLoggerError> {
+    log::set_logger(|max_log_level| {
+        max_log_level.set(LogLevelFilter::Info);
+        Box::new(DefaultLogger { level: LogLevelFilter::Info, file: Some(Mutex::new(file)) })
+    })
+}
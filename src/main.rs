@@ -11,19 +11,42 @@ extern crate zoneinfo;
 extern crate iron;
 extern crate router;
 extern crate rustc_serialize;
-extern crate staticfile;
 extern crate mount;
+extern crate libc;
+extern crate hyper;
 
+mod assets;
+mod clock;
 mod config;
+mod control;
+mod ctl;
+mod daemon;
+mod driver;
+mod editor;
+mod logging;
+mod options;
 mod serial;
+mod signals;
+mod simulate;
+mod systemd;
 mod tracker;
 mod ticker;
 mod web;
 
 use tracker::Tracker;
+use control::Control;
+use options::Options;
+use signals::Signals;
 use std::env;
 use std::default::Default;
+use std::io::prelude::*;
+use std::io;
+use std::mem;
+use std::fs;
+use std::net::TcpListener;
+use std::os::unix::io::FromRawFd;
 use std::path::PathBuf;
+use std::process;
 
 const USER_CONFIG: &'static str = ".plugwise.toml";
 const USER_LOGCONFIG: &'static str = ".keeper.log.toml";
@@ -33,6 +56,9 @@ const SYSTEM_CONFIG: &'static str = "/etc/keeper/plugwise.toml";
 const SYSTEM_LOGCONFIG: &'static str = "/etc/keeper/logging.toml";
 const SYSTEM_WEB: &'static str = "/etc/keeper/web";
 
+const SOCKET_NAME: &'static str = "keeper.sock";
+const LOG_NAME: &'static str = "keeper.log";
+
 fn get_config_file(local_config: &str, system_config: &str) -> Option<PathBuf> {
     if let Some(mut homedir) = env::home_dir() {
         homedir.push(local_config);
@@ -59,16 +85,132 @@ fn get_config_file(local_config: &str, system_config: &str) -> Option<PathBuf> {
     None
 }
 
+fn exit_with_error(message: String) -> ! {
+    let _ = writeln!(io::stderr(), "keeper: {}", message);
+    process::exit(1);
+}
+
 fn main() {
-    let logging_config_file = get_config_file(USER_LOGCONFIG, SYSTEM_LOGCONFIG).expect("BUG: unable to find home/user directory for logging");
-    let plugwise_config_file = get_config_file(USER_CONFIG, SYSTEM_CONFIG).expect("BUG: unable to find home/user directory for plugwise configuration");
-    let webresources = get_config_file(USER_WEB, SYSTEM_WEB).expect("BUG: unable to find web resources");
+    let args: Vec<String> = env::args().collect();
+
+    if args.get(1).map_or(false, |arg| arg == "ctl") {
+        process::exit(ctl::run(&args[2..]));
+    }
+
+    let default_config = || Options::env_path("KEEPER_CONFIG").or_else(|| get_config_file(USER_CONFIG, SYSTEM_CONFIG));
+
+    if args.get(1).map_or(false, |arg| arg == "simulate") {
+        process::exit(simulate::run(&args[2..], default_config()));
+    }
+
+    if args.get(1).map_or(false, |arg| arg == "preview") {
+        process::exit(simulate::preview(&args[2..], default_config()));
+    }
+
+    let options = Options::parse(&args[1..]).unwrap_or_else(|err| exit_with_error(err));
 
-    log4rs::init_file(logging_config_file, Default::default()).unwrap();
+    if options.help {
+        println!("{}", options::USAGE);
+        return;
+    }
 
-    let tracker = Tracker::spawn(plugwise_config_file);
+    let state_dir = options.state_dir;
+    let plugwise_config_file = options.config.or_else(|| get_config_file(USER_CONFIG, SYSTEM_CONFIG))
+        .unwrap_or_else(|| exit_with_error(format!(
+            "unable to find plugwise configuration ~/{} or {}; use --config", USER_CONFIG, SYSTEM_CONFIG)));
+    let logging_config_file = options.log_config.or_else(|| get_config_file(USER_LOGCONFIG, SYSTEM_LOGCONFIG));
+    let webresources = options.web_root.or_else(|| get_config_file(USER_WEB, SYSTEM_WEB));
+
+    let config = config::Config::new(&plugwise_config_file).unwrap_or_else(|err|
+        exit_with_error(format!("unable to load {}: {}", plugwise_config_file.display(), err)));
+
+    if let Some(ref state_dir) = state_dir {
+        if let Err(err) = fs::create_dir_all(state_dir) {
+            exit_with_error(format!("unable to create state directory {}: {}", state_dir.display(), err));
+        }
+    }
+
+    let detach = options.detach && !systemd::supervised();
+
+    // without logging configuration a detached daemon logs to a file in the state directory
+    let log_file = if detach && logging_config_file.is_none() {
+        let path = state_dir.as_ref().map(|state_dir| state_dir.join(LOG_NAME)).unwrap_or_else(||
+            exit_with_error("no logging configuration found; use --log-config or --state-dir to detach".into()));
+        Some(logging::open_file(&path).unwrap_or_else(|err|
+            exit_with_error(format!("unable to open {}: {}", path.display(), err))))
+    } else {
+        None
+    };
+
+    if detach {
+        if let Err(err) = daemon::daemonize() {
+            exit_with_error(format!("unable to detach from terminal: {}", err));
+        }
+    }
+
+    // handle termination signals in the main thread; all other threads inherit the blocked signals
+    let signals = Signals::block(&[libc::SIGTERM, libc::SIGINT]);
+
+    match (logging_config_file, log_file) {
+        (Some(logging_config_file), _) => {
+            if let Err(err) = log4rs::init_file(&logging_config_file, Default::default()) {
+                exit_with_error(format!("unable to load {}: {}", logging_config_file.display(), err));
+            }
+        },
+        (None, Some(log_file)) => logging::init_file(log_file).expect("BUG: unable to initialize logging"),
+        (None, None) => logging::init_stderr().expect("BUG: unable to initialize logging"),
+    }
+
+    if options.detach && !detach {
+        warn!("started by systemd; not detaching from the terminal");
+    }
+
+    let socket = config.device.socket.as_ref().map(PathBuf::from).or_else(||
+        state_dir.as_ref().map(|state_dir| state_dir.join(SOCKET_NAME)));
+
+    let clock = clock::from_config(&config.device);
+    let tracker = Tracker::spawn(plugwise_config_file, config, clock);
+
+    let control = socket.as_ref().and_then(|socket| match Control::spawn(tracker.get_client(), socket) {
+        Ok(control) => Some(control),
+        Err(err) => {
+            error!("unable to create control socket {}: {}", socket.display(), err);
+            None
+        }
+    });
+
+    match webresources {
+        Some(ref webresources) => info!("serving web resources of {}", webresources.display()),
+        None => info!("no web resources ~/{} or {} found; serving the embedded user interface", USER_WEB, SYSTEM_WEB),
+    }
+
+    let listener = systemd::listen_fds().into_iter().next().map(|fd| unsafe {
+        TcpListener::from_raw_fd(fd)
+    });
 
     let mut web = web::Web::new();
-    web.serve(tracker.get_client(), &webresources);
+    let mut listening = web.serve(tracker.get_client(), webresources.as_ref().map(|p| p.as_path()), listener)
+                       .unwrap_or_else(|err| exit_with_error(format!("unable to start web server: {}", err)));
+
+    let signal = signals.wait();
+    info!("received signal {}, shutting down", signal);
+    systemd::notify("STOPPING=1");
+
+    // closing the listener does not stop the threads handling the requests, so the web server
+    // and the control socket refuse requests from now on instead
+    web.stop();
+    if let Some(ref control) = control {
+        control.stop();
+    }
+    if let Err(err) = listening.close() {
+        warn!("unable to stop web server: {}", err);
+    }
     tracker.teardown();
+
+    if let Some(socket) = socket {
+        let _ = fs::remove_file(socket);
+    }
+
+    // the web server threads cannot be joined, since they may still wait for new connections
+    mem::forget(listening);
 }
@@ -0,0 +1,92 @@
+// This module parses the command line options of the daemon
+
+use std::env;
+use std::path::PathBuf;
+
+pub const USAGE: &'static str = "usage: keeper [OPTIONS]
+       keeper ctl [--help]
+       keeper simulate [--help]
+       keeper preview [--help]
+
+options:
+    --config FILE       plugwise configuration           (KEEPER_CONFIG)
+    --log-config FILE   log4rs logging configuration     (KEEPER_LOG_CONFIG)
+    --web-root DIR      web user interface resources     (KEEPER_WEB_ROOT)
+    --state-dir DIR     directory for runtime state      (KEEPER_STATE_DIR)
+    --foreground        stay in the foreground (default) (KEEPER_FOREGROUND=1)
+    --detach            detach from the terminal         (KEEPER_DETACH=1)
+    --help              show this help
+
+Files that are not given are searched for in the home directory, the current
+directory and /etc/keeper. Without logging configuration keeper logs to stderr,
+or to keeper.log in the state directory when detached.";
+
+#[derive(Debug, Default)]
+pub struct Options {
+    pub config: Option<PathBuf>,
+    pub log_config: Option<PathBuf>,
+    pub web_root: Option<PathBuf>,
+    pub state_dir: Option<PathBuf>,
+    pub detach: bool,
+    pub help: bool,
+}
+
+/// Make a path absolute, since the daemon changes its working directory when detaching
+fn absolute(path: PathBuf) -> PathBuf {
+    env::current_dir().map(|dir| dir.join(&path)).unwrap_or(path)
+}
+
+impl Options {
+    /// Absolute path given by a environment variable; `None` when unset or empty
+    pub fn env_path(name: &str) -> Option<PathBuf> {
+        env::var_os(name).and_then(|value| {
+            if value.is_empty() {
+                None
+            } else {
+                Some(absolute(PathBuf::from(value)))
+            }
+        })
+    }
+
+    fn env_flag(name: &str) -> bool {
+        env::var(name).map(|value| !value.is_empty() && value != "0").unwrap_or(false)
+    }
+
+    /// Parse the command line arguments (without the program name); options that are not given
+    /// are taken from the environment.
+    pub fn parse(args: &[String]) -> Result<Options, String> {
+        let mut options = Options {
+            config: Options::env_path("KEEPER_CONFIG"),
+            log_config: Options::env_path("KEEPER_LOG_CONFIG"),
+            web_root: Options::env_path("KEEPER_WEB_ROOT"),
+            state_dir: Options::env_path("KEEPER_STATE_DIR"),
+            detach: Options::env_flag("KEEPER_DETACH") && !Options::env_flag("KEEPER_FOREGROUND"),
+            help: false,
+        };
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            // both "--option value" and "--option=value" are accepted
+            let (name, inline) = match arg.find('=') {
+                Some(pos) if arg.starts_with("--") => (&arg[..pos], Some(arg[pos + 1..].to_string())),
+                _ => (&arg[..], None),
+            };
+            let mut value = || inline.clone().or_else(|| args.next().cloned())
+                                     .map(|value| absolute(PathBuf::from(value)))
+                                     .ok_or(format!("option {} requires a value", name));
+
+            match name {
+                "--config" => options.config = Some(try!(value())),
+                "--log-config" => options.log_config = Some(try!(value())),
+                "--web-root" => options.web_root = Some(try!(value())),
+                "--state-dir" => options.state_dir = Some(try!(value())),
+                "--foreground" | "-f" => options.detach = false,
+                "--detach" | "-d" => options.detach = true,
+                "--help" | "-h" => options.help = true,
+                _ => return Err(format!("unknown option '{}'", arg)),
+            }
+        }
+
+        Ok(options)
+    }
+}
@@ -4,6 +4,7 @@ use std::thread;
 use std::collections;
 use std::error::Error;
 use std::fmt;
+use time::{Duration, precise_time_ns};
 
 use plugwise;
 
@@ -30,13 +31,27 @@ impl fmt::Display for SerialError {
     }
 }
 
+/// Result of probing a circle
+#[derive(Debug, Clone)]
+pub struct Probe {
+    pub mac: u64,
+    pub reachable: bool,
+    /// state of the relay (when reachable)
+    pub relay: Option<bool>,
+    /// response time of the circle; the stick does not report the link quality, so a slow
+    /// response is the best indication of a weak connection
+    pub latency: Option<Duration>,
+    pub error: Option<String>,
+}
+
 enum Command {
     ConnectDevice(Option<Sender<ConnectResponse>>, String),
     ConnectStub,
-    Hangup,
+    Hangup(Sender<()>),
     RegisterCircle(String, u64),
     SwitchOn(String),
     SwitchOff(String),
+    Probe(Vec<u64>, Sender<Vec<Probe>>),
 }
 
 enum ConnectResponse {
@@ -80,7 +95,11 @@ impl Serial {
                                     "creating a simulation instance unexpectedly failed!");
                     plugwise = Some(new_plugwise);
                 },
-                Command::Hangup => break,
+                Command::Hangup(tx) => {
+                    // all prior commands are handled; the channel is processed in order
+                    let _ = tx.send(());
+                    break;
+                },
                 Command::RegisterCircle(alias, mac) => {
                     if let Some(ref plugwise) = plugwise {
                         let circle = plugwise.create_circle(mac);
@@ -105,10 +124,46 @@ impl Serial {
                         }
                     }
                 },
+                Command::Probe(macs, tx) => {
+                    let probes = macs.into_iter().map(|mac| Serial::probe(&plugwise, mac)).collect();
+                    let _ = tx.send(probes);
+                },
             }
         }
     }
 
+    fn probe(plugwise: &Option<Box<plugwise::Plugwise>>, mac: u64) -> Probe {
+        let mut probe = Probe {
+            mac: mac,
+            reachable: false,
+            relay: None,
+            latency: None,
+            error: None,
+        };
+
+        let result = match *plugwise {
+            Some(ref plugwise) => {
+                let start = precise_time_ns();
+                plugwise.create_circle(mac)
+                        .and_then(|circle| circle.is_switched_on())
+                        .map(|relay| (relay, Duration::nanoseconds((precise_time_ns() - start) as i64)))
+                        .map_err(|err| err.description().into())
+            },
+            None => Err(String::from("stick not connected")),
+        };
+
+        match result {
+            Ok((relay, latency)) => {
+                probe.reachable = true;
+                probe.relay = Some(relay);
+                probe.latency = Some(latency);
+            },
+            Err(err) => probe.error = Some(err),
+        }
+
+        probe
+    }
+
     pub fn spawn() -> SerialClient {
         let (boot_tx, boot_rx) = channel();
 
@@ -155,9 +210,13 @@ impl SerialClient {
         }
     }
 
+    /// Stop the serial thread after all pending commands are handled
     pub fn hangup(&self) {
-        self.tx.send(Command::Hangup)
+        let (tx, rx) = channel();
+
+        self.tx.send(Command::Hangup(tx))
                .expect("BUG: cannot bring serial thread down");
+        rx.recv().expect("BUG: serial thread did not acknowledge hangup");
     }
 
     pub fn register_circle(&self, alias: &str, mac: u64) {
@@ -170,6 +229,16 @@ impl SerialClient {
                .expect("BUG: unable to request to switch circle on");
     }
 
+    /// Check which of the given circles respond; blocks until all circles are probed. Fails
+    /// when the serial thread is stopped (by a reload) meanwhile, since the probe runs outside
+    /// the tracker thread.
+    pub fn probe(&self, macs: Vec<u64>) -> Result<Vec<Probe>, String> {
+        let (tx, rx) = channel();
+
+        try!(self.tx.send(Command::Probe(macs, tx)).map_err(|_| String::from("stick disconnected")));
+        rx.recv().map_err(|_| String::from("stick disconnected while probing"))
+    }
+
     pub fn switch_off(&self, alias: &str) {
         self.tx.send(Command::SwitchOff(alias.into()))
                .expect("BUG: unable to request to switch circle off");
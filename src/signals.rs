@@ -0,0 +1,43 @@
+// This module handles the termination signals of the daemon
+
+use libc;
+use std::mem;
+
+/// A set of signals that are handled synchronously by `wait`
+pub struct Signals {
+    set: libc::sigset_t,
+}
+
+impl Signals {
+    /// Block the given signals for the current thread and all threads spawned afterwards, so
+    /// they can be received with `wait`. Must be called before any thread is spawned.
+    pub fn block(signals: &[libc::c_int]) -> Signals {
+        unsafe {
+            let mut set: libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut set);
+            for signal in signals {
+                libc::sigaddset(&mut set, *signal);
+            }
+            if libc::pthread_sigmask(libc::SIG_BLOCK, &set, 0 as *mut libc::sigset_t) != 0 {
+                panic!("BUG: unable to block signals");
+            }
+
+            Signals {
+                set: set
+            }
+        }
+    }
+
+    /// Wait until one of the signals is received and return it
+    pub fn wait(&self) -> libc::c_int {
+        let mut signal = 0;
+
+        unsafe {
+            if libc::sigwait(&self.set, &mut signal) != 0 {
+                panic!("BUG: unable to wait for signals");
+            }
+        }
+
+        signal
+    }
+}
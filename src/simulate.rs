@@ -0,0 +1,205 @@
+// This module implements `keeper simulate`, which runs the schedule of a configuration through
+// virtual time against the plugwise simulator and prints the toggles, every switch action and
+// the events that were skipped or merged, and `keeper preview`, which prints the same schedule
+// per switch in the time zone of its circle
+
+use clock::{SimulatedClock, zone_time};
+use config::Config;
+use std::collections::BTreeMap;
+use std::io;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use time::{Duration, Timespec, at, get_time, strptime};
+use tracker;
+use zoneinfo::ZoneInfo;
+
+const DEFAULT_DAYS: i64 = 2;
+/// Faster than this, a tick takes less real time than the tracker needs to process it
+const MAX_SPEED: u32 = 1000000;
+
+const USAGE: &'static str = "usage: keeper simulate [--config FILE] [--speed 3600x|max] [--days N] [--start YYYY-MM-DD]
+
+Runs the schedule of the configuration from midnight of the start day (default today) for the
+given number of days (default 2) and prints the toggles of every switch (with the template
+they come from), every switch action and the events that were skipped or merged. With --speed
+the virtual time runs the given factor (at most 1000000x) faster than real time; by default
+the simulation runs as fast as possible.";
+
+const PREVIEW_USAGE: &'static str = "usage: keeper preview [--config FILE] [--days N] [--start YYYY-MM-DD]
+
+Prints the schedule of every switch from midnight of the start day (default today) for the
+given number of days (default 2): the moments its level changes and the events that were
+skipped or merged (and why), in the time zone of the circle.";
+
+/// Parse a speed like "3600x" (or "3600"); "max" is returned as 0
+fn parse_speed(speed: &str) -> Option<u32> {
+    match speed {
+        "max" => Some(0),
+        _ => speed.trim_right_matches('x').parse().ok().and_then(|speed| match speed {
+            1...MAX_SPEED => Some(speed),
+            _ => None,
+        }),
+    }
+}
+
+/// Local midnight of the given date
+fn parse_start(date: &str) -> Option<Timespec> {
+    strptime(date, "%Y-%m-%d").ok().map(|tm| {
+        let ts = tm.to_timespec();
+        ts - Duration::seconds(at(ts).tm_utcoff as i64)
+    })
+}
+
+fn today() -> Timespec {
+    let mut tm = at(get_time());
+    tm.tm_hour = 0;
+    tm.tm_min = 0;
+    tm.tm_sec = 0;
+    tm.tm_nsec = 0;
+    tm.to_timespec()
+}
+
+/// The level changes and diagnostics of every switch, in the time zone of its circle
+fn print_preview(config: Config, start: Timespec, end: Timespec) {
+    let zones: BTreeMap<_, _> = config.circles.iter().map(|circle| {
+        let zoneinfo = circle.site.timezone.as_ref().and_then(|tz| ZoneInfo::by_tz(tz).ok()).unwrap_or_else(||
+            ZoneInfo::get_local_zoneinfo().expect("BUG: not able to load local zoneinfo"));
+        let name = circle.site.timezone.clone().unwrap_or(String::from("local time"));
+        (circle.alias.clone(), (name, zoneinfo))
+    }).collect();
+    let mut lines = BTreeMap::new();
+
+    let mut clock = SimulatedClock::new(start, 0);
+    let diagnostics = tracker::simulate(config, &mut clock, end, |action| {
+        lines.entry(action.switch.clone()).or_insert(vec![]).push((action.timestamp, format!("{}", action.state)));
+    });
+    for diagnostic in diagnostics.iter().filter(|d| d.timestamp >= start && d.timestamp < end) {
+        lines.entry(diagnostic.switch.clone()).or_insert(vec![])
+             .push((diagnostic.timestamp, format!("{}: {}", diagnostic.toggle, diagnostic.reason)));
+    }
+
+    for (switch, lines) in &mut lines {
+        let &(ref name, ref zoneinfo) = &zones[switch];
+        // the sort is stable, so the level change comes before the diagnostics of the moment
+        lines.sort_by(|a, b| a.0.cmp(&b.0));
+
+        println!("{} ({})", switch, name);
+        for &(ts, ref line) in lines.iter() {
+            println!("  {}  {}", zone_time(zoneinfo, ts).strftime("%a %Y-%m-%d %H:%M").unwrap(), line);
+        }
+        println!("");
+    }
+}
+
+fn execute(args: &[String], default_config: Option<PathBuf>, preview: bool) -> Result<(), String> {
+    let help = if preview { PREVIEW_USAGE } else { USAGE };
+    let usage = |message: String| format!("{}\n\n{}", message, help);
+    let mut config = default_config;
+    let mut speed = 0;
+    let mut days = DEFAULT_DAYS;
+    let mut start = today();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().ok_or(usage(format!("{} requires a value", arg)));
+
+        match &arg[..] {
+            "--config" => config = Some(PathBuf::from(try!(value()))),
+            "--speed" if !preview => {
+                let value = try!(value());
+                speed = try!(parse_speed(&value).ok_or(usage(format!("invalid speed '{}'", value))));
+            },
+            "--days" => {
+                let value = try!(value());
+                days = try!(value.parse().ok().and_then(|days| if days > 0 { Some(days) } else { None })
+                                 .ok_or(usage(format!("invalid number of days '{}'", value))));
+            },
+            "--start" => {
+                let value = try!(value());
+                start = try!(parse_start(&value).ok_or(usage(format!("invalid date '{}'", value))));
+            },
+            "-h" | "--help" => return Err(help.into()),
+            _ => return Err(usage(format!("unknown option '{}'", arg))),
+        }
+    }
+
+    let config_file = try!(config.ok_or(usage("unable to find plugwise configuration; use --config or KEEPER_CONFIG".into())));
+    let config = try!(Config::new(&config_file).map_err(|err|
+        format!("unable to load {}: {}", config_file.display(), err)));
+
+    if preview {
+        print_preview(config, start, start + Duration::days(days));
+        return Ok(());
+    }
+
+    // the toggles of every switch, with their condition and the template they come from
+    for circle in config.circles.iter().filter(|circle| !circle.toggles.is_empty()) {
+        for toggle in &circle.toggles {
+            let condition = if toggle.condition.is_always() { String::new() } else { format!("  {}", toggle.condition) };
+            let template = toggle.template.as_ref().map_or(String::new(), |name| format!("  (template '{}')", name));
+            println!("{:<24} {:<16} {} - {}{}{}", circle.alias, toggle.alias, toggle.start, toggle.end, condition, template);
+        }
+    }
+    println!("");
+
+    let mut clock = SimulatedClock::new(start, speed);
+    let mut actions = 0;
+
+    let diagnostics = tracker::simulate(config, &mut clock, start + Duration::days(days), |action| {
+        actions += 1;
+        println!("{}  {:<24} {}",
+                 at(action.timestamp).strftime("%a %Y-%m-%d %H:%M:%S").unwrap(),
+                 action.switch,
+                 action.state);
+    });
+
+    println!("{} switch actions in {} days", actions, days);
+
+    // events that were skipped or merged while the schedule was built
+    let end = start + Duration::days(days);
+    for diagnostic in diagnostics.iter().filter(|d| d.timestamp >= start && d.timestamp < end) {
+        println!("{}  {:<24} {}: {}",
+                 at(diagnostic.timestamp).strftime("%a %Y-%m-%d %H:%M:%S").unwrap(),
+                 diagnostic.switch,
+                 diagnostic.toggle,
+                 diagnostic.reason);
+    }
+    Ok(())
+}
+
+fn exit_code(result: Result<(), String>) -> i32 {
+    match result {
+        Ok(()) => 0,
+        Err(err) => {
+            let _ = writeln!(io::stderr(), "{}", err);
+            1
+        }
+    }
+}
+
+/// Run `keeper simulate` with the arguments following `simulate`; returns the exit code.
+pub fn run(args: &[String], default_config: Option<PathBuf>) -> i32 {
+    exit_code(execute(args, default_config, false))
+}
+
+/// Run `keeper preview` with the arguments following `preview`; returns the exit code.
+pub fn preview(args: &[String], default_config: Option<PathBuf>) -> i32 {
+    exit_code(execute(args, default_config, true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_speed;
+
+    #[test]
+    fn speeds() {
+        assert_eq!(parse_speed("max"), Some(0));
+        assert_eq!(parse_speed("3600x"), Some(3600));
+        assert_eq!(parse_speed("60"), Some(60));
+        assert_eq!(parse_speed("1000000x"), Some(1000000));
+        assert_eq!(parse_speed("0x"), None);
+        assert_eq!(parse_speed("1000001x"), None);
+        assert_eq!(parse_speed("4294967296x"), None);
+        assert_eq!(parse_speed("fast"), None);
+    }
+}
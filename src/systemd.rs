@@ -0,0 +1,119 @@
+// This module implements the parts of the systemd service protocol used by keeper: readiness
+// and watchdog notifications (sd_notify) and socket activation (sd_listen_fds)
+
+use libc;
+use std::env;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use time::{Duration, precise_time_ns};
+
+/// First file descriptor passed by systemd
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Send a notification (like "READY=1") to systemd; ignored when keeper is not started by
+/// systemd as a notify service.
+pub fn notify(state: &str) {
+    if let Some(socket) = env::var_os("NOTIFY_SOCKET") {
+        if let Err(err) = send_notification(&socket.to_string_lossy(), state) {
+            warn!("unable to notify systemd: {}", err);
+        }
+    }
+}
+
+fn send_notification(socket: &str, state: &str) -> io::Result<()> {
+    let path = socket.as_bytes();
+
+    unsafe {
+        let mut addr: libc::sockaddr_un = mem::zeroed();
+
+        if path.is_empty() || path.len() >= addr.sun_path.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid NOTIFY_SOCKET"));
+        }
+
+        addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+        for (dst, src) in addr.sun_path.iter_mut().zip(path) {
+            *dst = *src as libc::c_char;
+        }
+        if path[0] == b'@' {
+            // abstract socket address
+            addr.sun_path[0] = 0;
+        }
+
+        let fd = libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let addr_len = mem::size_of::<libc::sa_family_t>() + path.len();
+        let sent = libc::sendto(fd,
+                                state.as_ptr() as *const libc::c_void,
+                                state.len(),
+                                libc::MSG_NOSIGNAL,
+                                &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+                                addr_len as libc::socklen_t);
+        let result = if sent == -1 { Err(io::Error::last_os_error()) } else { Ok(()) };
+
+        libc::close(fd);
+        result
+    }
+}
+
+/// Whether keeper is started by systemd as a notify service or with socket activation; the
+/// process must not fork then, since systemd addresses it by its process id
+pub fn supervised() -> bool {
+    env::var_os("NOTIFY_SOCKET").is_some() || env::var_os("LISTEN_PID").is_some()
+}
+
+/// Check whether a environment variable set by systemd is meant for this process
+fn for_this_process(pid_variable: &str) -> bool {
+    env::var(pid_variable).ok()
+                          .and_then(|pid| pid.parse::<libc::pid_t>().ok())
+                          .map_or(false, |pid| pid == unsafe { libc::getpid() })
+}
+
+/// File descriptors of the sockets passed by systemd socket activation
+pub fn listen_fds() -> Vec<RawFd> {
+    if !for_this_process("LISTEN_PID") {
+        return vec![];
+    }
+
+    let count = env::var("LISTEN_FDS").ok()
+                                      .and_then(|fds| fds.parse::<RawFd>().ok())
+                                      .unwrap_or(0);
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count).collect()
+}
+
+/// Sends keep-alive pings to the systemd watchdog
+pub struct Watchdog {
+    interval: Duration,
+    last_ping: u64,
+}
+
+impl Watchdog {
+    /// Returns a watchdog when systemd expects keep-alive pings
+    pub fn new() -> Option<Watchdog> {
+        if env::var_os("WATCHDOG_PID").is_some() && !for_this_process("WATCHDOG_PID") {
+            return None;
+        }
+
+        env::var("WATCHDOG_USEC").ok()
+                                 .and_then(|usec| usec.parse::<i64>().ok())
+                                 .map(|usec| Watchdog {
+                                     interval: Duration::microseconds(usec),
+                                     last_ping: 0,
+                                 })
+    }
+
+    /// Ping the watchdog; pings are limited to twice per watchdog interval
+    pub fn ping(&mut self) {
+        let now = precise_time_ns();
+        let elapsed = Duration::nanoseconds((now - self.last_ping) as i64);
+
+        if self.last_ping == 0 || elapsed * 2 >= self.interval {
+            self.last_ping = now;
+            notify("WATCHDOG=1");
+        }
+    }
+}
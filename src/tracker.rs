@@ -1,179 +1,1165 @@
 use dailyschedule::{Handler, Schedule};
 use std::cell::{Cell, RefCell};
-use std::collections::BTreeMap;
+use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::fs;
+use std::mem;
+use std::io::prelude::*;
 use std::path;
 use std::rc::Rc;
+use std::time as stdtime;
 use super::config;
 use super::serial;
+use editor::ConfigEditor;
+use driver::{SwitchDriver, PlugwiseDriver, ExternalDriver, NullDriver};
 use time::{Duration, Timespec, at_utc, at};
 use zoneinfo::ZoneInfo;
+use clock::{Clock, SimulatedClock, TimeStatus, zone_time};
 use ticker::Ticker;
+use systemd;
+use toml;
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{channel, Sender};
 use std::thread;
 
+/// Number of switch actions kept by the daemon (the history shown by the web user interface)
+const JOURNAL_SIZE: usize = 1000;
+
+/// Interval between two ticks of the clock
+const TICK_SECONDS: i64 = 10;
+
+/// Interval between two attempts to connect to the stick
+const CONNECT_RETRY_SECONDS: i64 = 60;
+
+/// Deviation of the expected time between two ticks, beyond which the clock is considered to
+/// have jumped (e.g. a NTP correction or a suspend)
+const JUMP_THRESHOLD_SECONDS: i64 = 5 * 60;
+
+/// Highest output level
+const MAX_LEVEL: u8 = 100;
+
+/// Number of backups of the configuration file that are kept when it is changed
+const CONFIG_BACKUPS: usize = 10;
+
+/// Output level of a switch in percent; binary switches are on at any level above 0
+#[derive(Eq, PartialEq, Ord, PartialOrd, Debug, Copy, Clone)]
+pub struct Level(u8);
+
+impl Level {
+    pub fn off() -> Level {
+        Level(0)
+    }
+
+    pub fn on() -> Level {
+        Level(MAX_LEVEL)
+    }
+
+    /// `None` when the percentage exceeds 100
+    pub fn new(percent: u8) -> Option<Level> {
+        if percent <= MAX_LEVEL { Some(Level(percent)) } else { None }
+    }
+
+    /// Parse "on", "off" or a percentage ("50" or "50%")
+    pub fn parse(level: &str) -> Option<Level> {
+        match level {
+            "on" => Some(Level::on()),
+            "off" => Some(Level::off()),
+            _ => level.trim_right_matches('%').parse().ok().and_then(Level::new),
+        }
+    }
+
+    pub fn percent(&self) -> u8 {
+        self.0
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.0 > 0
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            0 => write!(f, "off"),
+            MAX_LEVEL => write!(f, "on"),
+            percent => write!(f, "{}%", percent),
+        }
+    }
+}
+
+/// Schedule event of one of the toggles of a switch: its start or its end
 #[derive(Eq, PartialEq, Debug, Copy, Clone)]
-pub enum Context {
-    Off,
-    On
+struct Edge {
+    toggle: usize,
+    start: bool,
+}
+
+/// Level, fades and condition of a toggle
+#[derive(Debug, Clone)]
+struct Shape {
+    name: String,
+    level: Level,
+    /// the level rises from 0 during this period before the start event
+    fade_in: Duration,
+    /// the level falls to 0 during this period after the end event
+    fade_out: Duration,
+    /// days on which the toggle is used
+    condition: config::Condition,
 }
 
+impl Shape {
+    fn new(toggle: &config::Toggle) -> Shape {
+        Shape {
+            name: toggle.alias.clone(),
+            level: Level::new(toggle.level).expect("BUG: toggle level exceeds 100%"),
+            fade_in: Duration::minutes(toggle.fade_in as i64),
+            fade_out: Duration::minutes(toggle.fade_out as i64),
+            condition: toggle.condition,
+        }
+    }
+}
+
+/// On-period of a toggle: the level rises from `start` until `full`, holds until `end` and falls
+/// until `stop`
+#[derive(Debug, Copy, Clone)]
+struct Period {
+    start: Timespec,
+    full: Timespec,
+    end: Timespec,
+    stop: Timespec,
+    level: Level,
+    /// index of the toggle
+    toggle: usize,
+}
+
+impl Period {
+    fn level_at(&self, now: Timespec) -> Level {
+        let fraction = |part: Duration, whole: Duration| {
+            Level((self.level.0 as i64 * part.num_milliseconds() / whole.num_milliseconds()) as u8)
+        };
+
+        if now < self.start || now >= self.stop {
+            Level::off()
+        } else if now < self.full {
+            fraction(now - self.start, self.full - self.start)
+        } else if now < self.end {
+            self.level
+        } else {
+            fraction(self.stop - now, self.stop - self.end)
+        }
+    }
+}
+
+/// Status of a switch as reported to the clients of the tracker
+#[derive(Clone, Debug)]
+pub struct SwitchInfo {
+    pub state: Level,
+    /// state the schedule requires at this moment
+    pub scheduled_state: Level,
+    /// upcoming changes of the scheduled level; a fade runs from one event to the next
+    pub next_events: BTreeMap<Timespec, Level>,
+    /// whether the switch supports levels between off and on
+    pub dimmable: bool,
+    /// skipped and merged events of the toggles of the switch
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Switch action performed by the tracker
+#[derive(Clone, Debug)]
+pub struct Action {
+    pub timestamp: Timespec,
+    pub switch: String,
+    pub state: Level,
+}
+
+/// Event of a toggle that is skipped or merged with another toggle, and why
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    /// moment of the event
+    pub timestamp: Timespec,
+    pub switch: String,
+    pub toggle: String,
+    pub reason: String,
+}
+
+/// Record of the switch actions and the diagnostics of all switches
+struct Journal {
+    /// timestamp of the tick being processed
+    now: Cell<Timespec>,
+    actions: RefCell<VecDeque<Action>>,
+    diagnostics: RefCell<VecDeque<Diagnostic>>,
+    /// maximum number of actions and of diagnostics kept (unlimited when `None`)
+    limit: Option<usize>,
+}
+
+impl Journal {
+    fn new(limit: Option<usize>) -> Journal {
+        Journal {
+            now: Cell::new(Timespec::new(0, 0)),
+            actions: RefCell::new(VecDeque::new()),
+            diagnostics: RefCell::new(VecDeque::new()),
+            limit: limit,
+        }
+    }
+
+    fn record(&self, switch: &str, state: Level) {
+        let mut actions = self.actions.borrow_mut();
+
+        actions.push_back(Action {
+            timestamp: self.now.get(),
+            switch: switch.into(),
+            state: state,
+        });
+
+        if let Some(limit) = self.limit {
+            while actions.len() > limit {
+                actions.pop_front();
+            }
+        }
+    }
+
+    fn drain(&self) -> Vec<Action> {
+        self.actions.borrow_mut().drain(..).collect()
+    }
+
+    /// Recorded actions of a switch, oldest first
+    fn history(&self, switch: &str) -> Vec<Action> {
+        self.actions.borrow().iter().filter(|action| action.switch == switch).cloned().collect()
+    }
+
+    fn diagnose(&self, diagnostic: Diagnostic) {
+        info!("{}: toggle '{}' at {}: {}", diagnostic.switch, diagnostic.toggle,
+              at(diagnostic.timestamp).asctime(), diagnostic.reason);
+
+        let mut diagnostics = self.diagnostics.borrow_mut();
+        diagnostics.push_back(diagnostic);
+        if let Some(limit) = self.limit {
+            while diagnostics.len() > limit {
+                diagnostics.pop_front();
+            }
+        }
+    }
+
+    /// Diagnostics of a switch (all switches when `None`), in the order the schedule was built
+    fn diagnostics(&self, switch: Option<&str>) -> Vec<Diagnostic> {
+        self.diagnostics.borrow().iter()
+                        .filter(|diagnostic| switch.map_or(true, |switch| diagnostic.switch == switch))
+                        .cloned()
+                        .collect()
+    }
+}
+
+/// A switch can hold multiple toggles; at every moment the switch takes the highest level of the
+/// on-periods of all toggles. Overlapping and adjacent on-periods of the same level therefore
+/// result in a single on and a single off event.
 struct Switch {
-    serial: serial::SerialClient,
+    driver: Box<SwitchDriver>,
+    journal: Rc<Journal>,
     alias: String,
-    last_on: Cell<Timespec>,
-    state: Cell<Context>,
-    valid_events: RefCell<BTreeMap<Timespec, Context>>,
+    shutdown: config::ShutdownPolicy,
+    /// state of a switch that is not controlled by a schedule
+    fixed: Option<Level>,
+    /// level, fades and condition of every toggle
+    shapes: Vec<Shape>,
+    /// position of the switch, for the conditions of its toggles
+    site: config::Site,
+    /// time zone of the site, for the diagnostics
+    zoneinfo: ZoneInfo,
+    /// start of the on-period of each toggle, waiting for its off event
+    pending_on: RefCell<BTreeMap<usize, Timespec>>,
+    state: Cell<Level>,
+    /// state requested by the schedule (or the default of the circle)
+    scheduled: Cell<Level>,
+    /// end of a temporary manual override
+    override_until: Cell<Option<Timespec>>,
+    /// on-periods of all toggles
+    periods: RefCell<Vec<Period>>,
     /// when "hot" perform actual relay operations
     hot: Cell<bool>,
 }
 
 impl Switch {
-    fn new(alias: String, serial: serial::SerialClient) -> Switch {
+    fn new(alias: String,
+           driver: Box<SwitchDriver>,
+           journal: Rc<Journal>,
+           shutdown: config::ShutdownPolicy,
+           fixed: Option<Level>,
+           shapes: Vec<Shape>,
+           site: config::Site,
+           zoneinfo: ZoneInfo) -> Switch {
         Switch {
             alias: alias,
-            serial: serial,
-            last_on: Cell::new(Timespec::new(0, 0)),
-            state: Cell::new(Context::Off),
-            valid_events: RefCell::new(BTreeMap::new()),
+            driver: driver,
+            journal: journal,
+            shutdown: shutdown,
+            fixed: fixed,
+            shapes: shapes,
+            site: site,
+            zoneinfo: zoneinfo,
+            pending_on: RefCell::new(BTreeMap::new()),
+            state: Cell::new(fixed.unwrap_or(Level::off())),
+            scheduled: Cell::new(fixed.unwrap_or(Level::off())),
+            override_until: Cell::new(None),
+            periods: RefCell::new(vec![]),
             hot: Cell::new(false),
         }
     }
 }
 
 impl Switch {
-    fn set_switch_state(&self, state: Context) {
-        self.state.set(state);
+    /// Binary switches only know off and on
+    fn normalize(&self, level: Level) -> Level {
+        if self.driver.dimmable() || !level.is_on() {
+            level
+        } else {
+            Level::on()
+        }
+    }
+
+    fn set_switch_state(&self, state: Level) {
+        self.state.set(self.normalize(state));
         self.dispatch_context();
     }
 
     fn dispatch_context(&self) {
         if self.hot.get() {
-            info!("{}: {:?}", self.alias, self.state.get());
-            match self.state.get() {
-                Context::Off => self.serial.switch_off(&self.alias[..]),
-                Context::On => self.serial.switch_on(&self.alias[..]),
+            info!("{}: {}", self.alias, self.state.get());
+            self.journal.record(&self.alias, self.state.get());
+            self.driver.set_level(self.state.get());
+        }
+    }
+
+    /// Apply the state requested by the schedule; ignored (but remembered) as long as a temporary
+    /// override is active.
+    fn set_scheduled_state(&self, state: Level) {
+        self.scheduled.set(state);
+        if self.override_until.get().is_none() {
+            self.set_switch_state(state);
+        }
+    }
+
+    /// Manually set the state; without a end time the override lasts until the scheduled level
+    /// changes (during a fade that is the next step of the fade), otherwise the scheduled state
+    /// is restored at the end time.
+    fn override_state(&self, state: Level, until: Option<Timespec>) {
+        self.override_until.set(until);
+        self.set_switch_state(state);
+    }
+
+    /// Take over the manual override of the switch this switch replaces, so reloading the
+    /// configuration does not undo it
+    fn take_override(&self, previous: &Switch) {
+        if previous.override_until.get().is_some() || previous.state.get() != previous.scheduled.get() {
+            self.override_until.set(previous.override_until.get());
+            self.state.set(self.normalize(previous.state.get()));
+            self.scheduled.set(previous.scheduled.get());
+        }
+    }
+
+    fn expire_override(&self, now: Timespec) {
+        if let Some(until) = self.override_until.get() {
+            if until <= now {
+                info!("{}: override expired", self.alias);
+                self.override_until.set(None);
+                self.set_switch_state(self.scheduled.get());
             }
         }
     }
 
-    fn make_hot(&self) {
-        self.hot.set(true);
+    /// Apply the shutdown policy of the switch; the relay is set even when the switch is not hot
+    /// (yet), like right after a reload
+    fn shutdown(&self, now: Timespec) {
+        let state = match self.shutdown {
+            config::ShutdownPolicy::Keep => return,
+            config::ShutdownPolicy::Off => Level::off(),
+            config::ShutdownPolicy::Default => self.scheduled_state(now),
+        };
+
+        self.state.set(self.normalize(state));
+        info!("{}: {} (shutdown)", self.alias, self.state.get());
+        self.journal.record(&self.alias, self.state.get());
+        self.driver.set_level(self.state.get());
+    }
+
+    /// The state the schedule requires at the given moment: the highest level of the on-periods
+    /// at that moment.
+    fn scheduled_state(&self, now: Timespec) -> Level {
+        if let Some(fixed) = self.fixed {
+            return fixed;
+        }
+
+        let level = self.periods.borrow().iter()
+                                .map(|period| period.level_at(now))
+                                .max()
+                                .unwrap_or(Level::off());
+        self.normalize(level)
+    }
+
+    /// Follow the schedule: apply the scheduled state when it differs from the previous one
+    fn update(&self, now: Timespec) {
+        let state = self.scheduled_state(now);
+
+        if state != self.scheduled.get() {
+            debug!("update: {} {} {}", at(now).asctime(), state, self.alias);
+            self.set_scheduled_state(state);
+        }
+    }
+
+    /// Set the switch to the state the schedule requires at the given moment (unless an override
+    /// is active) and send it to the relay, even when the state did not change.
+    fn settle(&self, now: Timespec) {
+        let scheduled = self.scheduled_state(now);
+        // an override without end time lasts until the scheduled level changes
+        let overridden = self.override_until.get().is_some() ||
+                         (self.state.get() != self.scheduled.get() && scheduled == self.scheduled.get());
+        self.scheduled.set(scheduled);
+        if !overridden {
+            self.state.set(scheduled);
+        }
         self.dispatch_context();
     }
 
-    fn get_state(&self) -> Context {
+    fn make_hot(&self, now: Timespec) {
+        self.hot.set(true);
+        self.settle(now);
+    }
+
+    fn get_state(&self) -> Level {
         self.state.get()
     }
 
-    fn get_future_events(&self) -> BTreeMap<Timespec, Context> {
-        self.valid_events.borrow().clone()
+    /// Moments after `now` at which the scheduled level changes, or a fade starts
+    fn get_future_events(&self, now: Timespec) -> BTreeMap<Timespec, Level> {
+        let periods = self.periods.borrow();
+        let mut moments: Vec<_> = periods.iter()
+                                         .flat_map(|p| vec![p.start, p.full, p.end, p.stop])
+                                         .filter(|&ts| ts > now)
+                                         .collect();
+        moments.sort();
+        moments.dedup();
+
+        let mut events = BTreeMap::new();
+        let mut previous = self.scheduled_state(now);
+
+        for ts in moments {
+            let fading = periods.iter().any(|p| p.start == ts && p.full > p.start);
+            let level = match (fading, self.driver.dimmable()) {
+                // a binary switch is on as soon as the fade starts
+                (true, false) => Level::on(),
+                _ => self.scheduled_state(ts),
+            };
+
+            if level != previous || fading {
+                events.insert(ts, level);
+            }
+            previous = level;
+        }
+
+        events
     }
 }
 
-impl Handler<Context> for Switch {
+impl Handler<Edge> for Switch {
     /// Hint the event-handler for future events; this function will
-    /// add only valid events (where on event lies before the off event) to
-    /// the valid events list.
-    fn hint(&self, ts: &Timespec, context: &Context) {
-        match *context {
-            Context::On => self.last_on.set(*ts),
-            Context::Off => {
-                if *ts > self.last_on.get() {
-                    debug!("scheduled: {} On  {}", at(self.last_on.get()).asctime(), self.alias);
-                    debug!("scheduled: {} Off {}", at(*ts).asctime(), self.alias);
-                    let mut events = self.valid_events.borrow_mut();
-                    events.insert(self.last_on.get(), Context::On);
-                    events.insert(*ts, Context::Off);
+    /// add only valid periods (where on event lies before the off event of the same toggle) to
+    /// the on-periods of the switch. A toggle is skipped on the days its condition does not hold.
+    fn hint(&self, ts: &Timespec, edge: &Edge) {
+        let shape = &self.shapes[edge.toggle];
+        let format = |ts: Timespec, format: &str| {
+            format!("{}", zone_time(&self.zoneinfo, ts).strftime(format).expect("BUG: invalid time format"))
+        };
+        let day = |ts: Timespec| format(ts, "%Y-%m-%d");
+        let time = |ts: Timespec| format(ts, "%H:%M");
+        let diagnose = |ts: Timespec, reason: String| self.journal.diagnose(Diagnostic {
+            timestamp: ts,
+            switch: self.alias.clone(),
+            toggle: shape.name.clone(),
+            reason: reason,
+        });
+
+        if edge.start {
+            let previous = self.pending_on.borrow_mut().remove(&edge.toggle);
+            if let Some(previous) = previous {
+                diagnose(previous, format!("start at {} on {} skipped, since the next start came before its end",
+                                           time(previous), day(previous)));
+            }
+
+            match shape.condition.skip_reason(*ts, &self.site, &self.zoneinfo) {
+                None => {
+                    self.pending_on.borrow_mut().insert(edge.toggle, *ts);
+                },
+                Some(reason) => diagnose(*ts, format!("skipped on {}: {}", day(*ts), reason)),
+            }
+        } else {
+            let start = self.pending_on.borrow_mut().remove(&edge.toggle);
+
+            if let Some(start) = start {
+                if *ts > start {
+                    let period = Period {
+                        start: start - shape.fade_in,
+                        full: start,
+                        end: *ts,
+                        stop: *ts + shape.fade_out,
+                        level: shape.level,
+                        toggle: edge.toggle,
+                    };
+
+                    for other in self.periods.borrow().iter().filter(|p| p.start < period.stop && period.start < p.stop) {
+                        diagnose(start, format!("on-period on {} overlaps toggle '{}' and is merged with it \
+                                                 (the highest level applies)", day(start), self.shapes[other.toggle].name));
+                    }
+
+                    debug!("scheduled: {} {}  {}", at(start).asctime(), shape.level, self.alias);
+                    debug!("scheduled: {} off {}", at(*ts).asctime(), self.alias);
+                    self.periods.borrow_mut().push(period);
+                } else {
+                    diagnose(*ts, format!("end before start on {} (start at {}, end at {}); skipped",
+                                          day(start), time(start), time(*ts)));
                 }
             }
         }
     }
 
-    /// Perform action only when the timestamp is considered valid;
-    /// Remove the current or prior timestamps from the expected timestamps.
-    fn kick(&self, ts: &Timespec, context: &Context) {
-        if self.valid_events.borrow().contains_key(ts) {
-            debug!("kick: {} {:?} {}", at(*ts).asctime(), context, self.alias);
-            self.set_switch_state(*context);
+    /// Follow the schedule at the moment of the event; Remove the periods that are completely
+    /// passed.
+    fn kick(&self, ts: &Timespec, _: &Edge) {
+        self.update(*ts);
+        self.periods.borrow_mut().retain(|period| period.stop > *ts);
+    }
+}
 
-            let mut events = self.valid_events.borrow_mut();
+/// Status of a stick as reported to the clients of the tracker
+#[derive(Clone, Debug)]
+pub struct StickStatus {
+    /// serial device; the plugwise simulator when `None`
+    pub device: Option<String>,
+    pub connected: bool,
+    /// reason of the last failed attempt to connect
+    pub error: Option<String>,
+    pub circles: Vec<String>,
+}
+
+/// Circle found by the discovery
+#[derive(Clone, Debug)]
+pub struct Discovered {
+    pub stick: String,
+    pub mac: u64,
+    /// alias of the circle when it is configured already
+    pub alias: Option<String>,
+    pub probe: serial::Probe,
+}
+
+/// Status of the tracker
+#[derive(Clone, Debug)]
+pub struct Status {
+    pub time: TimeStatus,
+    pub sticks: BTreeMap<String, StickStatus>,
+}
+
+/// A Plugwise stick with its own serial thread
+struct Stick {
+    serial: serial::SerialClient,
+    /// serial device; the plugwise simulator is used when `None`
+    device: Option<String>,
+    connected: bool,
+    /// moment and reason of the last failed attempt to connect
+    failure: Option<(Timespec, String)>,
+}
+
+impl Stick {
+    fn new(device: Option<String>) -> Stick {
+        Stick {
+            serial: serial::Serial::spawn(),
+            device: device,
+            connected: false,
+            failure: None,
+        }
+    }
+
+    fn describe(&self) -> &str {
+        self.device.as_ref().map_or("simulator", |dev| &dev[..])
+    }
 
-            // don't like the clone here, but keeps events mutable inside the loop
-            for (e, _) in events.clone().iter().take_while(|&(&e, _)| e <= *ts) {
-                events.remove(e);
+    fn register_circles(&self, name: &str, config: &config::Config) {
+        for circle in &config.circles {
+            if let Some((mac, stick)) = circle.plugwise() {
+                if stick == name {
+                    self.serial.register_circle(&circle.alias, mac);
+                }
             }
         }
     }
 }
 
+/// Whether the circle is a Plugwise circle connected to the named stick
+fn on_stick(circle: &config::Circle, name: &str) -> bool {
+    circle.plugwise().map_or(false, |(_, stick)| stick == name)
+}
+
 struct TrackerInner {
-    serial: serial::SerialClient,
-    schedule: Schedule<Context, Switch>,
+    configfile: path::PathBuf,
+    config: Rc<config::Config>,
+    /// local time zone
+    zoneinfo: ZoneInfo,
+    sticks: BTreeMap<String, Stick>,
+    /// always use the plugwise simulator, regardless of the configured device
+    simulate: bool,
+    journal: Rc<Journal>,
+    /// schedule of every time zone in use (the local time zone when `None`)
+    schedules: BTreeMap<Option<String>, Schedule<Edge, Switch>>,
     schedule_ref: Timespec,
     initial: bool,
+    /// systemd is notified after the switches are made hot for the first time
+    ready: bool,
+    /// timestamp of the last tick
+    now: Timespec,
     switches: BTreeMap<String, Rc<Switch>>,
+    /// switches replaced by a reload, until their overrides are taken over
+    replaced: BTreeMap<String, Rc<Switch>>,
 }
 
 impl TrackerInner {
     fn load_schedule(&mut self, config: &config::Config) {
-        self.switches.clear();
-        match config.device.serial_device {
-            None => self.serial.connect_stub(),
-            Some(ref dev) => self.serial.connect_device(&dev[..]).unwrap() // XXX
+        // the overrides of the replaced switches are taken over when the new ones become hot
+        for (alias, switch) in mem::replace(&mut self.switches, BTreeMap::new()) {
+            self.replaced.entry(alias).or_insert(switch);
         }
+        // the diagnostics are made again while the new schedule is filled
+        self.journal.diagnostics.borrow_mut().clear();
+        self.update_sticks(config);
+
         for circle in &config.circles {
-            self.serial.register_circle(&circle.alias, circle.mac);
-            let switch = Rc::new(Switch::new(circle.alias.clone(), self.serial.clone()));
+            let shutdown = circle.shutdown.unwrap_or(config.device.shutdown);
+            let fixed = match circle.default {
+                config::CircleSetting::On => Some(Level::on()),
+                config::CircleSetting::Off => Some(Level::off()),
+                config::CircleSetting::Schedule => None,
+            };
+            let driver: Box<SwitchDriver> = match circle.driver {
+                config::Driver::Plugwise(_, ref stick) => {
+                    Box::new(PlugwiseDriver::new(self.sticks[stick].serial.clone(), &circle.alias))
+                },
+                _ if self.simulate => Box::new(NullDriver::new(circle.dimmable())),
+                config::Driver::Http(ref on, ref off, ref level) => {
+                    Box::new(ExternalDriver::http(&circle.alias, on, off, level.as_ref().map(|l| &l[..])))
+                },
+                config::Driver::Command(ref on, ref off, ref level) => {
+                    Box::new(ExternalDriver::command(&circle.alias, on, off, level.as_ref().map(|l| &l[..])))
+                },
+            };
+            let switch = Rc::new(Switch::new(circle.alias.clone(),
+                                             driver,
+                                             self.journal.clone(),
+                                             shutdown,
+                                             fixed,
+                                             circle.toggles.iter().map(Shape::new).collect(),
+                                             circle.site.clone(),
+                                             self.get_zoneinfo(&circle.site.timezone)));
             match circle.default {
-                config::CircleSetting::On => switch.set_switch_state(Context::On),
-                config::CircleSetting::Off => switch.set_switch_state(Context::Off),
+                config::CircleSetting::On | config::CircleSetting::Off => {},
                 config::CircleSetting::Schedule => {
-                    for toggle in &circle.toggles {
-                        let start = toggle.start.create_dailyevent(&config.device);
-                        let end = toggle.end.create_dailyevent(&config.device);
+                    for (first, second) in circle.overlapping_toggles() {
+                        warn!("{}: toggles '{}' and '{}' overlap; the highest level of both applies",
+                              circle.alias, first, second);
+                    }
+                    let timezone = circle.site.timezone.clone();
+                    if !self.schedules.contains_key(&timezone) {
+                        let zoneinfo = self.get_zoneinfo(&timezone);
+                        self.schedules.insert(timezone.clone(), Schedule::new(zoneinfo));
+                    }
+                    let schedule = self.schedules.get_mut(&timezone).expect("BUG: schedule just created");
+
+                    for (index, toggle) in circle.toggles.iter().enumerate() {
+                        let start = toggle.start.create_dailyevent(&circle.site);
+                        let end = toggle.end.create_dailyevent(&circle.site);
 
-                        self.schedule.add_event(start, switch.clone(), Context::On);
-                        self.schedule.add_event(end, switch.clone(), Context::Off);
+                        schedule.add_event(start, switch.clone(),
+                                           Edge { toggle: index, start: true });
+                        schedule.add_event(end, switch.clone(),
+                                           Edge { toggle: index, start: false });
                     }
                 }
             }
             self.switches.insert(circle.alias.clone(), switch);
         }
+
+        for (name, stick) in &mut self.sticks {
+            if stick.connected {
+                stick.register_circles(name, config);
+            }
+        }
+        self.connect();
+    }
+
+    /// Start a serial thread for every new stick and stop the threads of the sticks that are
+    /// removed or whose device changed
+    fn update_sticks(&mut self, config: &config::Config) {
+        let simulate = self.simulate;
+        let device = |stick: &config::Stick| if simulate { None } else { stick.device.clone() };
+
+        let obsolete: Vec<_> = self.sticks.iter()
+                                          .filter(|&(name, stick)| !config.sticks.iter().any(|s| {
+                                              s.name == *name && device(s) == stick.device
+                                          }))
+                                          .map(|(name, _)| name.clone())
+                                          .collect();
+        for name in obsolete {
+            if let Some(stick) = self.sticks.remove(&name) {
+                stick.serial.hangup();
+            }
+        }
+
+        for stick in &config.sticks {
+            if !self.sticks.contains_key(&stick.name) {
+                self.sticks.insert(stick.name.clone(), Stick::new(device(stick)));
+            }
+        }
     }
 
-    fn new(config: &config::Config, zoneinfo: &ZoneInfo) -> TrackerInner {
-        let schedule = Schedule::new(zoneinfo.clone());
-        let serial = serial::Serial::spawn();
+    /// Connect to the sticks that are not connected yet and register their circles; when the
+    /// switches are hot already, they are set to their current state. A failed attempt is
+    /// retried by a later tick.
+    fn connect(&mut self) {
+        let config = self.config.clone();
 
+        for (name, stick) in &mut self.sticks {
+            if stick.connected {
+                continue;
+            }
+            if let Some((failed, _)) = stick.failure {
+                if self.now - failed < Duration::seconds(CONNECT_RETRY_SECONDS) {
+                    continue;
+                }
+            }
+
+            let result = match stick.device {
+                None => Ok(stick.serial.connect_stub()),
+                Some(ref dev) => stick.serial.connect_device(&dev[..]),
+            };
+
+            match result {
+                Ok(()) => {
+                    if stick.failure.is_some() {
+                        info!("stick {}: connected to {}", name, stick.describe());
+                    }
+                    stick.register_circles(name, &config);
+                    stick.connected = true;
+                    stick.failure = None;
+
+                    if !self.initial {
+                        for circle in config.circles.iter().filter(|circle| on_stick(circle, name)) {
+                            if let Some(switch) = self.switches.get(&circle.alias) {
+                                switch.settle(self.now);
+                            }
+                        }
+                    }
+                },
+                Err(err) => {
+                    if stick.failure.is_none() {
+                        error!("stick {}: unable to connect to {}: {}; retrying every {} seconds",
+                               name, stick.describe(), err, CONNECT_RETRY_SECONDS);
+                    }
+                    stick.failure = Some((self.now, format!("{}", err)));
+                }
+            }
+        }
+    }
+
+    fn get_stick_status(&self) -> BTreeMap<String, StickStatus> {
+        self.sticks.iter().map(|(name, stick)| {
+            (name.clone(), StickStatus {
+                device: stick.device.clone(),
+                connected: stick.connected,
+                error: stick.failure.as_ref().map(|&(_, ref err)| err.clone()),
+                circles: self.config.circles.iter()
+                                            .filter(|circle| on_stick(circle, name))
+                                            .map(|circle| circle.alias.clone())
+                                            .collect(),
+            })
+        }).collect()
+    }
+
+    /// Serial client and configured circles (mac and alias) of every stick
+    fn get_sticks(&self) -> Vec<(String, serial::SerialClient, Vec<(u64, String)>)> {
+        self.sticks.iter().map(|(name, stick)| {
+            let circles = self.config.circles.iter()
+                                             .filter_map(|circle| match circle.plugwise() {
+                                                 Some((mac, stick)) if stick == name => Some((mac, circle.alias.clone())),
+                                                 _ => None,
+                                             })
+                                             .collect();
+            (name.clone(), stick.serial.clone(), circles)
+        }).collect()
+    }
+
+    fn hangup(&self) {
+        for stick in self.sticks.values() {
+            stick.serial.hangup();
+        }
+    }
+
+    fn new(configfile: path::PathBuf,
+           config: config::Config,
+           zoneinfo: &ZoneInfo,
+           journal: Journal,
+           simulate: bool) -> TrackerInner {
         let mut tracker = TrackerInner {
-            schedule: schedule,
-            serial: serial,
+            configfile: configfile,
+            config: Rc::new(config),
+            zoneinfo: zoneinfo.clone(),
+            schedules: BTreeMap::new(),
+            sticks: BTreeMap::new(),
+            simulate: simulate,
+            journal: Rc::new(journal),
             schedule_ref: Timespec::new(0,0),
             initial: true,
+            ready: false,
+            now: Timespec::new(0,0),
             switches: BTreeMap::new(),
+            replaced: BTreeMap::new(),
         };
 
-        tracker.load_schedule(config);
+        let config = tracker.config.clone();
+        tracker.load_schedule(&config);
 
         tracker
     }
 
+    /// Reload the configuration file and rebuild the schedule; the current schedule is kept when
+    /// the configuration file contains errors.
+    fn reload(&mut self) -> Result<(), String> {
+        let config = try!(config::Config::new(&self.configfile).map_err(|err|
+            format!("unable to load {}: {}", self.configfile.display(), err)));
+
+        self.config = Rc::new(config);
+        self.rebuild();
+        info!("reloaded {}", self.configfile.display());
+
+        if self.now != Timespec::new(0,0) {
+            let now = self.now;
+            self.process_tick(now);
+        }
+
+        Ok(())
+    }
+
+    /// Apply an edit to the configuration file and reload it. Nothing is written when the edited
+    /// configuration is invalid; the original file is restored when it cannot be loaded.
+    fn edit_config<F>(&mut self, edit: F) -> Result<(), String>
+        where F: FnOnce(&mut ConfigEditor) -> Result<(), String> {
+        let mut original = String::new();
+        try!(fs::File::open(&self.configfile).and_then(|mut file| file.read_to_string(&mut original))
+                 .map_err(|err| format!("unable to read {}: {}", self.configfile.display(), err)));
+
+        let mut editor = ConfigEditor::new(&original);
+        try!(edit(&mut editor));
+        let updated = format!("{}", editor);
+        try!(config::Config::parse(&self.configfile, &updated).map_err(|err| format!("{}", err)));
+
+        try!(self.backup_config(&original));
+        try!(self.write_config(&updated));
+        match self.reload() {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                try!(self.write_config(&original));
+                Err(err)
+            }
+        }
+    }
+
+    /// Change the configuration and write the changed tables and keys back to the configuration
+    /// file. The change is applied to the configuration as it is in the file (which may differ
+    /// from the loaded configuration when the file was edited by hand). Tables of included files
+    /// cannot be changed.
+    fn change_config<F>(&mut self, change: F) -> Result<(), String>
+        where F: FnOnce(&mut config::Config) -> Result<(), String> {
+        let configfile = self.configfile.clone();
+
+        self.edit_config(|editor| {
+            let current = try!(config::Config::parse(&configfile, &format!("{}", editor))
+                                   .map_err(|err| format!("{}", err)));
+            let mut changed = current.clone();
+
+            try!(change(&mut changed));
+
+            let (mut old, mut new) = (current.to_toml(), changed.to_toml());
+            if let Some((name, file)) = current.changed_included_table(&old, &new) {
+                return Err(format!("'{}' is defined in {} and cannot be changed", name, file));
+            }
+            old.retain(|&(ref name, _)| current.included_file(name).is_none());
+            new.retain(|&(ref name, _)| current.included_file(name).is_none());
+
+            editor.update(&old, &new);
+            Ok(())
+        })
+    }
+
+    /// Add a discovered circle to the configuration file and reload it
+    fn add_circle(&mut self, alias: &str, mac: u64, stick: &str) -> Result<(), String> {
+        if !config::valid_alias(alias) {
+            return Err(format!("invalid alias '{}', expected letters, digits, '-' or '_' (and not config, site, stick, \
+                                template or include)", alias));
+        }
+
+        try!(self.change_config(|config| {
+            if config.circles.iter().any(|c| c.alias == alias) {
+                return Err(format!("circle '{}' already exists", alias));
+            }
+            if let Some(circle) = config.circles.iter().find(|c| c.plugwise().map(|(m, _)| m) == Some(mac)) {
+                return Err(format!("circle '{}' with mac {:016X} already exists", circle.alias, mac));
+            }
+            if !config.sticks.iter().any(|s| s.name == stick) {
+                return Err(format!("unknown stick '{}'", stick));
+            }
+
+            // the circle stays off until toggles are added
+            let site = config.device.site();
+            config.circles.push(config::Circle {
+                alias: alias.into(),
+                driver: config::Driver::Plugwise(mac, stick.into()),
+                default: config::CircleSetting::Off,
+                shutdown: None,
+                site_name: None,
+                site: site,
+                templates: vec![],
+                toggles: vec![],
+            });
+            Ok(())
+        }));
+        info!("added circle '{}' ({:016X}) to {}", alias, mac, self.configfile.display());
+        Ok(())
+    }
+
+    /// Create a circle or change the settings (not the toggles) of a circle; settings without a
+    /// value are removed
+    fn set_circle(&mut self, alias: &str, settings: &[(String, Option<toml::Value>)]) -> Result<(), String> {
+        let exists = self.config.circles.iter().any(|c| c.alias == alias);
+
+        if !exists && !config::valid_alias(alias) {
+            return Err(format!("invalid alias '{}', expected letters, digits, '-' or '_' (and not config, site, stick, \
+                                template or include)", alias));
+        }
+        if let Some(&(ref key, _)) = settings.iter().find(|&&(ref key, _)| !config::circle_setting(key)) {
+            return Err(format!("unknown circle setting '{}'", key));
+        }
+        // everybody with access to the web interface would be able to run commands otherwise
+        if let Some(&(ref key, _)) = settings.iter().find(|&&(ref key, _)| config::driver_setting(key)) {
+            return Err(format!("circle setting '{}' can only be changed in the configuration file", key));
+        }
+        if let Some(file) = self.config.included_file(alias) {
+            return Err(format!("circle '{}' is defined in {} and cannot be changed", alias, file));
+        }
+
+        try!(self.edit_config(|editor| {
+            if exists {
+                for &(ref key, ref value) in settings {
+                    match *value {
+                        Some(ref value) => { let _ = editor.set(alias, key, value); },
+                        None => { let _ = editor.remove(alias, key); },
+                    }
+                }
+            } else {
+                let pairs: Vec<_> = settings.iter()
+                                            .filter_map(|&(ref key, ref value)| value.clone().map(|value| (key.clone(), value)))
+                                            .collect();
+                editor.add_table(alias, &pairs, None);
+            }
+            Ok(())
+        }));
+        info!("{} circle '{}' in {}", if exists { "changed" } else { "added" }, alias, self.configfile.display());
+        Ok(())
+    }
+
+    /// Remove a circle and its toggles
+    fn delete_circle(&mut self, alias: &str) -> Result<(), String> {
+        try!(self.change_config(|config| {
+            if !config.circles.iter().any(|c| c.alias == alias) {
+                return Err(format!("unknown circle '{}'", alias));
+            }
+            config.circles.retain(|c| c.alias != alias);
+            Ok(())
+        }));
+        info!("removed circle '{}' from {}", alias, self.configfile.display());
+        Ok(())
+    }
+
+    /// Create or replace the toggle `name` of a circle; the toggle is renamed when its alias
+    /// differs from `name`
+    fn set_toggle(&mut self, alias: &str, name: &str, toggle: &config::Toggle) -> Result<(), String> {
+        try!(self.change_config(|config| {
+            let circle = try!(config.circles.iter_mut().find(|c| c.alias == alias)
+                                    .ok_or(format!("unknown circle '{}'", alias)));
+            let new = &toggle.alias[..];
+
+            if !circle.toggles.iter().any(|t| t.alias == new) && !config::valid_toggle_name(new) {
+                return Err(format!("invalid toggle name '{}', expected letters, digits, '-' or '_' \
+                                    (and no circle setting)", new));
+            }
+            if new != name && circle.toggles.iter().any(|t| t.alias == new) {
+                return Err(format!("toggle '{}' of circle '{}' already exists", new, alias));
+            }
+            // a changed toggle of a template becomes a toggle of the circle, which overrides the
+            // toggle of the template; renaming it would bring back the toggle of the template
+            let template = circle.toggles.iter().find(|t| t.alias == name).and_then(|t| t.template.clone());
+            match template {
+                Some(ref template) if new != name =>
+                    return Err(format!("toggle '{}' of circle '{}' comes from template '{}' and cannot be renamed",
+                                       name, alias, template)),
+                _ => {},
+            }
+
+            match circle.toggles.iter().position(|t| t.alias == name) {
+                Some(n) => circle.toggles[n] = toggle.clone(),
+                None => circle.toggles.push(toggle.clone()),
+            }
+            Ok(())
+        }));
+        info!("changed toggle '{}' of circle '{}' in {}", toggle.alias, alias, self.configfile.display());
+        Ok(())
+    }
+
+    /// Remove a toggle of a circle
+    fn delete_toggle(&mut self, alias: &str, name: &str) -> Result<(), String> {
+        try!(self.change_config(|config| {
+            let circle = try!(config.circles.iter_mut().find(|c| c.alias == alias)
+                                    .ok_or(format!("unknown circle '{}'", alias)));
+
+            match circle.toggles.iter().find(|t| t.alias == name) {
+                None => return Err(format!("unknown toggle '{}' of circle '{}'", name, alias)),
+                Some(&config::Toggle { template: Some(ref template), .. }) =>
+                    return Err(format!("toggle '{}' of circle '{}' comes from template '{}'; remove the \
+                                        template from the circle instead", name, alias, template)),
+                Some(_) => {},
+            }
+            circle.toggles.retain(|t| t.alias != name);
+            Ok(())
+        }));
+        info!("removed toggle '{}' of circle '{}' from {}", name, alias, self.configfile.display());
+        Ok(())
+    }
+
+    /// Keep a copy of the configuration file as `<file>.<timestamp>-<counter>.bak` before it is
+    /// changed (the counter separates the copies made within the same second); only the newest
+    /// copies are kept
+    fn backup_config(&self, content: &str) -> Result<(), String> {
+        let name = match self.configfile.file_name().and_then(|name| name.to_str()) {
+            Some(name) => String::from(name),
+            None => return Ok(()),
+        };
+        let timestamp = format!("{}", ::time::now().strftime("%Y%m%d-%H%M%S").expect("BUG: invalid time format"));
+        let mut counter = 0;
+        let mut backup = self.configfile.with_file_name(format!("{}.{}-{:03}.bak", name, timestamp, counter));
+        while backup.exists() {
+            counter += 1;
+            backup = self.configfile.with_file_name(format!("{}.{}-{:03}.bak", name, timestamp, counter));
+        }
+
+        try!(fs::File::create(&backup)
+                 .and_then(|mut file| file.write_all(content.as_bytes()))
+                 .map_err(|err| format!("unable to write {}: {}", backup.display(), err)));
+
+        // the timestamps and counters sort chronologically
+        let prefix = format!("{}.", name);
+        let mut backups: Vec<_> = backup.parent()
+                                        .and_then(|dir| fs::read_dir(dir).ok())
+                                        .map_or(vec![], |entries| entries.filter_map(|entry| entry.ok())
+                                                                         .map(|entry| entry.path())
+                                                                         .collect());
+        backups.retain(|path| path.file_name().and_then(|n| n.to_str()).map_or(false, |n| {
+            n.starts_with(&prefix) && n.ends_with(".bak")
+        }));
+        backups.sort();
+
+        while backups.len() > CONFIG_BACKUPS {
+            let oldest = backups.remove(0);
+            if let Err(err) = fs::remove_file(&oldest) {
+                warn!("unable to remove {}: {}", oldest.display(), err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replace the configuration file atomically
+    fn write_config(&self, content: &str) -> Result<(), String> {
+        let mut temp = self.configfile.clone().into_os_string();
+        temp.push(".tmp");
+
+        fs::File::create(&temp)
+            .and_then(|mut file| file.write_all(content.as_bytes()).and_then(|_| file.sync_all()))
+            .and_then(|_| fs::rename(&temp, &self.configfile))
+            .map_err(|err| format!("unable to write {}: {}", self.configfile.display(), err))
+    }
+
+    /// Recreate the switches and the schedule; the next tick fills the schedule from scratch
+    /// and settles the switches to the state the schedule requires at that moment.
+    fn rebuild(&mut self) {
+        let config = self.config.clone();
+
+        self.schedules.clear();
+        self.load_schedule(&config);
+        self.initial = true;
+    }
+
+    /// Detect whether the clock jumped since the previous tick. Small backward corrections are
+    /// harmless, since events that passed are never kicked again. Larger jumps would cause
+    /// events to be missed (backwards) or a burst of outdated events to be dispatched (forwards),
+    /// so in both cases the schedule is rebuilt for the new time.
+    fn detect_jump(&mut self, timestamp: Timespec) {
+        if self.initial || self.now == Timespec::new(0,0) {
+            return;
+        }
+
+        let deviation = timestamp - self.now - Duration::seconds(TICK_SECONDS);
+
+        if deviation.num_seconds().abs() > JUMP_THRESHOLD_SECONDS {
+            warn!("clock jumped from {} to {}; rebuilding schedule",
+                  at(self.now).asctime(), at(timestamp).asctime());
+            self.rebuild();
+        }
+    }
+
+    /// Time zone information of the given time zone; the local time zone when `None` or when the
+    /// time zone cannot be loaded
+    fn get_zoneinfo(&self, timezone: &Option<String>) -> ZoneInfo {
+        match *timezone {
+            Some(ref tz) => ZoneInfo::by_tz(&tz[..]).unwrap_or_else(|_| {
+                error!("unable to load time zone {}; using local time zone", tz);
+                self.zoneinfo.clone()
+            }),
+            None => self.zoneinfo.clone(),
+        }
+    }
+
     fn update_schedule(&mut self) {
-        self.schedule.update_schedule(self.schedule_ref);
+        for schedule in self.schedules.values_mut() {
+            schedule.update_schedule(self.schedule_ref);
+        }
         self.schedule_ref = self.schedule_ref + Duration::days(1);
     }
 
     fn process_tick(&mut self, timestamp: Timespec) {
+        self.detect_jump(timestamp);
+        // after a small backward correction the schedule waits until the clock caught up, so no
+        // level is applied twice
+        let timestamp = if self.initial { timestamp } else { cmp::max(timestamp, self.now) };
+        self.now = timestamp;
+        self.journal.now.set(timestamp);
+
+        self.connect();
+
         if self.initial {
             let mut tm = at_utc(timestamp);
             tm.tm_hour = 0;
             tm.tm_min = 0;
             tm.tm_sec = 0;
             tm.tm_nsec = 0;
-            self.schedule_ref = tm.to_timespec();
+            // start a day earlier, so on-periods that started yesterday are known
+            self.schedule_ref = tm.to_timespec() - Duration::days(1);
 
-            // fill schedule for 48 hours:
+            // fill schedule for 72 hours:
+            self.update_schedule();
             self.update_schedule();
             self.update_schedule();
         }
@@ -183,18 +1169,47 @@ impl TrackerInner {
             self.update_schedule();
         }
 
-        self.schedule.kick_event(timestamp);
+        for schedule in self.schedules.values_mut() {
+            schedule.kick_event(timestamp);
+        }
+
+        for switch in self.switches.values() {
+            // fades change the level between the events of the schedule
+            switch.update(timestamp);
+            switch.expire_override(timestamp);
+        }
 
         if self.initial {
             self.initial = false;
             // configure the switch to actually set the relay (otherwise the initial kicks will
-            // quickly toggle switches unintendedly
-            for (_, ref mut switch) in &mut self.switches {
-                switch.make_hot();
+            // quickly toggle switches unintendedly); the switches are set to the state the
+            // schedule requires now, instead of relying on the outcome of the initial kicks
+            for (alias, switch) in &self.switches {
+                if let Some(previous) = self.replaced.get(alias) {
+                    switch.take_override(previous);
+                    switch.expire_override(timestamp);
+                }
+                switch.make_hot(timestamp);
+            }
+            self.replaced.clear();
+
+            if !self.ready {
+                self.ready = true;
+                systemd::notify("READY=1");
             }
         }
     }
 
+    /// Apply the shutdown policy of every switch and wait until the drivers performed it
+    fn shutdown(&self) {
+        for switch in self.switches.values() {
+            switch.shutdown(self.now);
+        }
+        for switch in self.switches.values() {
+            switch.driver.flush();
+        }
+    }
+
     fn get_list(&self) -> Vec<String> {
         let mut switches = vec![];
 
@@ -215,8 +1230,18 @@ enum Message {
     Tick,
     Teardown,
     List(Sender<Vec<String>>),
-    Get(String, Sender<Option<(Context, BTreeMap<Timespec, Context>)>>),
-    Switch(String, Context, Sender<Context>),
+    Get(String, Sender<Option<SwitchInfo>>),
+    Switch(String, Level, Option<Duration>, Sender<Option<Level>>),
+    Reload(Sender<Result<(), String>>),
+    Status(Sender<Status>),
+    Sticks(Sender<Vec<(String, serial::SerialClient, Vec<(u64, String)>)>>),
+    AddCircle(String, u64, String, Sender<Result<(), String>>),
+    SetCircle(String, Vec<(String, Option<toml::Value>)>, Sender<Result<(), String>>),
+    DeleteCircle(String, Sender<Result<(), String>>),
+    SetToggle(String, String, config::Toggle, Sender<Result<(), String>>),
+    DeleteToggle(String, String, Sender<Result<(), String>>),
+    Circle(String, Sender<Option<config::Circle>>),
+    History(String, Sender<Vec<Action>>),
 }
 
 pub struct Tracker {
@@ -225,23 +1250,25 @@ pub struct Tracker {
 }
 
 impl Tracker {
-    pub fn spawn(configfile: path::PathBuf) -> Tracker {
+    pub fn spawn(configfile: path::PathBuf, config: config::Config, clock: Box<Clock>) -> Tracker {
         let zoneinfo = ZoneInfo::get_local_zoneinfo().expect("BUG: not able to load local zoneinfo");
         let (tx, rx) = channel();
 
         let joiner = thread::spawn(move || {
-            let config = config::Config::new(&configfile).unwrap(); // XXX
-            let mut tracker = TrackerInner::new(&config, &zoneinfo);
-            let ticker = Ticker::spawn(&config.device.ntp_server,
-                                       Duration::seconds(10),
-                                       Duration::days(1),
-                                       Message::Tick);
+            let journal = Journal::new(Some(JOURNAL_SIZE));
+            let mut tracker = TrackerInner::new(configfile.clone(), config, &zoneinfo, journal, false);
+            let ticker = Ticker::spawn(clock, Duration::seconds(TICK_SECONDS), Message::Tick);
 
             tx.send(ticker.get_sender()).expect("BUG: tracker thread unable to communicate with spawner");
 
+            let mut watchdog = systemd::Watchdog::new();
+
             for (event, timestamp) in ticker.recv_iter() {
                 match event {
                     Message::Tick => {
+                        if let Some(ref mut watchdog) = watchdog {
+                            watchdog.ping();
+                        }
                         if let Some(timestamp) = timestamp {
                             tracker.process_tick(timestamp);
                         }
@@ -254,22 +1281,66 @@ impl Tracker {
                     },
                     Message::Get(ref switch, ref sender) => {
                         let switch = tracker.get_switch(switch);
-                        let result = switch.map(|switch|(switch.get_state(), switch.get_future_events()));
+                        let result = switch.map(|switch| SwitchInfo {
+                            state: switch.get_state(),
+                            scheduled_state: switch.scheduled_state(tracker.now),
+                            next_events: switch.get_future_events(tracker.now),
+                            dimmable: switch.driver.dimmable(),
+                            diagnostics: tracker.journal.diagnostics(Some(&switch.alias)),
+                        });
                         sender.send(result).expect("BUG: unable to send switch status");
                     },
-                    Message::Switch(ref switch, ref state, ref sender) => {
+                    Message::Switch(ref switch, ref state, ref duration, ref sender) => {
+                        let until = duration.map(|duration| tracker.now + duration);
                         let switch = tracker.get_switch(switch);
-                        let result = switch.map_or(Context::Off, |switch| {
-                            switch.set_switch_state(*state);
+                        let result = switch.map(|switch| {
+                            switch.override_state(*state, until);
                             switch.get_state()
                         });
 
                         sender.send(result).expect("BUG: unable to send toggle result");
                     },
+                    Message::Reload(ref sender) => {
+                        sender.send(tracker.reload()).expect("BUG: unable to send reload result");
+                    },
+                    Message::Sticks(ref sender) => {
+                        sender.send(tracker.get_sticks()).expect("BUG: unable to send sticks");
+                    },
+                    Message::AddCircle(ref alias, mac, ref stick, ref sender) => {
+                        sender.send(tracker.add_circle(alias, mac, stick))
+                              .expect("BUG: unable to send add result");
+                    },
+                    Message::SetCircle(ref alias, ref settings, ref sender) => {
+                        sender.send(tracker.set_circle(alias, settings)).expect("BUG: unable to send edit result");
+                    },
+                    Message::DeleteCircle(ref alias, ref sender) => {
+                        sender.send(tracker.delete_circle(alias)).expect("BUG: unable to send edit result");
+                    },
+                    Message::SetToggle(ref alias, ref name, ref toggle, ref sender) => {
+                        sender.send(tracker.set_toggle(alias, name, toggle)).expect("BUG: unable to send edit result");
+                    },
+                    Message::DeleteToggle(ref alias, ref name, ref sender) => {
+                        sender.send(tracker.delete_toggle(alias, name)).expect("BUG: unable to send edit result");
+                    },
+                    Message::Circle(ref alias, ref sender) => {
+                        let circle = tracker.config.circles.iter().find(|circle| circle.alias == *alias);
+                        sender.send(circle.cloned()).expect("BUG: unable to send circle");
+                    },
+                    Message::History(ref switch, ref sender) => {
+                        sender.send(tracker.journal.history(switch)).expect("BUG: unable to send history");
+                    },
+                    Message::Status(ref sender) => {
+                        let status = Status {
+                            time: ticker.get_status(),
+                            sticks: tracker.get_stick_status(),
+                        };
+                        sender.send(status).expect("BUG: unable to send status");
+                    },
                 }
             }
             ticker.stop_ticker();
-            tracker.serial.hangup();
+            tracker.shutdown();
+            tracker.hangup();
         });
 
         let sender = rx.recv().expect("BUG: tracker thread unable to bootstrap");
@@ -296,6 +1367,37 @@ impl Tracker {
     }
 }
 
+/// Run the schedule of a configuration against the plugwise simulator until the clock passes
+/// `until`; every switch action is passed to `record`. Returns the diagnostics of all switches.
+pub fn simulate<F>(config: config::Config,
+                   clock: &mut SimulatedClock,
+                   until: Timespec,
+                   mut record: F) -> Vec<Diagnostic> where F: FnMut(&Action) {
+    let zoneinfo = ZoneInfo::get_local_zoneinfo().expect("BUG: not able to load local zoneinfo");
+    let mut tracker = TrackerInner::new(path::PathBuf::new(), config, &zoneinfo, Journal::new(None), true);
+    let step = Duration::seconds(TICK_SECONDS);
+
+    loop {
+        let now = clock.now().expect("BUG: simulated clock without time");
+        if now > until {
+            break;
+        }
+
+        tracker.process_tick(now);
+        for action in tracker.journal.drain() {
+            record(&action);
+        }
+
+        match clock.real_interval(step).num_milliseconds() {
+            0 => clock.advance(step),
+            interval => thread::sleep(stdtime::Duration::from_millis(interval as u64)),
+        }
+    }
+
+    tracker.hangup();
+    tracker.journal.diagnostics(None)
+}
+
 type TrackerSender = Arc<Mutex<Sender<(Message, Option<Timespec>)>>>;
 
 #[derive(Clone)]
@@ -311,7 +1413,7 @@ impl TrackerClient {
         rx.recv().expect("BUG: unable to receive list")
     }
 
-    pub fn get_switch(&self, switch: &str) -> Option<(Context, BTreeMap<Timespec, Context>)> {
+    pub fn get_switch(&self, switch: &str) -> Option<SwitchInfo> {
         let tracker = self.tx.lock().expect("BUG: unable to get channel");
         let (tx, rx) = channel();
         tracker.send((Message::Get(switch.into(), tx), None))
@@ -319,11 +1421,456 @@ impl TrackerClient {
         rx.recv().expect("BUG: unable to receive switch status")
     }
 
-    pub fn switch(&self, switch: &str, state: Context) -> Context {
+    /// Override the level of a switch, optionally only for the given duration. Returns the
+    /// resulting level (binary switches are on at any level above 0) or `None` when the switch
+    /// is unknown.
+    pub fn switch(&self, switch: &str, state: Level, duration: Option<Duration>) -> Option<Level> {
         let tracker = self.tx.lock().expect("BUG: unable to get channel");
         let (tx, rx) = channel();
-        tracker.send((Message::Switch(switch.into(), state, tx), None))
+        tracker.send((Message::Switch(switch.into(), state, duration, tx), None))
             .expect("BUG: unable to toggle switch");
         rx.recv().expect("BUG: unable to get toggle result")
     }
+
+    pub fn get_status(&self) -> Status {
+        let tracker = self.tx.lock().expect("BUG: unable to get channel");
+        let (tx, rx) = channel();
+        tracker.send((Message::Status(tx), None)).expect("BUG: unable to get status");
+        rx.recv().expect("BUG: unable to receive status")
+    }
+
+    /// Probe the configured circles and the given (unconfigured) circles on every stick. The
+    /// stick does not provide a list of the circles in its network, so new circles are found by
+    /// probing their mac (printed on the circle). The probing takes place in the calling thread,
+    /// so the tracker is not blocked meanwhile; fails when a stick is disconnected (by a reload)
+    /// while probing.
+    pub fn discover(&self, macs: &[u64]) -> Result<Vec<Discovered>, String> {
+        let sticks = {
+            let tracker = self.tx.lock().expect("BUG: unable to get channel");
+            let (tx, rx) = channel();
+            tracker.send((Message::Sticks(tx), None)).expect("BUG: unable to get sticks");
+            rx.recv().expect("BUG: unable to receive sticks")
+        };
+        let configured: Vec<u64> = sticks.iter()
+                                         .flat_map(|&(_, _, ref circles)| circles.iter().map(|&(mac, _)| mac))
+                                         .collect();
+        let mut discovered = vec![];
+
+        for (name, serial, circles) in sticks {
+            let mut probe: Vec<u64> = circles.iter().map(|&(mac, _)| mac).collect();
+            probe.extend(macs.iter().filter(|mac| !configured.contains(mac)));
+
+            for result in try!(serial.probe(probe).map_err(|err| format!("stick {}: {}", name, err))) {
+                discovered.push(Discovered {
+                    stick: name.clone(),
+                    mac: result.mac,
+                    alias: circles.iter().find(|&&(mac, _)| mac == result.mac).map(|&(_, ref alias)| alias.clone()),
+                    probe: result,
+                });
+            }
+        }
+
+        Ok(discovered)
+    }
+
+    /// Add a discovered circle to the configuration file
+    pub fn add_circle(&self, alias: &str, mac: u64, stick: &str) -> Result<(), String> {
+        let tracker = self.tx.lock().expect("BUG: unable to get channel");
+        let (tx, rx) = channel();
+        tracker.send((Message::AddCircle(alias.into(), mac, stick.into(), tx), None))
+            .expect("BUG: unable to add circle");
+        rx.recv().expect("BUG: unable to get add result")
+    }
+
+    /// Create a circle or change its settings in the configuration file; settings without a
+    /// value are removed
+    pub fn set_circle(&self, alias: &str, settings: Vec<(String, Option<toml::Value>)>) -> Result<(), String> {
+        let tracker = self.tx.lock().expect("BUG: unable to get channel");
+        let (tx, rx) = channel();
+        tracker.send((Message::SetCircle(alias.into(), settings, tx), None)).expect("BUG: unable to set circle");
+        rx.recv().expect("BUG: unable to get edit result")
+    }
+
+    /// Remove a circle from the configuration file
+    pub fn delete_circle(&self, alias: &str) -> Result<(), String> {
+        let tracker = self.tx.lock().expect("BUG: unable to get channel");
+        let (tx, rx) = channel();
+        tracker.send((Message::DeleteCircle(alias.into(), tx), None)).expect("BUG: unable to delete circle");
+        rx.recv().expect("BUG: unable to get edit result")
+    }
+
+    /// Create, replace or rename (when the alias of `toggle` differs from `name`) a toggle of a
+    /// circle in the configuration file
+    pub fn set_toggle(&self, alias: &str, name: &str, toggle: config::Toggle) -> Result<(), String> {
+        let tracker = self.tx.lock().expect("BUG: unable to get channel");
+        let (tx, rx) = channel();
+        tracker.send((Message::SetToggle(alias.into(), name.into(), toggle, tx), None))
+            .expect("BUG: unable to set toggle");
+        rx.recv().expect("BUG: unable to get edit result")
+    }
+
+    /// Remove a toggle of a circle from the configuration file
+    pub fn delete_toggle(&self, alias: &str, name: &str) -> Result<(), String> {
+        let tracker = self.tx.lock().expect("BUG: unable to get channel");
+        let (tx, rx) = channel();
+        tracker.send((Message::DeleteToggle(alias.into(), name.into(), tx), None))
+            .expect("BUG: unable to delete toggle");
+        rx.recv().expect("BUG: unable to get edit result")
+    }
+
+    /// Configuration of a circle; `None` when the circle is unknown
+    pub fn get_circle(&self, alias: &str) -> Option<config::Circle> {
+        let tracker = self.tx.lock().expect("BUG: unable to get channel");
+        let (tx, rx) = channel();
+        tracker.send((Message::Circle(alias.into(), tx), None)).expect("BUG: unable to get circle");
+        rx.recv().expect("BUG: unable to receive circle")
+    }
+
+    /// Recent actions of a switch, oldest first
+    pub fn get_history(&self, switch: &str) -> Vec<Action> {
+        let tracker = self.tx.lock().expect("BUG: unable to get channel");
+        let (tx, rx) = channel();
+        tracker.send((Message::History(switch.into(), tx), None)).expect("BUG: unable to get history");
+        rx.recv().expect("BUG: unable to receive history")
+    }
+
+    pub fn reload(&self) -> Result<(), String> {
+        let tracker = self.tx.lock().expect("BUG: unable to get channel");
+        let (tx, rx) = channel();
+        tracker.send((Message::Reload(tx), None)).expect("BUG: unable to reload");
+        rx.recv().expect("BUG: unable to get reload result")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config;
+    use dailyschedule::Handler;
+    use driver::NullDriver;
+    use std::collections::BTreeMap;
+    use std::rc::Rc;
+    use time::{Duration, Timespec};
+
+    /// Midnight of the day of the tests
+    const MIDNIGHT: i64 = 1782000000 - 1782000000 % 86400;
+
+    fn at_time(hour: i64, minute: i64) -> Timespec {
+        Timespec::new(MIDNIGHT + hour * 3600 + minute * 60, 0)
+    }
+
+    /// A scheduled switch with a toggle of every (level, fade in) pair
+    fn switch(dimmable: bool, toggles: &[(u8, i64)]) -> Switch {
+        let shapes = toggles.iter().enumerate().map(|(n, &(level, fade_in))| Shape {
+            name: format!("toggle{}", n),
+            level: Level::new(level).unwrap(),
+            fade_in: Duration::minutes(fade_in),
+            fade_out: Duration::zero(),
+            condition: config::Condition::default(),
+        }).collect();
+        let site = config::Site { latitude: 52.0, longitude: 5.0, timezone: None };
+
+        Switch::new("lamp".into(), Box::new(NullDriver::new(dimmable)), Rc::new(Journal::new(None)),
+                    config::ShutdownPolicy::Keep, None, shapes, site, utc_zone())
+    }
+
+    fn utc_zone() -> ZoneInfo {
+        ZoneInfo::by_tz("UTC").expect("zoneinfo of UTC")
+    }
+
+    /// Hint the on-period of a toggle
+    fn period(switch: &Switch, toggle: usize, start: Timespec, end: Timespec) {
+        switch.hint(&start, &Edge { toggle: toggle, start: true });
+        switch.hint(&end, &Edge { toggle: toggle, start: false });
+    }
+
+    fn events(events: &[(Timespec, u8)]) -> BTreeMap<Timespec, Level> {
+        events.iter().map(|&(ts, level)| (ts, Level::new(level).unwrap())).collect()
+    }
+
+    #[test]
+    fn overlapping_periods_merge() {
+        let lamp = switch(false, &[(100, 0), (100, 0)]);
+        period(&lamp, 0, at_time(18, 0), at_time(22, 0));
+        period(&lamp, 1, at_time(21, 0), at_time(23, 0));
+
+        assert_eq!(lamp.get_future_events(at_time(12, 0)), events(&[(at_time(18, 0), 100), (at_time(23, 0), 0)]));
+        assert_eq!(lamp.scheduled_state(at_time(21, 30)), Level::on());
+
+        let diagnostics = lamp.journal.diagnostics(Some("lamp"));
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].toggle, "toggle1");
+        assert!(diagnostics[0].reason.contains("merged"));
+    }
+
+    #[test]
+    fn adjacent_periods_merge() {
+        let lamp = switch(false, &[(100, 0), (100, 0)]);
+        period(&lamp, 0, at_time(18, 0), at_time(20, 0));
+        period(&lamp, 1, at_time(20, 0), at_time(22, 0));
+
+        assert_eq!(lamp.get_future_events(at_time(12, 0)), events(&[(at_time(18, 0), 100), (at_time(22, 0), 0)]));
+    }
+
+    #[test]
+    fn highest_level_of_overlapping_periods() {
+        let lamp = switch(true, &[(50, 0), (100, 0)]);
+        period(&lamp, 0, at_time(18, 0), at_time(22, 0));
+        period(&lamp, 1, at_time(20, 0), at_time(21, 0));
+
+        assert_eq!(lamp.get_future_events(at_time(12, 0)),
+                   events(&[(at_time(18, 0), 50), (at_time(20, 0), 100), (at_time(21, 0), 50), (at_time(22, 0), 0)]));
+    }
+
+    #[test]
+    fn binary_switch_ignores_levels() {
+        let lamp = switch(false, &[(50, 0), (100, 0)]);
+        period(&lamp, 0, at_time(18, 0), at_time(22, 0));
+        period(&lamp, 1, at_time(20, 0), at_time(21, 0));
+
+        assert_eq!(lamp.get_future_events(at_time(12, 0)), events(&[(at_time(18, 0), 100), (at_time(22, 0), 0)]));
+    }
+
+    #[test]
+    fn end_before_start_is_skipped() {
+        let lamp = switch(false, &[(100, 0)]);
+        period(&lamp, 0, at_time(7, 0), at_time(6, 30));
+
+        assert!(lamp.get_future_events(at_time(0, 0)).is_empty());
+        let diagnostics = lamp.journal.diagnostics(Some("lamp"));
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].reason.starts_with("end before start"));
+    }
+
+    #[test]
+    fn override_survives_replacement() {
+        let lamp = switch(false, &[(100, 0)]);
+        period(&lamp, 0, at_time(18, 0), at_time(22, 0));
+        lamp.make_hot(at_time(12, 0));
+        lamp.override_state(Level::on(), Some(at_time(14, 0)));
+
+        let replacement = switch(false, &[(100, 0)]);
+        period(&replacement, 0, at_time(18, 0), at_time(22, 0));
+        replacement.take_override(&lamp);
+        replacement.make_hot(at_time(13, 0));
+        assert_eq!(replacement.get_state(), Level::on());
+
+        replacement.expire_override(at_time(14, 0));
+        assert_eq!(replacement.get_state(), Level::off());
+    }
+
+    #[test]
+    fn open_override_survives_replacement() {
+        let lamp = switch(false, &[(100, 0)]);
+        period(&lamp, 0, at_time(18, 0), at_time(22, 0));
+        lamp.make_hot(at_time(12, 0));
+        lamp.override_state(Level::on(), None);
+
+        let replacement = switch(false, &[(100, 0)]);
+        period(&replacement, 0, at_time(18, 0), at_time(22, 0));
+        replacement.take_override(&lamp);
+        replacement.make_hot(at_time(13, 0));
+        assert_eq!(replacement.get_state(), Level::on());
+
+        // the override lasts until the scheduled level changes
+        replacement.update(at_time(18, 0));
+        replacement.update(at_time(22, 0));
+        assert_eq!(replacement.get_state(), Level::off());
+    }
+
+    #[test]
+    fn no_override_to_take_over() {
+        let lamp = switch(false, &[(100, 0)]);
+        lamp.make_hot(at_time(12, 0));
+
+        let replacement = switch(false, &[(100, 0)]);
+        period(&replacement, 0, at_time(12, 0), at_time(22, 0));
+        replacement.take_override(&lamp);
+        replacement.make_hot(at_time(13, 0));
+        assert_eq!(replacement.get_state(), Level::on());
+    }
+
+    #[test]
+    fn shutdown_of_switch_that_is_not_hot() {
+        let lamp = Switch::new("lamp".into(), Box::new(NullDriver::new(false)), Rc::new(Journal::new(None)),
+                               config::ShutdownPolicy::Off, Some(Level::on()), vec![],
+                               config::Site { latitude: 52.0, longitude: 5.0, timezone: None }, utc_zone());
+        lamp.shutdown(at_time(12, 0));
+
+        let history = lamp.journal.history("lamp");
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].state, Level::off());
+    }
+
+    /// A lamp in Amsterdam that is on from 01:00 until 04:00 (across the DST transitions) and
+    /// from 18:00 until 23:00 local time
+    const AMSTERDAM: &'static str = "[config]\nlatitude = 52.37\nlongitude = 4.89\n\
+                                     timezone = \"Europe/Amsterdam\"\nntp = \"pool.ntp.org\"\n\n\
+                                     [lamp]\ndefault = \"schedule\"\ndriver = \"command\"\n\
+                                     on_command = \"true\"\noff_command = \"true\"\n\
+                                     [lamp.night]\nstart_fixed = [1, 0]\nend_fixed = [4, 0]\n\
+                                     [lamp.evening]\nstart_fixed = [18, 0]\nend_fixed = [23, 0]\n";
+
+    /// 2026-03-28 00:00 UTC, the day before the start of DST
+    const SPRING: i64 = 1774656000;
+    /// 2026-10-24 00:00 UTC, the day before the end of DST
+    const AUTUMN: i64 = 1792800000;
+
+    fn utc(day: i64, hour: i64, minute: i64) -> Timespec {
+        Timespec::new(day + hour * 3600 + minute * 60, 0)
+    }
+
+    /// Drives a tracker (with the AMSTERDAM configuration) through process_tick with a
+    /// simulated clock, and keeps the moments the lamp changes
+    struct Run {
+        tracker: TrackerInner,
+        clock: SimulatedClock,
+        changes: Vec<(Timespec, Level)>,
+    }
+
+    impl Run {
+        fn new(start: Timespec) -> Run {
+            let config = config::Config::parse(path::Path::new("/nonexistent/plugwise.toml"), AMSTERDAM)
+                .unwrap_or_else(|err| panic!("{}", err));
+            let zoneinfo = utc_zone();
+
+            Run {
+                tracker: TrackerInner::new(path::PathBuf::new(), config, &zoneinfo, Journal::new(None), true),
+                clock: SimulatedClock::new(start, 0),
+                changes: vec![],
+            }
+        }
+
+        /// Tick every TICK_SECONDS until (and including) the given moment
+        fn until(&mut self, until: Timespec) {
+            loop {
+                let now = self.clock.now().unwrap();
+                if now > until {
+                    break;
+                }
+
+                self.tracker.process_tick(now);
+                for action in self.tracker.journal.drain() {
+                    // settling after a rebuild sends the current level again
+                    if self.changes.last().map_or(true, |&(_, state)| state != action.state) {
+                        self.changes.push((action.timestamp, action.state));
+                    }
+                }
+                self.clock.advance(Duration::seconds(TICK_SECONDS));
+            }
+        }
+
+        /// Let the clock jump between two ticks
+        fn jump(&mut self, interval: Duration) {
+            self.clock.advance(interval);
+        }
+    }
+
+    fn changes(changes: &[(Timespec, u8)]) -> Vec<(Timespec, Level)> {
+        changes.iter().map(|&(ts, level)| (ts, Level::new(level).unwrap())).collect()
+    }
+
+    #[test]
+    fn start_of_dst() {
+        let mut run = Run::new(utc(SPRING, 12, 0));
+        run.until(utc(SPRING, 60, 0));
+
+        assert_eq!(run.changes, changes(&[
+            (utc(SPRING, 12, 0), 0),
+            (utc(SPRING, 17, 0), 100),  // 18:00 CET
+            (utc(SPRING, 22, 0), 0),    // 23:00 CET
+            (utc(SPRING, 24, 0), 100),  // 01:00 CET
+            (utc(SPRING, 26, 0), 0),    // 04:00 CEST
+            (utc(SPRING, 40, 0), 100),  // 18:00 CEST
+            (utc(SPRING, 45, 0), 0),    // 23:00 CEST
+            (utc(SPRING, 47, 0), 100),  // 01:00 CEST
+            (utc(SPRING, 50, 0), 0),    // 04:00 CEST
+        ]));
+    }
+
+    #[test]
+    fn end_of_dst() {
+        let mut run = Run::new(utc(AUTUMN, 12, 0));
+        run.until(utc(AUTUMN, 60, 0));
+
+        assert_eq!(run.changes, changes(&[
+            (utc(AUTUMN, 12, 0), 0),
+            (utc(AUTUMN, 16, 0), 100),  // 18:00 CEST
+            (utc(AUTUMN, 21, 0), 0),    // 23:00 CEST
+            (utc(AUTUMN, 23, 0), 100),  // 01:00 CEST
+            (utc(AUTUMN, 27, 0), 0),    // 04:00 CET, after 02:00-03:00 passed twice
+            (utc(AUTUMN, 41, 0), 100),  // 18:00 CET
+            (utc(AUTUMN, 46, 0), 0),    // 23:00 CET
+            (utc(AUTUMN, 48, 0), 100),  // 01:00 CET
+            (utc(AUTUMN, 51, 0), 0),    // 04:00 CET
+        ]));
+    }
+
+    #[test]
+    fn forward_jump_across_dst() {
+        let mut run = Run::new(utc(SPRING, 12, 0));
+        run.until(utc(SPRING, 23, 50));
+        // the clock jumps past the start of the night and the start of DST
+        run.jump(Duration::hours(2));
+        run.until(utc(SPRING, 30, 0));
+
+        assert_eq!(run.changes, changes(&[
+            (utc(SPRING, 12, 0), 0),
+            (utc(SPRING, 17, 0), 100),
+            (utc(SPRING, 22, 0), 0),
+            // settled after the jump to 01:50:10 UTC (03:50 CEST), in the middle of the night
+            (utc(SPRING, 25, 50) + Duration::seconds(10), 100),
+            (utc(SPRING, 26, 0), 0),
+        ]));
+    }
+
+    #[test]
+    fn backward_jump_across_dst() {
+        let mut run = Run::new(utc(AUTUMN, 12, 0));
+        run.until(utc(AUTUMN, 24, 30));
+        // the clock jumps back before the start of the night
+        run.jump(-Duration::hours(2));
+        run.until(utc(AUTUMN, 30, 0));
+
+        assert_eq!(run.changes, changes(&[
+            (utc(AUTUMN, 12, 0), 0),
+            (utc(AUTUMN, 16, 0), 100),
+            (utc(AUTUMN, 21, 0), 0),
+            (utc(AUTUMN, 23, 0), 100),
+            // settled after the jump to 22:30:10 UTC, the night starts again at 23:00 UTC
+            (utc(AUTUMN, 22, 30) + Duration::seconds(10), 0),
+            (utc(AUTUMN, 23, 0), 100),
+            (utc(AUTUMN, 27, 0), 0),
+        ]));
+    }
+
+    #[test]
+    fn small_backward_correction_repeats_nothing() {
+        let mut run = Run::new(utc(SPRING, 12, 0));
+        run.until(utc(SPRING, 17, 1));
+        // less than JUMP_THRESHOLD_SECONDS: the events that passed are not kicked again
+        run.jump(-Duration::minutes(3));
+        run.until(utc(SPRING, 23, 0));
+
+        assert_eq!(run.changes, changes(&[
+            (utc(SPRING, 12, 0), 0),
+            (utc(SPRING, 17, 0), 100),
+            (utc(SPRING, 22, 0), 0),
+        ]));
+    }
+
+    #[test]
+    fn driver_settings_are_rejected() {
+        let mut run = Run::new(utc(SPRING, 12, 0));
+        let keys = ["driver", "on_command", "off_command", "level_command", "on_url", "off_url", "level_url"];
+
+        for key in &keys {
+            for value in vec![Some(toml::Value::String("touch /tmp/owned".into())), None] {
+                for alias in &["lamp", "new"] {
+                    let result = run.tracker.set_circle(alias, &[(String::from(*key), value.clone())]);
+                    assert!(result.unwrap_err().contains("only be changed in the configuration file"));
+                }
+            }
+        }
+    }
 }
@@ -1,23 +1,600 @@
+use hyper;
+use hyper::net::{Fresh, HttpStream, NetworkListener};
+use hyper::server::{Server, Request as HttpRequest, Response as HttpResponse};
 use iron::status;
 use iron::prelude::*;
+use iron::error::HttpResult;
 use iron::mime::Mime;
+use iron::{Listening, Protocol};
 use router::Router;
-use super::tracker::{TrackerClient, Context};
-use rustc_serialize::json;
+use super::tracker::{TrackerClient, Level, SwitchInfo, Status, Discovered, Action, Diagnostic};
+use super::config::{Circle, Condition, Driver, Event, Site, Toggle};
+use super::clock::TimeSource;
+use super::assets::Assets;
+use rustc_serialize::json::{self, Json};
 use std::collections::BTreeMap;
-use time::at_utc;
+use time::{Duration, Timespec, at_utc, get_time};
+use std::io;
+use std::io::Read;
+use std::net::{SocketAddr, TcpListener};
 use std::path::Path;
-use staticfile::Static;
+use std::sync::{Arc, RwLock};
 use mount::Mount;
+use toml;
 
-pub struct Web;
+const LISTEN_ADDRESS: &'static str = "0.0.0.0:3000";
+const HTTP_THREADS: usize = 8;
+
+/// JSON: status of a switch; the levels are percentages, the booleans tell whether the level is
+/// above 0
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct SwitchStatus {
+    pub switch: bool,
+    pub scheduled_state: bool,
+    pub next_events: BTreeMap<String, bool>,
+    pub dimmable: bool,
+    pub level: u8,
+    pub scheduled_level: u8,
+    pub next_levels: BTreeMap<String, u8>,
+    /// events of the toggles that were skipped or merged, and why
+    pub diagnostics: Vec<DiagnosticEntry>,
+}
+
+impl SwitchStatus {
+    pub fn new(info: &SwitchInfo) -> SwitchStatus {
+        let mut next_events = BTreeMap::new();
+        let mut next_levels = BTreeMap::new();
+
+        for (ts, state) in &info.next_events {
+            let ts = format!("{}", at_utc(*ts).rfc3339());
+            let _ = next_events.insert(ts.clone(), state.is_on());
+            let _ = next_levels.insert(ts, state.percent());
+        }
+
+        SwitchStatus {
+            switch: info.state.is_on(),
+            scheduled_state: info.scheduled_state.is_on(),
+            next_events: next_events,
+            dimmable: info.dimmable,
+            level: info.state.percent(),
+            scheduled_level: info.scheduled_state.percent(),
+            next_levels: next_levels,
+            diagnostics: info.diagnostics.iter().map(DiagnosticEntry::new).collect(),
+        }
+    }
+}
+
+/// JSON: skipped or merged event of a toggle
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct DiagnosticEntry {
+    pub time: String,
+    pub toggle: String,
+    pub reason: String,
+}
+
+impl DiagnosticEntry {
+    pub fn new(diagnostic: &Diagnostic) -> DiagnosticEntry {
+        DiagnosticEntry {
+            time: format!("{}", at_utc(diagnostic.timestamp).rfc3339()),
+            toggle: diagnostic.toggle.clone(),
+            reason: diagnostic.reason.clone(),
+        }
+    }
+}
+
+/// Hours and minutes of a "HH:MM" time
+fn parse_time(time: &str) -> Option<(u8, u8)> {
+    let mut parts = time.splitn(2, ':').map(|part| part.trim().parse::<u8>().ok());
+    match (parts.next(), parts.next()) {
+        (Some(Some(h)), Some(Some(m))) => Some((h, m)),
+        _ => None,
+    }
+}
+
+/// JSON: event of a toggle
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct EventConfig {
+    /// "fixed", "fuzzy", "sunrise" or "sunset"
+    pub kind: String,
+    /// time of a fixed event or earliest time of a fuzzy event ("HH:MM", hours beyond 23 are
+    /// the next day)
+    pub time: Option<String>,
+    /// latest time of a fuzzy event
+    pub latest: Option<String>,
+    /// variance of a sun based event in minutes
+    pub variance: Option<u16>,
+    /// moment of a sun based event today (without variance)
+    pub today: Option<String>,
+}
+
+impl EventConfig {
+    /// The event of a toggle edited by the web user interface; the times and variance are
+    /// checked when the configuration is loaded
+    fn to_event(&self) -> Result<Event, String> {
+        let time = |time: &Option<String>| time.as_ref().and_then(|time| parse_time(time))
+                                                .ok_or_else(|| format!("invalid time of {} event, expected HH:MM", self.kind));
+
+        match &self.kind[..] {
+            "fixed" => time(&self.time).map(|(h, m)| Event::Fixed(h, m)),
+            "fuzzy" => Ok(Event::Fuzzy(try!(time(&self.time)), try!(time(&self.latest)))),
+            "sunrise" => Ok(Event::Sunrise(self.variance.unwrap_or(0))),
+            "sunset" => Ok(Event::Sunset(self.variance.unwrap_or(0))),
+            kind => Err(format!("invalid event kind '{}', expected fixed, fuzzy, sunrise or sunset", kind)),
+        }
+    }
+
+    fn new(event: &Event, site: &Site, now: Timespec) -> EventConfig {
+        let time = |(h, m): (u8, u8)| Some(format!("{:02}:{:02}", h, m));
+        let (kind, first, latest, variance) = match *event {
+            Event::Fixed(h, m) => ("fixed", time((h, m)), None, None),
+            Event::Fuzzy(first, second) => ("fuzzy", time(first), time(second), None),
+            Event::Sunrise(variance) => ("sunrise", None, None, Some(variance)),
+            Event::Sunset(variance) => ("sunset", None, None, Some(variance)),
+        };
+
+        EventConfig {
+            kind: kind.into(),
+            time: first,
+            latest: latest,
+            variance: variance,
+            today: event.sun_time(now, site).map(|ts| format!("{}", at_utc(ts).rfc3339())),
+        }
+    }
+}
+
+/// JSON: toggle of a circle
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct ToggleConfig {
+    pub name: String,
+    pub start: EventConfig,
+    pub end: EventConfig,
+    pub level: u8,
+    /// minutes
+    pub fade_in: u16,
+    pub fade_out: u16,
+    /// "dark" or "light": only on days the toggle starts in the dark or in daylight
+    pub when: Option<String>,
+    /// shortest and longest daylight ("HH:MM") of the days the toggle is used
+    pub min_daylight: Option<String>,
+    pub max_daylight: Option<String>,
+    /// template the toggle comes from; saving it gives the circle a toggle of its own
+    pub template: Option<String>,
+}
+
+impl ToggleConfig {
+    fn to_toggle(&self) -> Result<Toggle, String> {
+        let daylight = |daylight: &Option<String>| match *daylight {
+            Some(ref daylight) => parse_time(daylight).map(|(h, m)| Some(h as u16 * 60 + m as u16))
+                                                      .ok_or_else(|| format!("invalid daylight '{}', expected HH:MM", daylight)),
+            None => Ok(None),
+        };
+        let condition = Condition {
+            dark: match self.when.as_ref().map(|when| &when[..]) {
+                Some("dark") => Some(true),
+                Some("light") => Some(false),
+                None => None,
+                Some(when) => return Err(format!("invalid condition '{}', expected dark or light", when)),
+            },
+            min_daylight: try!(daylight(&self.min_daylight)),
+            max_daylight: try!(daylight(&self.max_daylight)),
+        };
+
+        Ok(Toggle {
+            alias: self.name.clone(),
+            start: try!(self.start.to_event()),
+            end: try!(self.end.to_event()),
+            level: self.level,
+            fade_in: self.fade_in,
+            fade_out: self.fade_out,
+            condition: condition,
+            template: None,
+        })
+    }
+
+    fn new(toggle: &Toggle, site: &Site, now: Timespec) -> ToggleConfig {
+        let daylight = |minutes: u16| format!("{:02}:{:02}", minutes / 60, minutes % 60);
+
+        ToggleConfig {
+            name: toggle.alias.clone(),
+            start: EventConfig::new(&toggle.start, site, now),
+            end: EventConfig::new(&toggle.end, site, now),
+            level: toggle.level,
+            fade_in: toggle.fade_in,
+            fade_out: toggle.fade_out,
+            when: toggle.condition.dark.map(|dark| String::from(if dark { "dark" } else { "light" })),
+            min_daylight: toggle.condition.min_daylight.map(&daylight),
+            max_daylight: toggle.condition.max_daylight.map(&daylight),
+            template: toggle.template.clone(),
+        }
+    }
+}
+
+/// JSON: configuration of a circle
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct CircleConfig {
+    pub alias: String,
+    /// "plugwise", "http" or "command"
+    pub driver: String,
+    /// "off", "on" or "schedule"
+    pub default: String,
+    pub dimmable: bool,
+    pub mac: Option<String>,
+    pub stick: Option<String>,
+    pub on_url: Option<String>,
+    pub off_url: Option<String>,
+    pub level_url: Option<String>,
+    pub on_command: Option<String>,
+    pub off_command: Option<String>,
+    pub level_command: Option<String>,
+    /// "keep", "off" or "default"; the policy of `[config]` when `null`
+    pub shutdown: Option<String>,
+    pub site: Option<String>,
+    pub timezone: Option<String>,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub templates: Vec<String>,
+    pub toggles: Vec<ToggleConfig>,
+}
+
+impl CircleConfig {
+    pub fn new(circle: &Circle, now: Timespec) -> CircleConfig {
+        let (urls, commands) = match circle.driver {
+            Driver::Http(ref on, ref off, ref level) => ((Some(on.clone()), Some(off.clone()), level.clone()), (None, None, None)),
+            Driver::Command(ref on, ref off, ref level) => ((None, None, None), (Some(on.clone()), Some(off.clone()), level.clone())),
+            Driver::Plugwise(..) => ((None, None, None), (None, None, None)),
+        };
+
+        CircleConfig {
+            alias: circle.alias.clone(),
+            driver: match circle.driver {
+                Driver::Plugwise(..) => "plugwise",
+                Driver::Http(..) => "http",
+                Driver::Command(..) => "command",
+            }.into(),
+            default: circle.default.name().into(),
+            dimmable: circle.dimmable(),
+            mac: circle.plugwise().map(|(mac, _)| format!("{:016X}", mac)),
+            stick: circle.plugwise().map(|(_, stick)| stick.into()),
+            on_url: urls.0,
+            off_url: urls.1,
+            level_url: urls.2,
+            on_command: commands.0,
+            off_command: commands.1,
+            level_command: commands.2,
+            shutdown: circle.shutdown.map(|shutdown| shutdown.name().into()),
+            site: circle.site_name.clone(),
+            timezone: circle.site.timezone.clone(),
+            latitude: circle.site.latitude,
+            longitude: circle.site.longitude,
+            templates: circle.templates.clone(),
+            toggles: circle.toggles.iter().map(|toggle| ToggleConfig::new(toggle, &circle.site, now)).collect(),
+        }
+    }
+}
+
+/// JSON: switch action in the history of a switch
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct HistoryEntry {
+    pub time: String,
+    pub switch: bool,
+    pub level: u8,
+}
+
+impl HistoryEntry {
+    pub fn new(action: &Action) -> HistoryEntry {
+        HistoryEntry {
+            time: format!("{}", at_utc(action.timestamp).rfc3339()),
+            switch: action.state.is_on(),
+            level: action.state.percent(),
+        }
+    }
+}
+
+/// JSON: status of the daemon
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct DaemonStatus {
+    /// "ntp", "system", "simulated" or "none" (no trusted time available yet)
+    pub time_source: String,
+    pub ntp_server: Option<String>,
+    pub last_ntp_sync: Option<String>,
+    /// difference between NTP and the system clock in seconds
+    pub ntp_offset: Option<f64>,
+    pub sticks: BTreeMap<String, StickState>,
+}
+
+/// JSON: status of a stick
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct StickState {
+    /// serial device; `null` for the plugwise simulator
+    pub device: Option<String>,
+    pub connected: bool,
+    pub error: Option<String>,
+    pub circles: Vec<String>,
+}
+
+impl DaemonStatus {
+    pub fn new(status: &Status) -> DaemonStatus {
+        let time = &status.time;
+
+        DaemonStatus {
+            time_source: match time.source {
+                Some(TimeSource::Ntp) => "ntp",
+                Some(TimeSource::System) => "system",
+                Some(TimeSource::Simulated) => "simulated",
+                None => "none",
+            }.into(),
+            ntp_server: time.ntp_server.clone(),
+            last_ntp_sync: time.last_sync.map(|ts| format!("{}", at_utc(ts).rfc3339())),
+            ntp_offset: time.offset.map(|offset| offset.num_milliseconds() as f64 / 1000.0),
+            sticks: status.sticks.iter().map(|(name, stick)| {
+                (name.clone(), StickState {
+                    device: stick.device.clone(),
+                    connected: stick.connected,
+                    error: stick.error.clone(),
+                    circles: stick.circles.clone(),
+                })
+            }).collect(),
+        }
+    }
+}
+
+/// Parse a duration like "90s", "30m", "2h", "1d" or "1h30m"
+pub fn parse_duration(duration: &str) -> Option<Duration> {
+    let mut total = Duration::zero();
+    let mut number = String::new();
+
+    for c in duration.chars() {
+        if c.is_digit(10) {
+            number.push(c);
+        } else {
+            let value = match number.parse::<i64>() {
+                Ok(value) => value,
+                Err(_) => return None,
+            };
+            number.clear();
+            total = total + match c {
+                's' => Duration::seconds(value),
+                'm' => Duration::minutes(value),
+                'h' => Duration::hours(value),
+                'd' => Duration::days(value),
+                _ => return None,
+            };
+        }
+    }
+
+    if !number.is_empty() || total <= Duration::zero() {
+        None
+    } else {
+        Some(total)
+    }
+}
+
+/// Override a switch; `/switch/:switch/:state` takes "on" or "off" and returns whether the switch
+/// is on, `/switch/:switch/level/:level` takes a percentage and returns the resulting level
+fn toggle_switch(tracker: &TrackerClient, req: &mut Request, dim: bool) -> IronResult<Response> {
+    let params = req.extensions.get::<Router>().unwrap();
+    let switch = &params.find("switch");
+    let state = if dim { params.find("level") } else { params.find("state") };
+    let duration = match params.find("duration") {
+        Some(duration) => match parse_duration(duration) {
+            Some(duration) => Some(duration),
+            None => return Ok(Response::with(status::BadRequest)),
+        },
+        None => None,
+    };
+
+    Ok(switch.and_then(|ref switch| state.and_then(|ref state| {
+        let state = match (dim, *state) {
+            (true, level) => match level.trim_right_matches('%').parse().ok().and_then(Level::new) {
+                Some(state) => state,
+                None => return None,
+            },
+            (false, "on") => Level::on(),
+            (false, "off") => Level::off(),
+            (false, _) => return None,
+        };
+        tracker.switch(switch, state, duration).map(|new_state| {
+            let json = if dim {
+                format!("{}", json::as_json(&new_state.percent()))
+            } else {
+                format!("{}", json::as_json(&new_state.is_on()))
+            };
+            let content_type = "application/json".parse::<Mime>().unwrap();
+            Response::with((content_type, status::Ok, json))
+        })
+    })).unwrap_or_else(||Response::with(status::NotFound)))
+}
+
+/// JSON: circle found by the discovery
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct DiscoveredCircle {
+    pub stick: String,
+    pub mac: String,
+    /// `null` when the circle is not configured yet
+    pub alias: Option<String>,
+    pub reachable: bool,
+    pub switch: Option<bool>,
+    /// response time of the circle
+    pub latency_ms: Option<i64>,
+    pub error: Option<String>,
+}
+
+impl DiscoveredCircle {
+    pub fn new(discovered: &Discovered) -> DiscoveredCircle {
+        DiscoveredCircle {
+            stick: discovered.stick.clone(),
+            mac: format!("{:016X}", discovered.mac),
+            alias: discovered.alias.clone(),
+            reachable: discovered.probe.reachable,
+            switch: discovered.probe.relay,
+            latency_ms: discovered.probe.latency.map(|latency| latency.num_milliseconds()),
+            error: discovered.probe.error.clone(),
+        }
+    }
+}
+
+/// Parse a comma separated list of hexadecimal macs
+pub fn parse_macs(macs: &str) -> Option<Vec<u64>> {
+    macs.split(',')
+        .filter(|mac| !mac.is_empty())
+        .map(|mac| u64::from_str_radix(mac.trim(), 16).ok())
+        .collect()
+}
+
+fn discover(tracker: &TrackerClient, req: &mut Request) -> IronResult<Response> {
+    let macs = match req.extensions.get::<Router>().unwrap().find("macs").map(parse_macs) {
+        Some(Some(macs)) => macs,
+        Some(None) => return Ok(Response::with(status::BadRequest)),
+        None => vec![],
+    };
+
+    Ok(match tracker.discover(&macs) {
+        Ok(discovered) => {
+            let discovered: Vec<_> = discovered.iter().map(DiscoveredCircle::new).collect();
+            let content_type = "application/json".parse::<Mime>().unwrap();
+            Response::with((content_type, status::Ok, format!("{}", json::as_json(&discovered))))
+        },
+        Err(err) => Response::with((status::ServiceUnavailable, err)),
+    })
+}
+
+fn read_body(req: &mut Request) -> Result<String, String> {
+    let mut body = String::new();
+    try!(req.body.read_to_string(&mut body).map_err(|err| format!("unable to read request: {}", err)));
+    Ok(body)
+}
+
+/// Settings of a circle from a JSON object; `null` removes a setting
+fn circle_settings(body: &str) -> Result<Vec<(String, Option<toml::Value>)>, String> {
+    let settings = try!(Json::from_str(body).map_err(|err| format!("invalid JSON: {}", err)));
+    let settings = try!(settings.as_object().ok_or(String::from("expected a JSON object")));
+
+    settings.iter().map(|(key, value)| {
+        let value = match *value {
+            Json::Null => None,
+            Json::String(ref value) => Some(toml::Value::String(value.clone())),
+            Json::I64(value) => Some(toml::Value::Integer(value)),
+            Json::U64(value) => Some(toml::Value::Integer(value as i64)),
+            Json::F64(value) => Some(toml::Value::Float(value)),
+            Json::Boolean(value) => Some(toml::Value::Boolean(value)),
+            _ => return Err(format!("invalid value of '{}', expected a string or a number", key)),
+        };
+        Ok((key.clone(), value))
+    }).collect()
+}
+
+/// Create a circle or change its settings
+fn set_circle(tracker: &TrackerClient, req: &mut Request) -> Result<(), String> {
+    let alias = String::from(req.extensions.get::<Router>().unwrap().find("circle").unwrap_or(""));
+    let settings = try!(read_body(req).and_then(|body| circle_settings(&body)));
+
+    tracker.set_circle(&alias, settings)
+}
+
+/// Create, replace or rename a toggle
+fn set_toggle(tracker: &TrackerClient, req: &mut Request) -> Result<(), String> {
+    let (alias, name) = {
+        let params = req.extensions.get::<Router>().unwrap();
+        (String::from(params.find("circle").unwrap_or("")), String::from(params.find("toggle").unwrap_or("")))
+    };
+    let toggle: ToggleConfig = try!(read_body(req).and_then(|body|
+        json::decode(&body).map_err(|err| format!("invalid toggle: {}", err))));
+
+    tracker.set_toggle(&alias, &name, try!(toggle.to_toggle()))
+}
+
+/// Response to a change of the configuration
+fn edit_response(result: Result<(), String>) -> Response {
+    match result {
+        Ok(()) => {
+            let content_type = "application/json".parse::<Mime>().unwrap();
+            Response::with((content_type, status::Ok, format!("{}", json::as_json(&true))))
+        },
+        Err(err) => Response::with((status::BadRequest, err)),
+    }
+}
+
+/// Listener handed over by systemd socket activation
+#[derive(Clone)]
+struct ActivatedListener(Arc<TcpListener>);
+
+impl NetworkListener for ActivatedListener {
+    type Stream = HttpStream;
+
+    fn accept(&mut self) -> hyper::Result<HttpStream> {
+        let (stream, _) = try!(self.0.accept());
+        Ok(HttpStream(stream))
+    }
+
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        self.0.local_addr()
+    }
+}
+
+/// Serves a Iron handler directly from hyper, since Iron is only able to serve on a listener it
+/// binds itself
+struct ActivatedHandler<H> {
+    handler: H,
+    addr: SocketAddr,
+}
+
+impl<H: Handler> hyper::server::Handler for ActivatedHandler<H> {
+    fn handle(&self, req: HttpRequest, mut res: HttpResponse<Fresh>) {
+        match Request::from_http(req, self.addr, &Protocol::Http) {
+            Ok(mut req) => {
+                let response = self.handler.handle(&mut req).unwrap_or_else(|err| err.response);
+                response.write_back(res);
+            },
+            Err(err) => {
+                error!("invalid HTTP request: {}", err);
+                *res.status_mut() = hyper::status::StatusCode::BadRequest;
+                let _ = res.send(err.as_bytes());
+            }
+        }
+    }
+}
+
+/// Refuses requests once the daemon is stopping, since the tracker is no longer available
+struct StoppingHandler<H> {
+    handler: H,
+    stopping: Arc<RwLock<bool>>,
+}
+
+impl<H: Handler> Handler for StoppingHandler<H> {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        // the lock is held while handling, so `Web::stop` waits for the running requests
+        let stopping = self.stopping.read().expect("BUG: stopping lock poisoned");
+        if *stopping {
+            return Ok(Response::with((status::ServiceUnavailable, "shutting down")));
+        }
+        self.handler.handle(req)
+    }
+}
+
+pub struct Web {
+    stopping: Arc<RwLock<bool>>,
+}
 
 impl Web {
     pub fn new() -> Web {
-        Web
+        Web {
+            stopping: Arc::new(RwLock::new(false)),
+        }
+    }
+
+    /// Refuse all further requests; returns once the running requests are finished, after which
+    /// the tracker may be torn down
+    pub fn stop(&self) {
+        *self.stopping.write().expect("BUG: stopping lock poisoned") = true;
     }
 
-    pub fn serve(&mut self, tracker: TrackerClient, webresources: &Path) {
+    /// Serve the web user interface (from `webresources`, falling back to the embedded one) and
+    /// the API; when a listener is given (by systemd socket activation) it is used instead of
+    /// binding to port 3000.
+    pub fn serve(&mut self,
+                 tracker: TrackerClient,
+                 webresources: Option<&Path>,
+                 listener: Option<TcpListener>) -> HttpResult<Listening> {
         let mut router = Router::new();
 
         // JSON: get available switches
@@ -34,62 +611,164 @@ impl Web {
             let switch = &req.extensions.get::<Router>().unwrap().find("switch");
             let content_type = "application/json".parse::<Mime>().unwrap();
 
-            #[derive(RustcEncodable)]
-            struct GetResult {
-                switch: bool,
-                next_events: BTreeMap<String, bool>
-            }
-
             Ok(switch.and_then(|ref switch| tracker4get.get_switch(switch)).map_or(
-                Response::with(status::NotFound), |(ref now, ref next)| {
-                    let mut next_events = BTreeMap::new();
-
-                    for (ts, state) in next {
-                        let _ = next_events.insert(
-                            format!("{}", at_utc(*ts).rfc3339()),
-                            *state == Context::On);
-                    }
-
-                    let get_result = GetResult {
-                        switch: *now == Context::On,
-                        next_events: next_events,
-                    };
+                Response::with(status::NotFound), |ref info| {
+                    let get_result = SwitchStatus::new(info);
                     let json = json::as_json(&get_result);
                     Response::with((content_type, status::Ok, format!("{}", json)))
                 }))
         });
 
-        // JSON: toggle switch
+        // JSON: configuration of the circle of a switch
+        let tracker4config = tracker.clone();
+        router.post("/config/:switch", move|req: &mut Request| {
+            let switch = req.extensions.get::<Router>().unwrap().find("switch");
+            let content_type = "application/json".parse::<Mime>().unwrap();
+
+            Ok(switch.and_then(|switch| tracker4config.get_circle(switch)).map_or(
+                Response::with(status::NotFound), |ref circle| {
+                    let config = CircleConfig::new(circle, get_time());
+                    Response::with((content_type, status::Ok, format!("{}", json::as_json(&config))))
+                }))
+        });
+
+        // JSON: recent actions of a switch
+        let tracker4history = tracker.clone();
+        router.post("/history/:switch", move|req: &mut Request| {
+            let switch = req.extensions.get::<Router>().unwrap().find("switch").unwrap_or("");
+            let history: Vec<_> = tracker4history.get_history(switch).iter().map(HistoryEntry::new).collect();
+            let content_type = "application/json".parse::<Mime>().unwrap();
+            Ok(Response::with((content_type, status::Ok, format!("{}", json::as_json(&history)))))
+        });
+
+        // JSON: toggle switch ("on" or "off"); returns whether the switch is on
         let tracker4switch = tracker.clone();
         router.post("/switch/:switch/:state", move|req: &mut Request| {
+            toggle_switch(&tracker4switch, req, false)
+        });
+
+        // JSON: toggle switch for a limited duration
+        let tracker4override = tracker.clone();
+        router.post("/switch/:switch/:state/:duration", move|req: &mut Request| {
+            toggle_switch(&tracker4override, req, false)
+        });
+
+        // JSON: dim switch to a percentage (binary switches are on above 0); returns the
+        // resulting level
+        let tracker4level = tracker.clone();
+        router.post("/switch/:switch/level/:level", move|req: &mut Request| {
+            toggle_switch(&tracker4level, req, true)
+        });
+
+        // JSON: dim switch for a limited duration
+        let tracker4leveloverride = tracker.clone();
+        router.post("/switch/:switch/level/:level/:duration", move|req: &mut Request| {
+            toggle_switch(&tracker4leveloverride, req, true)
+        });
+
+        // JSON: daemon status
+        let tracker4status = tracker.clone();
+        router.post("/status", move|_: &mut Request| {
+            let status = DaemonStatus::new(&tracker4status.get_status());
+            let content_type = "application/json".parse::<Mime>().unwrap();
+            Ok(Response::with((content_type, status::Ok, format!("{}", json::as_json(&status)))))
+        });
+
+        // JSON: probe the configured circles; circles that are not configured are only found by
+        // their mac (/discover/:macs), since the stick does not list the circles in its network
+        let tracker4discover = tracker.clone();
+        router.post("/discover", move|req: &mut Request| {
+            discover(&tracker4discover, req)
+        });
+
+        // JSON: probe the configured circles and the given (comma separated) macs
+        let tracker4probe = tracker.clone();
+        router.post("/discover/:macs", move|req: &mut Request| {
+            discover(&tracker4probe, req)
+        });
+
+        // JSON: add a discovered circle to the configuration
+        let tracker4add = tracker.clone();
+        router.post("/discover/add/:stick/:mac/:alias", move|req: &mut Request| {
+            let params = req.extensions.get::<Router>().unwrap();
+            let mac = match params.find("mac").and_then(|mac| u64::from_str_radix(mac, 16).ok()) {
+                Some(mac) => mac,
+                None => return Ok(Response::with(status::BadRequest)),
+            };
+            let stick = params.find("stick").unwrap_or("");
+            let alias = params.find("alias").unwrap_or("");
+
+            Ok(match tracker4add.add_circle(alias, mac, stick) {
+                Ok(()) => {
+                    let content_type = "application/json".parse::<Mime>().unwrap();
+                    Response::with((content_type, status::Ok, format!("{}", json::as_json(&true))))
+                },
+                Err(err) => Response::with((status::BadRequest, err)),
+            })
+        });
+
+        // JSON: create a circle or change its settings (a JSON object of circle table keys; `null`
+        // removes a key); the driver, its URLs and commands are refused, since they run on the host
+        let tracker4setcircle = tracker.clone();
+        router.post("/circle/:circle/set", move|req: &mut Request| {
+            Ok(edit_response(set_circle(&tracker4setcircle, req)))
+        });
+
+        // JSON: remove a circle and its toggles
+        let tracker4deletecircle = tracker.clone();
+        router.post("/circle/:circle/delete", move|req: &mut Request| {
+            let alias = req.extensions.get::<Router>().unwrap().find("circle").unwrap_or("");
+            Ok(edit_response(tracker4deletecircle.delete_circle(alias)))
+        });
+
+        // JSON: create, replace or rename (to the name in the body) a toggle (a toggle of
+        // /config/:switch)
+        let tracker4settoggle = tracker.clone();
+        router.post("/toggle/:circle/:toggle/set", move|req: &mut Request| {
+            Ok(edit_response(set_toggle(&tracker4settoggle, req)))
+        });
+
+        // JSON: remove a toggle
+        let tracker4deletetoggle = tracker.clone();
+        router.post("/toggle/:circle/:toggle/delete", move|req: &mut Request| {
             let params = req.extensions.get::<Router>().unwrap();
-            let switch = &params.find("switch");
-            let state = &params.find("state");
-
-            Ok(switch.and_then(|ref switch| state.and_then(|ref state| {
-                match *state {
-                    "on"|"off" => {
-                        let state = match *state {
-                            "off" => Context::Off,
-                            "on" => Context::On,
-                            _ => panic!("impossible to reach"),
-                        };
-                        let new_state = tracker4switch.switch(switch, state) == Context::On;
-                        let json = json::as_json(&new_state);
-                        let content_type = "application/json".parse::<Mime>().unwrap();
-                        Some(Response::with((content_type, status::Ok, format!("{}", json))))
-                    },
-                    _ => None
-                }
-            })).unwrap_or_else(||Response::with(status::NotFound)))
+            let (alias, name) = (params.find("circle").unwrap_or(""), params.find("toggle").unwrap_or(""));
+            Ok(edit_response(tracker4deletetoggle.delete_toggle(alias, name)))
+        });
+
+        // JSON: reload configuration
+        let tracker4reload = tracker.clone();
+        router.post("/reload", move|_: &mut Request| {
+            Ok(match tracker4reload.reload() {
+                Ok(()) => {
+                    let content_type = "application/json".parse::<Mime>().unwrap();
+                    Response::with((content_type, status::Ok, format!("{}", json::as_json(&true))))
+                },
+                Err(err) => Response::with((status::InternalServerError, err)),
+            })
         });
 
         let mut mount = Mount::new();
 
-        mount
-            .mount("/", Static::new(webresources))
-            .mount("/api", router);
+        mount.mount("/", Assets::new(webresources));
+        mount.mount("/api", router);
+
+        let handler = StoppingHandler {
+            handler: mount,
+            stopping: self.stopping.clone(),
+        };
 
-        Iron::new(mount).http("0.0.0.0:3000").unwrap();
+        match listener {
+            Some(listener) => {
+                let mut listener = ActivatedListener(Arc::new(listener));
+                let handler = ActivatedHandler {
+                    addr: try!(listener.local_addr()),
+                    handler: handler,
+                };
+                info!("serving on socket activated listener {}", handler.addr);
+                Server::new(listener).handle_threads(handler, HTTP_THREADS)
+            },
+            None => Iron::new(handler).http(LISTEN_ADDRESS),
+        }
     }
 }